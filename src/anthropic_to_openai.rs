@@ -1,31 +1,31 @@
-use crate::config::Config;
 use crate::models::*;
+use crate::routing::Route;
 use serde_json::json;
 
-pub fn map_model(anthropic_model: &str, settings: &Config) -> String {
-    if anthropic_model.contains("haiku") {
-        settings.model_haiku.clone()
-    } else if anthropic_model.contains("sonnet") {
-        settings.model_sonnet.clone()
-    } else if anthropic_model.contains("opus") {
-        settings.model_opus.clone()
-    } else {
-        anthropic_model.to_string()
+/// The `image_url` for an Anthropic image source, inlining base64 data as a data URL.
+fn image_url(source: &serde_json::Value) -> String {
+    match source["type"].as_str() {
+        Some("base64") => format!(
+            "data:{};base64,{}",
+            source["media_type"].as_str().unwrap_or("image/png"),
+            source["data"].as_str().unwrap_or("")
+        ),
+        _ => source["url"].as_str().unwrap_or("").to_string(),
     }
 }
 
-pub fn format_anthropic_to_openai(req: AnthropicRequest, settings: &Config) -> OpenAIRequest {
+pub fn format_anthropic_to_openai(req: AnthropicRequest, route: &Route) -> OpenAIRequest {
     let mut openapi_messages = Vec::new();
 
-    if let Some(system) = req.system {
-        if let Some(system_str) = system.as_str() {
-            openapi_messages.push(OpenAIMessage {
-                role: "system".to_string(),
-                content: Some(system_str.to_string()),
-                tool_calls: None,
-                tool_call_id: None,
-            });
-        }
+    if let Some(system) = req.system
+        && let Some(system_str) = system.as_str()
+    {
+        openapi_messages.push(OpenAIMessage {
+            role: "system".to_string(),
+            content: Some(system_str.to_string().into()),
+            tool_calls: None,
+            tool_call_id: None,
+        });
     }
 
     for message in req.messages {
@@ -33,13 +33,19 @@ pub fn format_anthropic_to_openai(req: AnthropicRequest, settings: &Config) -> O
             "user" => {
                 if let Some(content_array) = message.content.as_array() {
                     let mut user_text = String::new();
+                    let mut images = Vec::new();
                     for content in content_array {
                         if content["type"] == "text" {
                             user_text.push_str(content["text"].as_str().unwrap_or(""));
+                        } else if content["type"] == "image" {
+                            images.push(json!({
+                                "type": "image_url",
+                                "image_url": { "url": image_url(&content["source"]) },
+                            }));
                         } else if content["type"] == "tool_result" {
                             openapi_messages.push(OpenAIMessage {
                                 role: "tool".to_string(),
-                                content: Some(content["content"].to_string()),
+                                content: Some(content["content"].to_string().into()),
                                 tool_call_id: Some(
                                     content["tool_use_id"].as_str().unwrap_or("").to_string(),
                                 ),
@@ -47,10 +53,20 @@ pub fn format_anthropic_to_openai(req: AnthropicRequest, settings: &Config) -> O
                             });
                         }
                     }
-                    if !user_text.is_empty() {
+                    if !images.is_empty() {
+                        if !user_text.is_empty() {
+                            images.insert(0, json!({ "type": "text", "text": user_text }));
+                        }
+                        openapi_messages.push(OpenAIMessage {
+                            role: "user".to_string(),
+                            content: Some(OpenAIContent::Parts(images)),
+                            tool_calls: None,
+                            tool_call_id: None,
+                        });
+                    } else if !user_text.is_empty() {
                         openapi_messages.push(OpenAIMessage {
                             role: "user".to_string(),
-                            content: Some(user_text),
+                            content: Some(user_text.into()),
                             tool_calls: None,
                             tool_call_id: None,
                         });
@@ -58,7 +74,7 @@ pub fn format_anthropic_to_openai(req: AnthropicRequest, settings: &Config) -> O
                 } else if let Some(content_str) = message.content.as_str() {
                     openapi_messages.push(OpenAIMessage {
                         role: "user".to_string(),
-                        content: Some(content_str.to_string()),
+                        content: Some(content_str.to_string().into()),
                         tool_calls: None,
                         tool_call_id: None,
                     });
@@ -89,7 +105,7 @@ pub fn format_anthropic_to_openai(req: AnthropicRequest, settings: &Config) -> O
                         }
                     }
                     if !assistant_text.is_empty() {
-                        assistant_message.content = Some(assistant_text);
+                        assistant_message.content = Some(assistant_text.into());
                     }
                 }
                 if !tool_calls.is_empty() {
@@ -121,10 +137,79 @@ pub fn format_anthropic_to_openai(req: AnthropicRequest, settings: &Config) -> O
     }
 
     OpenAIRequest {
-        model: map_model(&req.model, settings),
+        model: route.model.clone(),
         messages: openapi_messages,
         temperature: req.temperature,
         stream: req.stream,
         tools,
+        max_tokens: req.max_tokens,
+        stream_options: req
+            .stream
+            .unwrap_or(false)
+            .then(|| json!({ "include_usage": true })),
+        models: route
+            .native_fallbacks
+            .then(|| route.candidates().cloned().collect()),
+        provider: (!route.provider.is_default()).then(|| route.provider.clone()),
+        transforms: None,
+    }
+}
+
+/// The OpenAI `finish_reason` corresponding to an Anthropic `stop_reason`
+pub fn finish_reason_for_stop_reason(stop_reason: &str) -> &'static str {
+    match stop_reason {
+        "tool_use" => "tool_calls",
+        "max_tokens" => "length",
+        "refusal" => "content_filter",
+        _ => "stop",
     }
 }
+
+/// Convert an Anthropic Messages response into an OpenAI Chat Completions response.
+pub fn format_anthropic_response_to_openai(resp: AnthropicResponse) -> serde_json::Value {
+    let mut text = String::new();
+    let mut tool_calls = Vec::new();
+    for block in &resp.content {
+        match block["type"].as_str() {
+            Some("text") => text.push_str(block["text"].as_str().unwrap_or("")),
+            Some("tool_use") => tool_calls.push(json!({
+                "id": block["id"],
+                "type": "function",
+                "function": {
+                    "name": block["name"],
+                    "arguments": block["input"].to_string(),
+                }
+            })),
+            _ => {}
+        }
+    }
+
+    let mut message = json!({
+        "role": "assistant",
+        "content": if text.is_empty() { serde_json::Value::Null } else { json!(text) },
+    });
+    if !tool_calls.is_empty() {
+        message["tool_calls"] = json!(tool_calls);
+    }
+
+    let usage = resp.usage.unwrap_or_default();
+    json!({
+        "id": resp.id,
+        "object": "chat.completion",
+        "created": std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+        "model": resp.model,
+        "choices": [{
+            "index": 0,
+            "message": message,
+            "finish_reason": finish_reason_for_stop_reason(&resp.stop_reason),
+        }],
+        "usage": {
+            "prompt_tokens": usage.input_tokens,
+            "completion_tokens": usage.output_tokens,
+            "total_tokens": usage.input_tokens + usage.output_tokens,
+        }
+    })
+}
@@ -0,0 +1,785 @@
+use crate::config::BatchConfig;
+use crate::models::AnthropicRequest;
+use crate::openai_to_anthropic::error_type_for_status;
+use crate::routing::{self, RequestFeatures};
+use crate::{AppState, client_api_key, complete_message, error_response};
+use axum::{
+    extract::{Json, Path, Query, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Duration, Utc};
+use futures_util::stream::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tokio::sync::Semaphore;
+
+/// Batches expire (and unprocessed requests are marked `expired`) after this long
+const BATCH_LIFETIME_HOURS: i64 = 24;
+/// Upper limit of requests in a single batch, matching Anthropic's limit
+const MAX_BATCH_REQUESTS: usize = 100_000;
+
+/// A batch as persisted to disk, one JSON file per batch
+#[derive(Clone, Serialize, Deserialize)]
+struct StoredBatch {
+    id: String,
+    created_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+    #[serde(default)]
+    ended_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    cancel_initiated_at: Option<DateTime<Utc>>,
+    items: Vec<BatchItem>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct BatchItem {
+    custom_id: String,
+    params: Value,
+    /// The Anthropic result object, `None` while the request is still processing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+}
+
+impl StoredBatch {
+    fn processing_status(&self) -> &'static str {
+        if self.ended_at.is_some() {
+            "ended"
+        } else if self.cancel_initiated_at.is_some() {
+            "canceling"
+        } else {
+            "in_progress"
+        }
+    }
+
+    /// The batch in Anthropic's `message_batch` format
+    fn to_json(&self) -> Value {
+        let mut counts: HashMap<&str, usize> = ["succeeded", "errored", "canceled", "expired"]
+            .into_iter()
+            .map(|kind| (kind, 0))
+            .collect();
+        let mut processing = 0;
+        for item in &self.items {
+            match item.result.as_ref().and_then(|r| r["type"].as_str()) {
+                Some(kind) => *counts.entry(kind).or_default() += 1,
+                None => processing += 1,
+            }
+        }
+
+        json!({
+            "id": self.id,
+            "type": "message_batch",
+            "processing_status": self.processing_status(),
+            "request_counts": {
+                "processing": processing,
+                "succeeded": counts["succeeded"],
+                "errored": counts["errored"],
+                "canceled": counts["canceled"],
+                "expired": counts["expired"],
+            },
+            "ended_at": self.ended_at,
+            "created_at": self.created_at,
+            "expires_at": self.expires_at,
+            "archived_at": null,
+            "cancel_initiated_at": self.cancel_initiated_at,
+            "results_url": self
+                .ended_at
+                .map(|_| format!("/v1/messages/batches/{}/results", self.id)),
+        })
+    }
+}
+
+/// A finished request as appended to a batch's results file
+#[derive(Serialize, Deserialize)]
+struct StoredResult {
+    index: usize,
+    result: Value,
+}
+
+/// Persistent store of all batches known to the proxy. Each batch is written to
+/// `<id>.json` when it is created, canceled or ends; results of single requests are
+/// appended to `<id>.results.jsonl` in between, which is folded into the batch file
+/// on the next full write.
+pub struct BatchStore {
+    dir: PathBuf,
+    batches: Mutex<HashMap<String, StoredBatch>>,
+    /// Held across writes so a batch file never goes back to an older state
+    files: tokio::sync::Mutex<()>,
+    permits: Semaphore,
+    concurrency: usize,
+}
+
+/// Run blocking file I/O off the async worker threads.
+async fn blocking(
+    task: impl FnOnce() -> std::io::Result<()> + Send + 'static,
+) -> Result<(), String> {
+    tokio::task::spawn_blocking(task)
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+}
+
+/// Read a batch file along with the results appended since it was written.
+fn read_batch(path: &std::path::Path) -> Result<StoredBatch, String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let mut batch: StoredBatch = serde_json::from_str(&contents).map_err(|e| e.to_string())?;
+    let results = match std::fs::read_to_string(path.with_extension("results.jsonl")) {
+        Ok(results) => results,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(batch),
+        Err(e) => return Err(e.to_string()),
+    };
+    // A line cut short by a crash is dropped, and its request processed again
+    for stored in results
+        .lines()
+        .filter_map(|line| serde_json::from_str::<StoredResult>(line).ok())
+    {
+        if let Some(item) = batch.items.get_mut(stored.index) {
+            item.result = Some(stored.result);
+        }
+    }
+    Ok(batch)
+}
+
+impl BatchStore {
+    /// Load all batches persisted in the configured directory.
+    pub fn load(config: &BatchConfig) -> Result<Self, String> {
+        let dir = PathBuf::from(&config.dir);
+        let unreadable = |e: std::io::Error| format!("{}: {e}", dir.display());
+        std::fs::create_dir_all(&dir).map_err(unreadable)?;
+
+        let mut batches = HashMap::new();
+        for entry in std::fs::read_dir(&dir).map_err(unreadable)? {
+            let path = entry.map_err(unreadable)?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            match read_batch(&path) {
+                Ok(batch) => {
+                    batches.insert(batch.id.clone(), batch);
+                }
+                Err(e) => tracing::warn!("Skipping unreadable batch file {}: {e}", path.display()),
+            }
+        }
+
+        let concurrency = config.concurrency.max(1);
+        Ok(BatchStore {
+            dir,
+            batches: Mutex::new(batches),
+            files: tokio::sync::Mutex::new(()),
+            permits: Semaphore::new(concurrency),
+            concurrency,
+        })
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.json"))
+    }
+
+    /// Write the whole batch, replacing its results file.
+    async fn persist(&self, id: &str) -> Result<(), String> {
+        let _files = self.files.lock().await;
+        // Taken under the lock, so it holds every result appended so far
+        let Some(batch) = self.get(id) else {
+            return Ok(());
+        };
+        let json = serde_json::to_vec(&batch).map_err(|e| e.to_string())?;
+        let path = self.path(id);
+        blocking(move || {
+            let partial = path.with_extension("json.tmp");
+            std::fs::write(&partial, json)?;
+            std::fs::rename(&partial, &path)?;
+            match std::fs::remove_file(path.with_extension("results.jsonl")) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            }
+        })
+        .await
+    }
+
+    fn get(&self, id: &str) -> Option<StoredBatch> {
+        self.batches.lock().unwrap().get(id).cloned()
+    }
+
+    /// Add a new batch and persist it.
+    async fn insert(&self, batch: StoredBatch) -> Result<(), String> {
+        let id = batch.id.clone();
+        self.batches.lock().unwrap().insert(id.clone(), batch);
+        let persisted = self.persist(&id).await;
+        if persisted.is_err() {
+            self.batches.lock().unwrap().remove(&id);
+        }
+        persisted
+    }
+
+    /// Apply `change` to a batch and persist the result.
+    async fn update(
+        &self,
+        id: &str,
+        change: impl FnOnce(&mut StoredBatch),
+    ) -> Option<Result<StoredBatch, String>> {
+        let batch = {
+            let mut batches = self.batches.lock().unwrap();
+            let batch = batches.get_mut(id)?;
+            change(batch);
+            batch.clone()
+        };
+        Some(self.persist(id).await.map(|()| batch))
+    }
+
+    /// Record the result of a single request by appending it to the results file.
+    async fn record(&self, id: &str, index: usize, result: Value) -> Result<(), String> {
+        let line = serde_json::to_string(&StoredResult {
+            index,
+            result: result.clone(),
+        })
+        .map_err(|e| e.to_string())?;
+        match self.batches.lock().unwrap().get_mut(id) {
+            Some(batch) => batch.items[index].result = Some(result),
+            None => return Ok(()),
+        }
+
+        let _files = self.files.lock().await;
+        let path = self.path(id).with_extension("results.jsonl");
+        blocking(move || {
+            use std::io::Write;
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?;
+            file.write_all(format!("{line}\n").as_bytes())
+        })
+        .await
+    }
+
+    /// Remove an ended batch and its files.
+    async fn remove(&self, id: &str) -> Result<(), String> {
+        self.batches.lock().unwrap().remove(id);
+        let _files = self.files.lock().await;
+        let path = self.path(id);
+        blocking(move || {
+            for path in [path.with_extension("results.jsonl"), path] {
+                match std::fs::remove_file(path) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            Ok(())
+        })
+        .await
+    }
+}
+
+/// Resume processing of all batches that had not ended when the proxy last stopped.
+pub async fn resume(state: &AppState) {
+    let unfinished: Vec<String> = state
+        .batches
+        .batches
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|b| b.ended_at.is_none())
+        .map(|b| b.id.clone())
+        .collect();
+    if unfinished.is_empty() {
+        return;
+    }
+
+    // The client's key is never written to disk, so resumed batches use the configured key
+    let api_key = state.config.read().await.api_key.clone();
+    for id in unfinished {
+        println!("Resuming message batch {id}");
+        tokio::spawn(process_batch(state.clone(), id, api_key.clone()));
+    }
+}
+
+async fn process_batch(state: AppState, id: String, api_key: String) {
+    let Some(batch) = state.batches.get(&id) else {
+        return;
+    };
+    let pending: Vec<(usize, Value)> = batch
+        .items
+        .iter()
+        .enumerate()
+        .filter(|(_, item)| item.result.is_none())
+        .map(|(index, item)| (index, item.params.clone()))
+        .collect();
+
+    futures_util::stream::iter(pending)
+        .for_each_concurrent(state.batches.concurrency, |(index, params)| {
+            let state = state.clone();
+            let id = id.clone();
+            let api_key = api_key.clone();
+            async move {
+                let _permit = state.batches.permits.acquire().await.unwrap();
+                let Some((canceled, expires_at)) = state
+                    .batches
+                    .batches
+                    .lock()
+                    .unwrap()
+                    .get(&id)
+                    .map(|b| (b.cancel_initiated_at.is_some(), b.expires_at))
+                else {
+                    return;
+                };
+
+                let result = if canceled {
+                    json!({ "type": "canceled" })
+                } else if Utc::now() > expires_at {
+                    json!({ "type": "expired" })
+                } else {
+                    match serde_json::from_value::<AnthropicRequest>(params) {
+                        Ok(mut request) => {
+                            request.stream = None;
+                            let features = RequestFeatures::from_anthropic(&request);
+                            let route = routing::resolve(
+                                &request.model,
+                                &features,
+                                &*state.config.read().await,
+                            );
+                            match route {
+                                Ok(route) => {
+                                    routing::record(&request.model, &route, &features);
+                                    let result = complete_message(
+                                        &state, &api_key, request, &features, None, route,
+                                    )
+                                    .await;
+                                    match result {
+                                        Ok(message) => {
+                                            json!({ "type": "succeeded", "message": message })
+                                        }
+                                        Err((status, body)) => {
+                                            errored_result(status.as_u16(), body)
+                                        }
+                                    }
+                                }
+                                Err(e) => errored_result(e.status().as_u16(), e.to_string()),
+                            }
+                        }
+                        Err(e) => errored_result(400, e.to_string()),
+                    }
+                };
+
+                // The result stays in memory; after a restart the request is sent again
+                if let Err(e) = state.batches.record(&id, index, result).await {
+                    tracing::error!("Could not save a result of message batch {id}: {e}");
+                }
+            }
+        })
+        .await;
+
+    let ended = state.batches.update(&id, |batch| {
+        batch.ended_at = Some(Utc::now());
+    });
+    if let Some(Err(e)) = ended.await {
+        tracing::error!("Could not save message batch {id}: {e}");
+    }
+    tracing::info!("Message batch {id} ended");
+}
+
+fn errored_result(status: u16, message: String) -> Value {
+    json!({
+        "type": "errored",
+        "error": {
+            "type": "error",
+            "error": {
+                "type": error_type_for_status(status),
+                "message": message,
+            }
+        }
+    })
+}
+
+fn not_found(id: &str) -> Response {
+    error_response(
+        StatusCode::NOT_FOUND,
+        format!("message_batch: {id} not found"),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct CreateBatch {
+    requests: Vec<BatchRequest>,
+}
+
+#[derive(Deserialize)]
+pub struct BatchRequest {
+    custom_id: String,
+    params: Value,
+}
+
+#[derive(Deserialize)]
+pub struct ListParams {
+    limit: Option<usize>,
+    after_id: Option<String>,
+    before_id: Option<String>,
+}
+
+/// POST /v1/messages/batches - Create a batch and start processing it in the background
+pub async fn create_batch(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(create): Json<CreateBatch>,
+) -> Response {
+    if create.requests.is_empty() || create.requests.len() > MAX_BATCH_REQUESTS {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            format!("requests: must contain between 1 and {MAX_BATCH_REQUESTS} items"),
+        );
+    }
+    let mut custom_ids = HashSet::new();
+    for request in &create.requests {
+        if !custom_ids.insert(request.custom_id.as_str()) {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                format!("custom_id: duplicate value {}", request.custom_id),
+            );
+        }
+        if let Err(e) = serde_json::from_value::<AnthropicRequest>(request.params.clone()) {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                format!("{}: invalid params: {e}", request.custom_id),
+            );
+        }
+    }
+
+    let api_key = match client_api_key(&headers) {
+        Some(key) => key,
+        None => state.config.read().await.api_key.clone(),
+    };
+
+    let now = Utc::now();
+    let batch = StoredBatch {
+        id: format!(
+            "msgbatch_{:x}",
+            now.timestamp_nanos_opt().unwrap_or_default()
+        ),
+        created_at: now,
+        expires_at: now + Duration::hours(BATCH_LIFETIME_HOURS),
+        ended_at: None,
+        cancel_initiated_at: None,
+        items: create
+            .requests
+            .into_iter()
+            .map(|r| BatchItem {
+                custom_id: r.custom_id,
+                params: r.params,
+                result: None,
+            })
+            .collect(),
+    };
+    let created = batch.to_json();
+    if let Err(e) = state.batches.insert(batch.clone()).await {
+        tracing::error!("Could not save message batch {}: {e}", batch.id);
+        return error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to save the batch",
+        );
+    }
+
+    tokio::spawn(process_batch(state.clone(), batch.id.clone(), api_key));
+
+    Json(created).into_response()
+}
+
+/// GET /v1/messages/batches - List batches, most recently created first
+pub async fn list_batches(
+    State(state): State<AppState>,
+    Query(params): Query<ListParams>,
+) -> impl IntoResponse {
+    let mut batches: Vec<StoredBatch> = state
+        .batches
+        .batches
+        .lock()
+        .unwrap()
+        .values()
+        .cloned()
+        .collect();
+    batches.sort_by_key(|b| std::cmp::Reverse(b.created_at));
+
+    if let Some(after_id) = &params.after_id
+        && let Some(index) = batches.iter().position(|b| &b.id == after_id)
+    {
+        batches.drain(..=index);
+    }
+    if let Some(before_id) = &params.before_id
+        && let Some(index) = batches.iter().position(|b| &b.id == before_id)
+    {
+        batches.truncate(index);
+    }
+    let limit = params.limit.unwrap_or(20).clamp(1, 1000);
+    let has_more = batches.len() > limit;
+    batches.truncate(limit);
+
+    Json(json!({
+        "data": batches.iter().map(StoredBatch::to_json).collect::<Vec<_>>(),
+        "has_more": has_more,
+        "first_id": batches.first().map(|b| b.id.clone()),
+        "last_id": batches.last().map(|b| b.id.clone()),
+    }))
+}
+
+/// GET /v1/messages/batches/{id} - Retrieve the status of a batch
+pub async fn get_batch(State(state): State<AppState>, Path(id): Path<String>) -> Response {
+    match state.batches.get(&id) {
+        Some(batch) => Json(batch.to_json()).into_response(),
+        None => not_found(&id),
+    }
+}
+
+/// GET /v1/messages/batches/{id}/results - Stream the results of an ended batch as JSONL
+pub async fn batch_results(State(state): State<AppState>, Path(id): Path<String>) -> Response {
+    let Some(batch) = state.batches.get(&id) else {
+        return not_found(&id);
+    };
+    if batch.ended_at.is_none() {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            format!("message_batch: {id} has not finished processing"),
+        );
+    }
+
+    let mut body = String::new();
+    for item in &batch.items {
+        let line = json!({ "custom_id": item.custom_id, "result": item.result });
+        body.push_str(&line.to_string());
+        body.push('\n');
+    }
+
+    ([(header::CONTENT_TYPE, "application/x-jsonl")], body).into_response()
+}
+
+/// POST /v1/messages/batches/{id}/cancel - Cancel all requests that have not started yet
+pub async fn cancel_batch(State(state): State<AppState>, Path(id): Path<String>) -> Response {
+    let updated = state.batches.update(&id, |batch| {
+        if batch.ended_at.is_none() && batch.cancel_initiated_at.is_none() {
+            batch.cancel_initiated_at = Some(Utc::now());
+        }
+    });
+    match updated.await {
+        Some(Ok(batch)) => Json(batch.to_json()).into_response(),
+        Some(Err(e)) => {
+            tracing::error!("Could not save message batch {id}: {e}");
+            error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to save the batch",
+            )
+        }
+        None => not_found(&id),
+    }
+}
+
+/// DELETE /v1/messages/batches/{id} - Delete an ended batch and its results
+pub async fn delete_batch(State(state): State<AppState>, Path(id): Path<String>) -> Response {
+    match state.batches.get(&id) {
+        None => return not_found(&id),
+        Some(batch) if batch.ended_at.is_none() => {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                format!("message_batch: {id} must be ended or canceled before deletion"),
+            );
+        }
+        Some(_) => {}
+    }
+    if let Err(e) = state.batches.remove(&id).await {
+        tracing::error!("Could not delete the files of message batch {id}: {e}");
+    }
+
+    Json(json!({ "id": id, "type": "message_batch_deleted" })).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use axum::body::to_bytes;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tempfile::TempDir;
+
+    /// An OpenAI upstream answering every request after `delay`, counting the requests
+    async fn upstream(delay: std::time::Duration) -> (String, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let app = axum::Router::new().route(
+            "/chat/completions",
+            axum::routing::post(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    tokio::time::sleep(delay).await;
+                    Json(json!({
+                        "id": "c1",
+                        "model": "a/b",
+                        "choices": [{
+                            "index": 0,
+                            "message": { "role": "assistant", "content": "hi" },
+                            "finish_reason": "stop",
+                        }],
+                        "usage": { "prompt_tokens": 3, "completion_tokens": 1 },
+                    }))
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (format!("http://{addr}"), requests)
+    }
+
+    /// A state sending batch requests to `base_url`, with batches kept in `dir`
+    fn state(dir: &TempDir, base_url: String, concurrency: usize) -> AppState {
+        let config = Config {
+            base_url,
+            api_key: "key".to_string(),
+            batches: BatchConfig {
+                dir: dir.path().to_string_lossy().into_owned(),
+                concurrency,
+            },
+            ..Config::default()
+        };
+        AppState::for_tests(config)
+    }
+
+    fn requests(count: usize) -> CreateBatch {
+        CreateBatch {
+            requests: (0..count)
+                .map(|i| BatchRequest {
+                    custom_id: format!("r{i}"),
+                    params: json!({
+                        "model": "a/b",
+                        "max_tokens": 16,
+                        "messages": [{ "role": "user", "content": "hello" }],
+                    }),
+                })
+                .collect(),
+        }
+    }
+
+    async fn json_body(response: Response) -> Value {
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    async fn ended(state: &AppState, id: &str) -> StoredBatch {
+        for _ in 0..200 {
+            if let Some(batch) = state.batches.get(id).filter(|b| b.ended_at.is_some()) {
+                return batch;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("batch {id} did not end");
+    }
+
+    #[tokio::test]
+    async fn processes_submitted_batches_and_returns_results() {
+        let (base_url, _) = upstream(std::time::Duration::ZERO).await;
+        let dir = TempDir::new().unwrap();
+        let state = state(&dir, base_url, 2);
+        let created = json_body(
+            create_batch(State(state.clone()), HeaderMap::new(), Json(requests(3))).await,
+        )
+        .await;
+        assert_eq!(created["processing_status"], "in_progress");
+        let id = created["id"].as_str().unwrap().to_string();
+
+        ended(&state, &id).await;
+        let response = batch_results(State(state.clone()), Path(id.clone())).await;
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let lines: Vec<Value> = std::str::from_utf8(&body)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1]["custom_id"], "r1");
+        assert_eq!(lines[1]["result"]["type"], "succeeded");
+        assert_eq!(lines[1]["result"]["message"]["content"][0]["text"], "hi");
+
+        // The ended batch is written in one file, with its results folded in
+        let path = state.batches.path(&id);
+        assert!(!path.with_extension("results.jsonl").exists());
+        let stored = read_batch(&path).unwrap();
+        assert!(stored.ended_at.is_some());
+        assert!(stored.items.iter().all(|item| item.result.is_some()));
+    }
+
+    #[tokio::test]
+    async fn cancels_requests_that_have_not_started() {
+        let (base_url, _) = upstream(std::time::Duration::from_millis(200)).await;
+        let dir = TempDir::new().unwrap();
+        let state = state(&dir, base_url, 1);
+        let created = json_body(
+            create_batch(State(state.clone()), HeaderMap::new(), Json(requests(3))).await,
+        )
+        .await;
+        let id = created["id"].as_str().unwrap().to_string();
+
+        let canceling = json_body(cancel_batch(State(state.clone()), Path(id.clone())).await).await;
+        assert_eq!(canceling["processing_status"], "canceling");
+        assert!(
+            read_batch(&state.batches.path(&id))
+                .unwrap()
+                .cancel_initiated_at
+                .is_some()
+        );
+
+        let batch = ended(&state, &id).await;
+        let canceled = batch
+            .items
+            .iter()
+            .filter(|item| item.result.as_ref().unwrap()["type"] == "canceled")
+            .count();
+        assert!(canceled >= 2);
+        assert_eq!(batch.to_json()["processing_status"], "ended");
+    }
+
+    #[tokio::test]
+    async fn resumes_unfinished_batches_from_their_results() {
+        let (base_url, upstream_requests) = upstream(std::time::Duration::ZERO).await;
+        let dir = TempDir::new().unwrap();
+        let state = state(&dir, base_url, 2);
+        let now = Utc::now();
+        let batch = StoredBatch {
+            id: "msgbatch_resumed".to_string(),
+            created_at: now,
+            expires_at: now + Duration::hours(BATCH_LIFETIME_HOURS),
+            ended_at: None,
+            cancel_initiated_at: None,
+            items: requests(3)
+                .requests
+                .into_iter()
+                .map(|r| BatchItem {
+                    custom_id: r.custom_id,
+                    params: r.params,
+                    result: None,
+                })
+                .collect(),
+        };
+        // Written before the restart: one result appended, and a line cut short
+        let path = state.batches.path(&batch.id);
+        std::fs::write(&path, serde_json::to_string(&batch).unwrap()).unwrap();
+        let finished = json!({ "index": 1, "result": { "type": "errored" } }).to_string();
+        std::fs::write(
+            path.with_extension("results.jsonl"),
+            format!("{finished}\n{{\"index\":2,\"res"),
+        )
+        .unwrap();
+
+        let settings = state.config.read().await.batches.clone();
+        let state = AppState {
+            batches: Arc::new(BatchStore::load(&settings).unwrap()),
+            ..state
+        };
+        let loaded = state.batches.get("msgbatch_resumed").unwrap();
+        assert_eq!(loaded.items[1].result.as_ref().unwrap()["type"], "errored");
+        assert!(loaded.items[2].result.is_none());
+
+        resume(&state).await;
+        let batch = ended(&state, "msgbatch_resumed").await;
+        assert_eq!(upstream_requests.load(Ordering::SeqCst), 2);
+        assert_eq!(batch.items[0].result.as_ref().unwrap()["type"], "succeeded");
+        assert_eq!(batch.items[1].result.as_ref().unwrap()["type"], "errored");
+    }
+}
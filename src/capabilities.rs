@@ -0,0 +1,309 @@
+use crate::AppState;
+use crate::metrics::METRICS;
+use crate::models::AnthropicRequest;
+use crate::openrouter::{Model, ModelSource};
+use crate::overflow::OverflowStrategy;
+use crate::routing::RequestFeatures;
+use axum::http::StatusCode;
+use serde_json::{Value, json};
+use std::sync::Arc;
+
+/// What a model accepts, taken from the OpenRouter model list
+#[derive(Debug, PartialEq)]
+pub struct Capabilities {
+    pub context_length: Option<u64>,
+    pub max_completion_tokens: Option<u32>,
+    pub images: bool,
+    pub tools: bool,
+}
+
+impl Capabilities {
+    /// Capabilities of a listed model; anything the listing does not state is assumed supported.
+    pub fn of(model: &Model) -> Self {
+        let input_modalities = model
+            .architecture
+            .as_ref()
+            .and_then(|a| a.modality.as_deref())
+            .and_then(|modality| modality.split("->").next());
+        Capabilities {
+            context_length: model.context_length.and_then(|c| u64::try_from(c).ok()),
+            max_completion_tokens: model
+                .top_provider
+                .as_ref()
+                .and_then(|p| p.max_completion_tokens)
+                .and_then(|t| u32::try_from(t).ok()),
+            images: input_modalities.is_none_or(|m| m.contains("image")),
+            tools: model
+                .supported_parameters
+                .as_ref()
+                .is_none_or(|params| params.iter().any(|p| p == "tools")),
+        }
+    }
+}
+
+fn record(kind: &'static str) {
+    METRICS.inc("ccorp_request_adaptations_total", &[("kind", kind)]);
+}
+
+fn clamp_max_tokens(req: &mut AnthropicRequest, model: &str, limit: u32) {
+    if let Some(max_tokens) = req.max_tokens
+        && max_tokens > limit
+    {
+        tracing::info!("Clamping max_tokens from {max_tokens} to {limit} for {model}");
+        req.max_tokens = Some(limit);
+        record("max_tokens_clamped");
+    }
+}
+
+/// Replace image blocks, including those nested in tool results, with a text notice.
+fn strip_images(content: &mut Value, notice: &str) -> bool {
+    let Some(blocks) = content.as_array_mut() else {
+        return false;
+    };
+    let mut stripped = false;
+    for block in blocks {
+        if block["type"] == "image" {
+            *block = json!({ "type": "text", "text": notice });
+            stripped = true;
+        } else if block["type"] == "tool_result" {
+            stripped |= strip_images(&mut block["content"], notice);
+        }
+    }
+    stripped
+}
+
+/// The text of a tool result, whose content is either a string or a list of blocks.
+fn tool_result_text(content: &Value) -> String {
+    match content {
+        Value::String(text) => text.clone(),
+        Value::Array(blocks) => blocks
+            .iter()
+            .filter_map(|block| block["text"].as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
+
+/// Turn tool calls and results in the history into text, for models that reject tool messages.
+fn flatten_tool_history(content: &mut Value) {
+    let Some(blocks) = content.as_array_mut() else {
+        return;
+    };
+    for block in blocks {
+        let text = match block["type"].as_str() {
+            Some("tool_use") => format!(
+                "[Called tool {} with {}]",
+                block["name"].as_str().unwrap_or_default(),
+                block["input"]
+            ),
+            Some("tool_result") => format!(
+                "[Result of tool call {}]\n{}",
+                block["tool_use_id"].as_str().unwrap_or_default(),
+                tool_result_text(&block["content"])
+            ),
+            _ => continue,
+        };
+        *block = json!({ "type": "text", "text": text });
+    }
+}
+
+/// Adapt `req` to what `model` supports, or explain why the model cannot serve it.
+pub fn adapt(
+    req: &mut AnthropicRequest,
+    model: &str,
+    capabilities: &Capabilities,
+    prompt_tokens: u64,
+    overflow: Option<OverflowStrategy>,
+) -> Result<(), (StatusCode, String)> {
+    // With an overflow strategy, long prompts are shortened after translation
+    if let Some(context_length) = capabilities.context_length
+        && overflow.is_none()
+    {
+        if prompt_tokens >= context_length {
+            record("context_exceeded");
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "prompt is too long: ~{prompt_tokens} tokens > {context_length} maximum of {model}"
+                ),
+            ));
+        }
+        let remaining = u32::try_from(context_length - prompt_tokens).unwrap_or(u32::MAX);
+        clamp_max_tokens(req, model, remaining);
+    }
+    if let Some(limit) = capabilities.max_completion_tokens {
+        clamp_max_tokens(req, model, limit);
+    }
+
+    if !capabilities.images {
+        let notice = format!("[Image omitted: {model} does not accept images]");
+        let mut stripped = false;
+        for message in &mut req.messages {
+            stripped |= strip_images(&mut message.content, &notice);
+        }
+        if stripped {
+            tracing::info!("Removed images from a request to text-only model {model}");
+            record("images_stripped");
+        }
+    }
+
+    if !capabilities.tools && req.tools.take().is_some() {
+        tracing::info!("Dropped tools from a request to {model}, which does not support them");
+        req.tool_choice = None;
+        for message in &mut req.messages {
+            flatten_tool_history(&mut message.content);
+        }
+        record("tools_dropped");
+    }
+
+    Ok(())
+}
+
+/// The OpenRouter model list for capability checks, empty while it is unavailable.
+pub async fn catalog(state: &AppState) -> Arc<Vec<Model>> {
+    let source = ModelSource::new(&*state.config.read().await);
+    match state.models_cache.get(&state.http, &source).await {
+        Ok(models) => models,
+        Err(e) => {
+            tracing::debug!("Skipping capability checks, model list unavailable: {e}");
+            Arc::default()
+        }
+    }
+}
+
+/// Adapt a request to `model`, when the catalog lists that model.
+pub fn adapt_to_model(
+    req: &mut AnthropicRequest,
+    model: &str,
+    catalog: &[Model],
+    features: &RequestFeatures,
+    overflow: Option<OverflowStrategy>,
+) -> Result<(), (StatusCode, String)> {
+    match catalog.iter().find(|m| m.id == model) {
+        Some(listed) => adapt(
+            req,
+            model,
+            &Capabilities::of(listed),
+            features.prompt_tokens,
+            overflow,
+        ),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn model(listing: Value) -> Model {
+        serde_json::from_value(listing).unwrap()
+    }
+
+    fn request() -> AnthropicRequest {
+        serde_json::from_value(json!({
+            "model": "claude-sonnet-4",
+            "max_tokens": 32000,
+            "tools": [{ "name": "read", "input_schema": {} }],
+            "tool_choice": { "type": "auto" },
+            "messages": [
+                { "role": "user", "content": [
+                    { "type": "text", "text": "look" },
+                    { "type": "image", "source": { "type": "base64", "media_type": "image/png", "data": "AAAA" } }
+                ]},
+                { "role": "assistant", "content": [{ "type": "tool_use", "id": "t1", "name": "read", "input": {} }] },
+                { "role": "user", "content": [{ "type": "tool_result", "tool_use_id": "t1", "content": "ok" }] }
+            ]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn capabilities_from_listing() {
+        let listed = model(json!({
+            "id": "a/b", "name": "B", "context_length": 131072,
+            "architecture": { "modality": "text->text" },
+            "top_provider": { "max_completion_tokens": 16384 },
+            "supported_parameters": ["max_tokens", "temperature"],
+        }));
+        assert_eq!(
+            Capabilities::of(&listed),
+            Capabilities {
+                context_length: Some(131072),
+                max_completion_tokens: Some(16384),
+                images: false,
+                tools: false,
+            }
+        );
+
+        let sparse = model(json!({ "id": "a/c", "name": "C" }));
+        let capabilities = Capabilities::of(&sparse);
+        assert!(capabilities.images && capabilities.tools);
+    }
+
+    #[test]
+    fn adapts_request_to_limited_model() {
+        let capabilities = Capabilities {
+            context_length: Some(20000),
+            max_completion_tokens: Some(16384),
+            images: false,
+            tools: false,
+        };
+        let mut req = request();
+        adapt(&mut req, "a/b", &capabilities, 5000, None).unwrap();
+
+        assert_eq!(req.max_tokens, Some(15000));
+        assert!(req.tools.is_none() && req.tool_choice.is_none());
+        assert_eq!(
+            req.messages[0].content[1]["text"],
+            "[Image omitted: a/b does not accept images]"
+        );
+        assert_eq!(
+            req.messages[1].content[0]["text"],
+            "[Called tool read with {}]"
+        );
+        assert_eq!(
+            req.messages[2].content[0]["text"],
+            "[Result of tool call t1]\nok"
+        );
+
+        let (status, message) =
+            adapt(&mut request(), "a/b", &capabilities, 25000, None).unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(message.starts_with("prompt is too long"));
+    }
+
+    #[test]
+    fn adapts_to_each_listed_model() {
+        let catalog = vec![
+            model(json!({
+                "id": "a/text", "name": "Text", "context_length": 8000,
+                "architecture": { "modality": "text->text" },
+                "supported_parameters": ["tools"],
+            })),
+            model(json!({
+                "id": "b/vision", "name": "Vision", "context_length": 200000,
+                "architecture": { "modality": "text+image->text" },
+                "supported_parameters": ["tools"],
+            })),
+        ];
+        let features = RequestFeatures {
+            prompt_tokens: 10000,
+            ..RequestFeatures::default()
+        };
+
+        let (status, _) =
+            adapt_to_model(&mut request(), "a/text", &catalog, &features, None).unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let mut req = request();
+        adapt_to_model(&mut req, "b/vision", &catalog, &features, None).unwrap();
+        assert_eq!(req.messages[0].content[1]["type"], "image");
+        assert_eq!(req.max_tokens, Some(32000));
+
+        // Models missing from the catalog are left alone
+        let mut req = request();
+        adapt_to_model(&mut req, "local/qwen", &catalog, &features, None).unwrap();
+        assert!(req.tools.is_some());
+    }
+}
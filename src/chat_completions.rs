@@ -0,0 +1,327 @@
+use crate::routing::{self, RequestFeatures};
+use crate::shutdown;
+use crate::upstream::{self, Payload, UpstreamBody, UpstreamStream};
+use crate::{AppState, SERVED_MODEL_HEADER, client_api_key, write_log};
+use axum::{
+    body::{Body, Bytes},
+    extract::{Json, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use serde_json::{Value, json};
+
+/// Build an error response in OpenAI's error format.
+pub fn openai_error_response(status: StatusCode, message: impl Into<String>) -> Response {
+    let body = json!({
+        "error": {
+            "message": message.into(),
+            "type": match status.as_u16() {
+                401 => "authentication_error",
+                429 => "rate_limit_error",
+                500.. => "server_error",
+                _ => "invalid_request_error",
+            },
+            "code": status.as_u16(),
+        }
+    });
+    (status, Json(body)).into_response()
+}
+
+/// An upstream error in OpenAI's error format. OpenAI-style bodies, such as OpenRouter's,
+/// are kept as they are; others, e.g. from Anthropic providers, are rebuilt from their message.
+fn upstream_error_response(status: StatusCode, body: String) -> Response {
+    let error = serde_json::from_str::<Value>(&body).ok();
+    match error.as_ref().filter(|error| error["error"].is_object()) {
+        Some(error) if error["type"] != "error" => Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap(),
+        Some(error) => {
+            let message = error["error"]["message"].as_str().unwrap_or_default();
+            openai_error_response(status, message)
+        }
+        None => openai_error_response(status, body),
+    }
+}
+
+/// POST /v1/chat/completions - OpenAI Chat Completions requests, forwarded with the proxy's model mapping
+pub async fn chat_completions_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(mut payload): Json<Value>,
+) -> Response {
+    let Some(api_key) = client_api_key(&headers) else {
+        return openai_error_response(
+            StatusCode::UNAUTHORIZED,
+            "Missing API key, send it as a bearer token or in x-api-key",
+        );
+    };
+    let Some(model) = payload["model"].as_str() else {
+        return openai_error_response(StatusCode::BAD_REQUEST, "model: field required");
+    };
+
+    let settings_guard = state.config.read().await;
+    let features =
+        RequestFeatures::from_openai(&payload).with_session_header(&headers, &settings_guard);
+    let route = match routing::resolve_request(model, &features, &headers, &settings_guard) {
+        Ok(route) => route,
+        Err((status, message)) => return openai_error_response(status, message),
+    };
+    drop(settings_guard);
+    routing::record(model, &route, &features);
+
+    if !route.provider.is_default() {
+        payload["provider"] = json!(route.provider);
+    }
+    if route.native_fallbacks {
+        payload["models"] = json!(route.candidates().collect::<Vec<_>>());
+    }
+    write_log(
+        &state,
+        "request",
+        &serde_json::to_string_pretty(&payload).unwrap(),
+    );
+
+    let response = match upstream::send(&state, &api_key, &route, Payload::chat(payload)).await {
+        Ok(response) => response,
+        Err(e) => {
+            write_log(&state, "response", &e.message);
+            return upstream_error_response(e.status, e.message);
+        }
+    };
+
+    let upstream_stream = match response.body {
+        UpstreamBody::Complete(body) => {
+            let body = body.to_string();
+            write_log(&state, "response", &body);
+            return Response::builder()
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(SERVED_MODEL_HEADER, response.model)
+                .body(Body::from(body))
+                .unwrap();
+        }
+        UpstreamBody::Stream(upstream_stream) => upstream_stream,
+    };
+
+    // The upstream already speaks OpenAI SSE, so chunks are passed through unchanged
+    let stream = async_stream::stream! {
+        let UpstreamStream { prefix, mut body, .. } = upstream_stream;
+        let mut full_response = String::from_utf8_lossy(&prefix).into_owned();
+        yield Ok::<_, axum::Error>(Bytes::from(prefix));
+        loop {
+            let Some(item) = state.shutdown.next(&mut body).await else {
+                let error = json!({ "error": shutdown::error()["error"] });
+                yield Ok::<_, axum::Error>(Bytes::from(format!("data: {error}\n\n")));
+                break;
+            };
+            let Some(item) = item else {
+                break;
+            };
+            match item {
+                Ok(chunk) => {
+                    full_response.push_str(&String::from_utf8_lossy(&chunk));
+                    yield Ok::<_, axum::Error>(chunk);
+                }
+                Err(e) => {
+                    tracing::error!("OpenRouter stream failed: {e}");
+                    let error = json!({
+                        "error": {
+                            "message": format!("The upstream stream failed: {e}"),
+                            "type": "server_error",
+                            "code": 502,
+                        }
+                    });
+                    yield Ok::<_, axum::Error>(Bytes::from(format!("data: {error}\n\n")));
+                    break;
+                }
+            }
+        }
+        write_log(&state, "response", &full_response);
+    };
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .header(SERVED_MODEL_HEADER, response.model)
+        .body(Body::from_stream(stream))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{BatchConfig, Config};
+    use axum::body::to_bytes;
+    use std::sync::{Arc, Mutex};
+    use tempfile::TempDir;
+
+    /// Serve `app` as the OpenRouter upstream
+    async fn serve(app: axum::Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{addr}")
+    }
+
+    /// An upstream answering every request with `status` and `body`
+    async fn failing_upstream(status: StatusCode, body: &'static str) -> String {
+        serve(axum::Router::new().route(
+            "/chat/completions",
+            axum::routing::post(move || async move { (status, body) }),
+        ))
+        .await
+    }
+
+    fn state(base_url: String, routing: Value, dir: &TempDir) -> AppState {
+        let mut config = Config {
+            base_url,
+            model_sonnet: "a/b".to_string(),
+            routing: serde_json::from_value(routing).unwrap(),
+            batches: BatchConfig {
+                dir: dir.path().to_string_lossy().into_owned(),
+                ..BatchConfig::default()
+            },
+            ..Config::default()
+        };
+        config.retry.max_retries = 0;
+        AppState::for_tests(config)
+    }
+
+    async fn complete(state: AppState, request: Value) -> (StatusCode, HeaderMap, Bytes) {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Bearer key".parse().unwrap());
+        let response = chat_completions_handler(State(state), headers, Json(request)).await;
+        let (parts, body) = response.into_parts();
+        let body = to_bytes(body, usize::MAX).await.unwrap();
+        (parts.status, parts.headers, body)
+    }
+
+    #[tokio::test]
+    async fn routes_requests_through_the_model_mapping() {
+        let received = Arc::new(Mutex::new(None));
+        let seen = received.clone();
+        let base_url = serve(axum::Router::new().route(
+            "/chat/completions",
+            axum::routing::post(move |Json(body): Json<Value>| {
+                *seen.lock().unwrap() = Some(body);
+                async {
+                    Json(json!({
+                        "id": "c1",
+                        "model": "a/b",
+                        "choices": [{
+                            "index": 0,
+                            "message": { "role": "assistant", "content": "hi" },
+                            "finish_reason": "stop",
+                        }],
+                    }))
+                }
+            }),
+        ))
+        .await;
+        let dir = TempDir::new().unwrap();
+        let state = state(base_url, json!({ "reject_unmapped": true }), &dir);
+
+        let (status, headers, body) = complete(
+            state.clone(),
+            json!({
+                "model": "claude-sonnet-4-20250514",
+                "messages": [{ "role": "user", "content": "hello" }],
+                "temperature": 0.2,
+            }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[SERVED_MODEL_HEADER], "a/b");
+        let completion: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(completion["choices"][0]["message"]["content"], "hi");
+        let request = received.lock().unwrap().take().unwrap();
+        assert_eq!(request["model"], "a/b");
+        assert_eq!(request["temperature"], 0.2);
+
+        let (status, _, body) = complete(
+            state,
+            json!({ "model": "gpt-4o", "messages": [{ "role": "user", "content": "hello" }] }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let error: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error["error"]["type"], "invalid_request_error");
+        assert!(received.lock().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn streams_upstream_chunks_unchanged() {
+        let chunks = [
+            r#"data: {"id":"c1","model":"a/b","choices":[{"index":0,"delta":{"role":"assistant","content":""}}]}"#,
+            r#"data: {"id":"c1","model":"a/b","choices":[{"index":0,"delta":{"content":"Hi"}}]}"#,
+            r#"data: {"id":"c1","model":"a/b","choices":[{"index":0,"delta":{},"finish_reason":"stop"}],"usage":{"prompt_tokens":3,"completion_tokens":1}}"#,
+            "data: [DONE]",
+        ]
+        .map(|chunk| format!("{chunk}\n\n"));
+        let sent = chunks.concat();
+        let base_url = serve(axum::Router::new().route(
+            "/chat/completions",
+            axum::routing::post(move || {
+                let chunks = chunks.clone();
+                async move {
+                    let body = futures_util::stream::iter(chunks.map(Ok::<_, std::io::Error>));
+                    Response::builder()
+                        .header(header::CONTENT_TYPE, "text/event-stream")
+                        .body(Body::from_stream(body))
+                        .unwrap()
+                }
+            }),
+        ))
+        .await;
+        let dir = TempDir::new().unwrap();
+
+        let (status, headers, body) = complete(
+            state(base_url, json!({}), &dir),
+            json!({
+                "model": "claude-sonnet-4-20250514",
+                "stream": true,
+                "messages": [{ "role": "user", "content": "hello" }],
+            }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "text/event-stream");
+        assert_eq!(headers[SERVED_MODEL_HEADER], "a/b");
+        assert_eq!(String::from_utf8_lossy(&body), sent);
+    }
+
+    #[tokio::test]
+    async fn forwards_upstream_errors_in_openai_format() {
+        let dir = TempDir::new().unwrap();
+        let request = json!({
+            "model": "claude-sonnet-4-20250514",
+            "messages": [{ "role": "user", "content": "hello" }],
+        });
+
+        // OpenRouter errors already have OpenAI's format and keep their details
+        let openrouter =
+            r#"{"error":{"message":"Rate limited","code":429,"metadata":{"provider_name":"P"}}}"#;
+        let base_url = failing_upstream(StatusCode::TOO_MANY_REQUESTS, openrouter).await;
+        let (status, _, body) = complete(state(base_url, json!({}), &dir), request.clone()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(body, openrouter);
+
+        let anthropic =
+            r#"{"type":"error","error":{"type":"invalid_request_error","message":"bad image"}}"#;
+        let base_url = failing_upstream(StatusCode::BAD_REQUEST, anthropic).await;
+        let (status, _, body) = complete(state(base_url, json!({}), &dir), request.clone()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            serde_json::from_slice::<Value>(&body).unwrap(),
+            json!({ "error": { "message": "bad image", "type": "invalid_request_error", "code": 400 } })
+        );
+
+        let base_url = failing_upstream(StatusCode::BAD_GATEWAY, "upstream exploded").await;
+        let (status, _, body) = complete(state(base_url, json!({}), &dir), request).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            serde_json::from_slice::<Value>(&body).unwrap(),
+            json!({ "error": { "message": "upstream exploded", "type": "server_error", "code": 502 } })
+        );
+    }
+}
@@ -0,0 +1,214 @@
+use crate::metrics::METRICS;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Settings of the per-model circuit breakers
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failed requests that open a model's circuit, 0 disables the breakers
+    pub failure_threshold: u32,
+    /// Seconds an open circuit rejects requests before a probe request is let through
+    pub open_secs: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            failure_threshold: 5,
+            open_secs: 30,
+        }
+    }
+}
+
+impl CircuitBreakerConfig {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Closed,
+    Open,
+    /// A single probe request is on its way to find out whether the model recovered
+    HalfOpen,
+}
+
+impl State {
+    fn name(self) -> &'static str {
+        match self {
+            State::Closed => "closed",
+            State::Open => "open",
+            State::HalfOpen => "half-open",
+        }
+    }
+
+    /// Value of the `ccorp_circuit_state` gauge
+    fn gauge(self) -> f64 {
+        match self {
+            State::Closed => 0.0,
+            State::Open => 1.0,
+            State::HalfOpen => 2.0,
+        }
+    }
+}
+
+struct Circuit {
+    state: State,
+    /// Consecutive failures
+    failures: u32,
+    /// When the circuit opened or the probe was let through
+    since: Instant,
+}
+
+impl Circuit {
+    fn transition(&mut self, model: &str, state: State) {
+        self.state = state;
+        self.since = Instant::now();
+        METRICS.set(
+            "ccorp_circuit_state",
+            &[("upstream_model", model)],
+            state.gauge(),
+        );
+    }
+}
+
+/// A circuit as shown in the web UI
+#[derive(Serialize)]
+pub struct CircuitStatus {
+    pub model: String,
+    pub state: &'static str,
+    pub failures: u32,
+    /// Seconds until an open circuit lets a probe through
+    pub probe_in_secs: Option<u64>,
+}
+
+/// Circuit breakers of all upstream models that failed recently, shared through `AppState`
+#[derive(Default)]
+pub struct CircuitBreakers {
+    circuits: Mutex<BTreeMap<String, Circuit>>,
+}
+
+impl CircuitBreakers {
+    /// Whether a request may be sent to `model`. Once an open circuit has waited
+    /// `open_secs`, one request at a time is let through as a probe.
+    pub fn allow(&self, config: &CircuitBreakerConfig, model: &str) -> bool {
+        if config.failure_threshold == 0 {
+            return true;
+        }
+        let mut circuits = self.circuits.lock().unwrap();
+        let Some(circuit) = circuits.get_mut(model) else {
+            return true;
+        };
+        match circuit.state {
+            State::Closed => true,
+            // A probe that never reported back, e.g. because the client went away, is
+            // replaced after the same wait
+            State::Open | State::HalfOpen
+                if circuit.since.elapsed() >= Duration::from_secs(config.open_secs) =>
+            {
+                tracing::info!("Circuit of {model} is half-open, probing it");
+                circuit.transition(model, State::HalfOpen);
+                true
+            }
+            State::Open | State::HalfOpen => false,
+        }
+    }
+
+    /// Record whether a request to `model` reached a working upstream.
+    pub fn record(&self, config: &CircuitBreakerConfig, model: &str, ok: bool) {
+        if config.failure_threshold == 0 {
+            return;
+        }
+        let mut circuits = self.circuits.lock().unwrap();
+        if ok {
+            if let Some(mut circuit) = circuits.remove(model)
+                && circuit.state != State::Closed
+            {
+                tracing::info!("Circuit of {model} closed");
+                circuit.transition(model, State::Closed);
+            }
+            return;
+        }
+
+        let circuit = circuits.entry(model.to_string()).or_insert(Circuit {
+            state: State::Closed,
+            failures: 0,
+            since: Instant::now(),
+        });
+        circuit.failures += 1;
+        let trips = match circuit.state {
+            State::Closed => circuit.failures >= config.failure_threshold,
+            State::HalfOpen => true,
+            // Requests sent before the circuit opened do not extend the wait
+            State::Open => false,
+        };
+        if trips {
+            tracing::warn!(
+                "Circuit of {model} opened after {} consecutive failures",
+                circuit.failures
+            );
+            circuit.transition(model, State::Open);
+        }
+    }
+
+    /// Every model with recent failures, for the web UI
+    pub fn statuses(&self, config: &CircuitBreakerConfig) -> Vec<CircuitStatus> {
+        let open_for = Duration::from_secs(config.open_secs);
+        self.circuits
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(model, circuit)| CircuitStatus {
+                model: model.clone(),
+                state: circuit.state.name(),
+                failures: circuit.failures,
+                probe_in_secs: (circuit.state == State::Open).then(|| {
+                    let wait = open_for.saturating_sub(circuit.since.elapsed());
+                    wait.as_millis().div_ceil(1000) as u64
+                }),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_after_failures_and_probes_recovery() {
+        let config = CircuitBreakerConfig {
+            failure_threshold: 2,
+            open_secs: 30,
+        };
+        let breakers = CircuitBreakers::default();
+        let expire = || {
+            let mut circuits = breakers.circuits.lock().unwrap();
+            circuits.get_mut("x/down").unwrap().since -= Duration::from_secs(31);
+        };
+
+        breakers.record(&config, "x/down", false);
+        assert!(breakers.allow(&config, "x/down"));
+        breakers.record(&config, "x/down", false);
+        assert!(!breakers.allow(&config, "x/down"));
+        assert!(breakers.allow(&config, "x/up"));
+        assert_eq!(breakers.statuses(&config)[0].probe_in_secs, Some(30));
+
+        // One probe at a time, a failed probe opens the circuit again
+        expire();
+        assert!(breakers.allow(&config, "x/down"));
+        assert!(!breakers.allow(&config, "x/down"));
+        breakers.record(&config, "x/down", false);
+        assert_eq!(breakers.statuses(&config)[0].state, "open");
+
+        expire();
+        assert!(breakers.allow(&config, "x/down"));
+        breakers.record(&config, "x/down", true);
+        assert!(breakers.allow(&config, "x/down"));
+        assert!(breakers.statuses(&config).is_empty());
+    }
+}
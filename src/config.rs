@@ -1,6 +1,15 @@
+use crate::circuit_breaker::CircuitBreakerConfig;
+use crate::http::HttpConfig;
+use crate::openrouter::ProviderPreferences;
+use crate::overflow::OverflowConfig;
+use crate::providers::ProviderConfig;
+use crate::routing::RoutingConfig;
+use crate::shutdown::ShutdownConfig;
+use crate::upstream::RetryConfig;
 use dotenvy::dotenv;
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::BTreeMap;
 use std::env;
 use std::fs;
 
@@ -9,6 +18,30 @@ use std::fs;
 struct JsonConfig {
     port: u16,
     models: ModelConfig,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    aliases: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BatchConfig::is_default")]
+    batches: BatchConfig,
+    #[serde(default, skip_serializing_if = "AnthropicUpstreamConfig::is_default")]
+    anthropic_upstream: AnthropicUpstreamConfig,
+    #[serde(default, skip_serializing_if = "HealthConfig::is_default")]
+    health: HealthConfig,
+    #[serde(default, skip_serializing_if = "RoutingConfig::is_default")]
+    routing: RoutingConfig,
+    #[serde(default, skip_serializing_if = "ProviderPreferences::is_default")]
+    provider: ProviderPreferences,
+    #[serde(default, skip_serializing_if = "OverflowConfig::is_default")]
+    overflow: OverflowConfig,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    providers: BTreeMap<String, ProviderConfig>,
+    #[serde(default, skip_serializing_if = "RetryConfig::is_default")]
+    retry: RetryConfig,
+    #[serde(default, skip_serializing_if = "HttpConfig::is_default")]
+    http: HttpConfig,
+    #[serde(default, skip_serializing_if = "CircuitBreakerConfig::is_default")]
+    circuit_breaker: CircuitBreakerConfig,
+    #[serde(default, skip_serializing_if = "ShutdownConfig::is_default")]
+    shutdown: ShutdownConfig,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -18,8 +51,88 @@ struct ModelConfig {
     opus: String,
 }
 
+/// Settings for the emulated Message Batches API
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct BatchConfig {
+    /// Directory batches are persisted to
+    pub dir: String,
+    /// Maximum number of batch requests processed at the same time
+    pub concurrency: usize,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        BatchConfig {
+            dir: "batches".to_string(),
+            concurrency: 4,
+        }
+    }
+}
+
+impl BatchConfig {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// The Anthropic-compatible upstream used by the reverse OpenAI endpoint
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct AnthropicUpstreamConfig {
+    /// Base URL of the Anthropic Messages API (e.g., https://api.anthropic.com/v1)
+    pub base_url: String,
+    /// Environment variable holding the key used when the client does not send one
+    pub api_key_env: String,
+    /// Value of the `anthropic-version` header
+    pub version: String,
+}
+
+impl Default for AnthropicUpstreamConfig {
+    fn default() -> Self {
+        AnthropicUpstreamConfig {
+            base_url: "https://api.anthropic.com/v1".to_string(),
+            api_key_env: "ANTHROPIC_API_KEY".to_string(),
+            version: "2023-06-01".to_string(),
+        }
+    }
+}
+
+impl AnthropicUpstreamConfig {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Settings for the readiness checks and the background upstream probe
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct HealthConfig {
+    /// Seconds between probe requests to every mapped model. Probing is opt-in since
+    /// every probe is a billed request: the default of 0 disables it, and turning it on
+    /// takes effect after a restart.
+    pub probe_interval_secs: u64,
+    /// Seconds the result of the API key check is reused by `/readyz`
+    pub key_check_ttl_secs: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            probe_interval_secs: 0,
+            key_check_ttl_secs: 60,
+        }
+    }
+}
+
+impl HealthConfig {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
 /// Runtime configuration loaded from environment variables.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct Config {
     /// The port to listen on
     pub port: u16,
@@ -33,6 +146,30 @@ pub struct Config {
     pub model_sonnet: String,
     /// Override model name for Claude Opus 4
     pub model_opus: String,
+    /// Additional model names accepted by the proxy, mapped to the Claude model they stand for
+    pub aliases: BTreeMap<String, String>,
+    /// Message Batches API settings
+    pub batches: BatchConfig,
+    /// Upstream for the reverse (OpenAI in, Anthropic out) endpoint
+    pub anthropic_upstream: AnthropicUpstreamConfig,
+    /// Health check and probe settings
+    pub health: HealthConfig,
+    /// Rules deciding which upstream model serves a requested model
+    pub routing: RoutingConfig,
+    /// OpenRouter provider preferences for every request, refined per routing rule
+    pub provider: ProviderPreferences,
+    /// Handling of prompts exceeding the model's context window, refined per routing rule
+    pub overflow: OverflowConfig,
+    /// Upstreams besides OpenRouter, referenced as `name/model`
+    pub providers: BTreeMap<String, ProviderConfig>,
+    /// Retries of failed upstream requests
+    pub retry: RetryConfig,
+    /// Timeouts, connection pooling, proxy and certificates of the upstream client
+    pub http: HttpConfig,
+    /// When failing upstream models are skipped
+    pub circuit_breaker: CircuitBreakerConfig,
+    /// How long active streams are waited for on shutdown
+    pub shutdown: ShutdownConfig,
 }
 
 impl Config {
@@ -58,6 +195,18 @@ impl Config {
             model_haiku: config.models.haiku,
             model_sonnet: config.models.sonnet,
             model_opus: config.models.opus,
+            aliases: config.aliases,
+            batches: config.batches,
+            anthropic_upstream: config.anthropic_upstream,
+            health: config.health,
+            routing: config.routing,
+            provider: config.provider,
+            overflow: config.overflow,
+            providers: config.providers,
+            retry: config.retry,
+            http: config.http,
+            circuit_breaker: config.circuit_breaker,
+            shutdown: config.shutdown,
         }
     }
 
@@ -70,6 +219,18 @@ impl Config {
                 sonnet: self.model_sonnet.clone(),
                 opus: self.model_opus.clone(),
             },
+            aliases: self.aliases.clone(),
+            batches: self.batches.clone(),
+            anthropic_upstream: self.anthropic_upstream.clone(),
+            health: self.health.clone(),
+            routing: self.routing.clone(),
+            provider: self.provider.clone(),
+            overflow: self.overflow.clone(),
+            providers: self.providers.clone(),
+            retry: self.retry.clone(),
+            http: self.http.clone(),
+            circuit_breaker: self.circuit_breaker.clone(),
+            shutdown: self.shutdown.clone(),
         };
 
         let json_string =
@@ -0,0 +1,651 @@
+use crate::metrics::METRICS;
+use crate::openai_to_anthropic::error_type_for_status;
+use crate::sse::{SseParser, anthropic_event};
+use axum::body::Bytes;
+use futures_util::stream::{BoxStream, StreamExt};
+use serde_json::{Map, Value, json};
+use std::collections::HashMap;
+
+/// Schema keywords Gemini accepts in function parameters; it rejects the rest of JSON Schema
+const SCHEMA_KEYWORDS: &[&str] = &[
+    "type",
+    "format",
+    "title",
+    "description",
+    "nullable",
+    "enum",
+    "items",
+    "minItems",
+    "maxItems",
+    "properties",
+    "required",
+    "minProperties",
+    "maxProperties",
+    "minLength",
+    "maxLength",
+    "pattern",
+    "minimum",
+    "maximum",
+    "anyOf",
+    "default",
+];
+
+/// Reduce a JSON Schema to the subset Gemini accepts.
+fn schema(schema: &Value) -> Value {
+    let Some(fields) = schema.as_object() else {
+        return schema.clone();
+    };
+    let mut converted = Map::new();
+    for (key, value) in fields {
+        match key.as_str() {
+            // `"type": ["string", "null"]` becomes a nullable string
+            "type" if value.is_array() => {
+                let types: Vec<&Value> = value.as_array().unwrap().iter().collect();
+                if let Some(non_null) = types.iter().find(|t| **t != "null") {
+                    converted.insert("type".to_string(), (*non_null).clone());
+                }
+                if types.iter().any(|t| *t == "null") {
+                    converted.insert("nullable".to_string(), json!(true));
+                }
+            }
+            "const" => {
+                converted.insert("enum".to_string(), json!([value]));
+            }
+            "properties" => {
+                let properties = value
+                    .as_object()
+                    .into_iter()
+                    .flatten()
+                    .map(|(name, property)| (name.clone(), self::schema(property)))
+                    .collect();
+                converted.insert(key.clone(), Value::Object(properties));
+            }
+            "items" => {
+                converted.insert(key.clone(), self::schema(value));
+            }
+            "anyOf" => {
+                let variants = value.as_array().into_iter().flatten().map(self::schema);
+                converted.insert(key.clone(), Value::Array(variants.collect()));
+            }
+            _ if SCHEMA_KEYWORDS.contains(&key.as_str()) => {
+                converted.insert(key.clone(), value.clone());
+            }
+            _ => {}
+        }
+    }
+    Value::Object(converted)
+}
+
+fn image_part(source: &Value) -> Value {
+    match source["type"].as_str() {
+        Some("base64") => json!({
+            "inlineData": { "mimeType": source["media_type"], "data": source["data"] }
+        }),
+        _ => json!({ "fileData": { "fileUri": source["url"] } }),
+    }
+}
+
+/// The text of a tool result and the images it contains
+fn tool_result(content: &Value) -> (String, Vec<Value>) {
+    match content {
+        Value::String(text) => (text.clone(), Vec::new()),
+        Value::Array(blocks) => {
+            let text = blocks
+                .iter()
+                .filter_map(|block| block["text"].as_str())
+                .collect::<Vec<_>>()
+                .join("\n");
+            let images = blocks
+                .iter()
+                .filter(|block| block["type"] == "image")
+                .map(|block| image_part(&block["source"]))
+                .collect();
+            (text, images)
+        }
+        _ => (String::new(), Vec::new()),
+    }
+}
+
+/// Convert Anthropic message content into Gemini parts.
+fn parts(content: &Value, tool_names: &mut HashMap<String, Value>) -> Vec<Value> {
+    let blocks = match content {
+        Value::String(text) => return vec![json!({ "text": text })],
+        Value::Array(blocks) => blocks,
+        _ => return Vec::new(),
+    };
+    let mut parts = Vec::new();
+    // Gemini needs the signature of its thoughts back on the function call that followed them
+    let mut signature = None;
+    for block in blocks {
+        match block["type"].as_str() {
+            Some("text") => parts.push(json!({ "text": block["text"] })),
+            Some("image") => parts.push(image_part(&block["source"])),
+            Some("thinking") => {
+                signature = block["signature"].as_str().filter(|s| !s.is_empty());
+            }
+            Some("tool_use") => {
+                let id = block["id"].as_str().unwrap_or_default();
+                tool_names.insert(id.to_string(), block["name"].clone());
+                let mut part = json!({
+                    "functionCall": { "name": block["name"], "args": block["input"] }
+                });
+                if let Some(signature) = signature.take() {
+                    part["thoughtSignature"] = json!(signature);
+                }
+                parts.push(part);
+            }
+            Some("tool_result") => {
+                let id = block["tool_use_id"].as_str().unwrap_or_default();
+                let (text, images) = tool_result(&block["content"]);
+                let key = if block["is_error"] == true {
+                    "error"
+                } else {
+                    "content"
+                };
+                parts.push(json!({
+                    "functionResponse": {
+                        "name": tool_names.get(id).cloned().unwrap_or_default(),
+                        "response": { key: text },
+                    }
+                }));
+                parts.extend(images);
+            }
+            _ => {}
+        }
+    }
+    parts
+}
+
+/// Convert an Anthropic Messages request into a Gemini `generateContent` request.
+pub fn request(req: &Value) -> Value {
+    let mut tool_names = HashMap::new();
+    let contents: Vec<Value> = req["messages"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|message| {
+            let role = if message["role"] == "assistant" {
+                "model"
+            } else {
+                "user"
+            };
+            json!({ "role": role, "parts": parts(&message["content"], &mut tool_names) })
+        })
+        .collect();
+    let mut request = json!({ "contents": contents });
+
+    let system = match &req["system"] {
+        Value::String(text) => text.clone(),
+        Value::Array(blocks) => blocks
+            .iter()
+            .filter_map(|block| block["text"].as_str())
+            .collect::<Vec<_>>()
+            .join("\n\n"),
+        _ => String::new(),
+    };
+    if !system.is_empty() {
+        request["systemInstruction"] = json!({ "parts": [{ "text": system }] });
+    }
+
+    if let Some(tools) = req["tools"].as_array() {
+        let declarations: Vec<Value> = tools
+            .iter()
+            .map(|tool| {
+                let mut declaration = json!({ "name": tool["name"] });
+                if tool["description"].is_string() {
+                    declaration["description"] = tool["description"].clone();
+                }
+                // Gemini rejects object schemas without properties
+                if tool["input_schema"]["properties"]
+                    .as_object()
+                    .is_some_and(|properties| !properties.is_empty())
+                {
+                    declaration["parameters"] = schema(&tool["input_schema"]);
+                }
+                declaration
+            })
+            .collect();
+        request["tools"] = json!([{ "functionDeclarations": declarations }]);
+    }
+    let tool_choice = &req["tool_choice"];
+    let mode = match tool_choice["type"].as_str() {
+        Some("any" | "tool") => Some("ANY"),
+        Some("none") => Some("NONE"),
+        Some(_) => Some("AUTO"),
+        None => None,
+    };
+    if let Some(mode) = mode {
+        let mut config = json!({ "mode": mode });
+        if tool_choice["type"] == "tool" {
+            config["allowedFunctionNames"] = json!([tool_choice["name"]]);
+        }
+        request["toolConfig"] = json!({ "functionCallingConfig": config });
+    }
+
+    let mut generation = Map::new();
+    for (from, to) in [
+        ("max_tokens", "maxOutputTokens"),
+        ("temperature", "temperature"),
+        ("top_p", "topP"),
+        ("top_k", "topK"),
+        ("stop_sequences", "stopSequences"),
+    ] {
+        if !req[from].is_null() {
+            generation.insert(to.to_string(), req[from].clone());
+        }
+    }
+    match req["thinking"]["type"].as_str() {
+        Some("enabled") => {
+            generation.insert(
+                "thinkingConfig".to_string(),
+                json!({ "thinkingBudget": req["thinking"]["budget_tokens"], "includeThoughts": true }),
+            );
+        }
+        Some("disabled") => {
+            generation.insert("thinkingConfig".to_string(), json!({ "thinkingBudget": 0 }));
+        }
+        _ => {}
+    }
+    if !generation.is_empty() {
+        request["generationConfig"] = Value::Object(generation);
+    }
+    request
+}
+
+fn usage(response: &Value) -> Value {
+    let metadata = &response["usageMetadata"];
+    let count = |field: &str| metadata[field].as_u64().unwrap_or(0);
+    json!({
+        "input_tokens": count("promptTokenCount"),
+        "output_tokens": count("candidatesTokenCount") + count("thoughtsTokenCount"),
+    })
+}
+
+fn stop_reason(finish_reason: &str, called_tools: bool) -> &'static str {
+    if called_tools {
+        "tool_use"
+    } else if finish_reason == "MAX_TOKENS" {
+        "max_tokens"
+    } else {
+        "end_turn"
+    }
+}
+
+fn message_id(response: &Value) -> String {
+    let id = response["responseId"].as_str().map_or_else(
+        || {
+            chrono::Utc::now()
+                .timestamp_nanos_opt()
+                .unwrap_or(0)
+                .to_string()
+        },
+        str::to_string,
+    );
+    format!("msg_gemini_{id}")
+}
+
+fn tool_use(message_id: &str, index: usize, call: &Value) -> Value {
+    let id = call["id"]
+        .as_str()
+        .map_or_else(|| format!("toolu_{message_id}_{index}"), str::to_string);
+    json!({ "type": "tool_use", "id": id, "name": call["name"], "input": call["args"] })
+}
+
+/// An Anthropic error for a Gemini error or a prompt Gemini refused to answer
+fn error(response: &Value) -> Option<Value> {
+    let (code, message) = if let Some(error) = response.get("error") {
+        let code = error["code"].as_u64().unwrap_or(500);
+        (
+            code,
+            error["message"].as_str().unwrap_or_default().to_string(),
+        )
+    } else if response["candidates"].as_array().is_none_or(Vec::is_empty) {
+        let reason = response["promptFeedback"]["blockReason"]
+            .as_str()
+            .unwrap_or("no candidates");
+        (400, format!("Gemini did not answer the prompt: {reason}"))
+    } else {
+        return None;
+    };
+    let error_type = u16::try_from(code).map_or("api_error", error_type_for_status);
+    Some(json!({
+        "type": "error",
+        "error": { "code": code, "type": error_type, "message": message },
+    }))
+}
+
+/// Convert a complete Gemini response into an Anthropic message.
+pub fn message(response: &Value) -> Value {
+    if let Some(error) = error(response) {
+        return error;
+    }
+    let id = message_id(response);
+    let candidate = &response["candidates"][0];
+    let mut content = Vec::new();
+    let mut tool_calls = 0;
+    for part in candidate["content"]["parts"]
+        .as_array()
+        .into_iter()
+        .flatten()
+    {
+        let signature = part["thoughtSignature"].as_str();
+        if part["thought"] == true {
+            content.push(json!({
+                "type": "thinking",
+                "thinking": part["text"],
+                "signature": signature.unwrap_or_default(),
+            }));
+        } else if !part["functionCall"].is_null() {
+            if let Some(signature) = signature {
+                content.push(json!({ "type": "thinking", "thinking": "", "signature": signature }));
+            }
+            content.push(tool_use(&id, tool_calls, &part["functionCall"]));
+            tool_calls += 1;
+        } else if let Some(text) = part["text"].as_str() {
+            content.push(json!({ "type": "text", "text": text }));
+        }
+    }
+    json!({
+        "id": id,
+        "type": "message",
+        "role": "assistant",
+        "model": response["modelVersion"],
+        "content": content,
+        "stop_reason": stop_reason(candidate["finishReason"].as_str().unwrap_or_default(), tool_calls > 0),
+        "stop_sequence": null,
+        "usage": usage(response),
+    })
+}
+
+/// The content block an encoder is currently writing
+#[derive(Clone, Copy, PartialEq)]
+enum OpenBlock {
+    Text,
+    Thinking,
+}
+
+/// Builds an Anthropic event stream from Gemini stream responses
+#[derive(Default)]
+struct EventEncoder {
+    id: String,
+    started: bool,
+    open_block: Option<(usize, OpenBlock)>,
+    next_index: usize,
+    tool_calls: usize,
+    stop_reason: Option<&'static str>,
+    usage: Option<Value>,
+}
+
+impl EventEncoder {
+    fn start_block(&mut self, events: &mut String, kind: Option<OpenBlock>, block: Value) -> usize {
+        self.close_block(events);
+        let index = self.next_index;
+        self.next_index += 1;
+        self.open_block = kind.map(|kind| (index, kind));
+        events.push_str(&anthropic_event(json!({
+            "type": "content_block_start",
+            "index": index,
+            "content_block": block,
+        })));
+        index
+    }
+
+    fn close_block(&mut self, events: &mut String) {
+        if let Some((index, _)) = self.open_block.take() {
+            events.push_str(&anthropic_event(
+                json!({ "type": "content_block_stop", "index": index }),
+            ));
+        }
+    }
+
+    /// Continue the open block of `kind`, or start one
+    fn block(&mut self, events: &mut String, kind: OpenBlock) -> usize {
+        match self.open_block {
+            Some((index, open)) if open == kind => index,
+            _ => {
+                let block = match kind {
+                    OpenBlock::Text => json!({ "type": "text", "text": "" }),
+                    OpenBlock::Thinking => json!({ "type": "thinking", "thinking": "" }),
+                };
+                self.start_block(events, Some(kind), block)
+            }
+        }
+    }
+
+    fn delta(events: &mut String, index: usize, delta: Value) {
+        events.push_str(&anthropic_event(
+            json!({ "type": "content_block_delta", "index": index, "delta": delta }),
+        ));
+    }
+
+    /// Translate one stream response into zero or more Anthropic events
+    fn translate(&mut self, response: &Value) -> String {
+        let mut events = String::new();
+        if response.get("error").is_some() {
+            return anthropic_event(error(response).unwrap_or_default());
+        }
+        if !self.started {
+            self.started = true;
+            self.id = message_id(response);
+            events.push_str(&anthropic_event(json!({
+                "type": "message_start",
+                "message": {
+                    "id": self.id,
+                    "type": "message",
+                    "role": "assistant",
+                    "model": response["modelVersion"],
+                    "content": [],
+                    "stop_reason": null,
+                    "stop_sequence": null,
+                    "usage": { "input_tokens": 0, "output_tokens": 0 },
+                }
+            })));
+        }
+        if !response["usageMetadata"].is_null() {
+            self.usage = Some(usage(response));
+        }
+
+        let candidate = &response["candidates"][0];
+        for part in candidate["content"]["parts"]
+            .as_array()
+            .into_iter()
+            .flatten()
+        {
+            let signature = part["thoughtSignature"].as_str();
+            if part["thought"] == true {
+                let index = self.block(&mut events, OpenBlock::Thinking);
+                if let Some(text) = part["text"].as_str().filter(|t| !t.is_empty()) {
+                    Self::delta(
+                        &mut events,
+                        index,
+                        json!({ "type": "thinking_delta", "thinking": text }),
+                    );
+                }
+                if let Some(signature) = signature {
+                    Self::delta(
+                        &mut events,
+                        index,
+                        json!({ "type": "signature_delta", "signature": signature }),
+                    );
+                }
+            } else if !part["functionCall"].is_null() {
+                if let Some(signature) = signature {
+                    let index = self.block(&mut events, OpenBlock::Thinking);
+                    Self::delta(
+                        &mut events,
+                        index,
+                        json!({ "type": "signature_delta", "signature": signature }),
+                    );
+                }
+                // Gemini sends each function call complete, in a single part
+                let mut block = tool_use(&self.id, self.tool_calls, &part["functionCall"]);
+                self.tool_calls += 1;
+                let input = block["input"].take();
+                block["input"] = json!({});
+                let index = self.start_block(&mut events, None, block);
+                Self::delta(
+                    &mut events,
+                    index,
+                    json!({ "type": "input_json_delta", "partial_json": input.to_string() }),
+                );
+                events.push_str(&anthropic_event(
+                    json!({ "type": "content_block_stop", "index": index }),
+                ));
+            } else if let Some(text) = part["text"].as_str().filter(|t| !t.is_empty()) {
+                let index = self.block(&mut events, OpenBlock::Text);
+                Self::delta(
+                    &mut events,
+                    index,
+                    json!({ "type": "text_delta", "text": text }),
+                );
+            }
+        }
+
+        if let Some(finish_reason) = candidate["finishReason"].as_str() {
+            self.stop_reason = Some(stop_reason(finish_reason, self.tool_calls > 0));
+        }
+        events
+    }
+
+    /// Close the message once the upstream stream has ended
+    fn finish(&mut self) -> String {
+        let mut events = String::new();
+        if !self.started {
+            return events;
+        }
+        self.close_block(&mut events);
+        let usage = self
+            .usage
+            .take()
+            .unwrap_or_else(|| json!({ "output_tokens": 0 }));
+        events.push_str(&anthropic_event(json!({
+            "type": "message_delta",
+            "delta": { "stop_reason": self.stop_reason.unwrap_or("end_turn"), "stop_sequence": null },
+            "usage": usage,
+        })));
+        events.push_str(&anthropic_event(json!({ "type": "message_stop" })));
+        events
+    }
+}
+
+/// Re-encode a Gemini `streamGenerateContent?alt=sse` stream as Anthropic events.
+pub fn event_stream(
+    mut body: BoxStream<'static, std::io::Result<Bytes>>,
+) -> BoxStream<'static, std::io::Result<Bytes>> {
+    async_stream::stream! {
+        let mut encoder = EventEncoder::default();
+        let mut parser = SseParser::default();
+        while let Some(chunk) = body.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            };
+            let mut events = String::new();
+            for event in parser.push(&chunk) {
+                match serde_json::from_str::<Value>(&event.data) {
+                    Ok(response) => events.push_str(&encoder.translate(&response)),
+                    Err(_) => METRICS.inc(
+                        "ccorp_translation_repairs_total",
+                        &[("kind", "unparseable_stream_chunk")],
+                    ),
+                }
+            }
+            if !events.is_empty() {
+                yield Ok(Bytes::from(events));
+            }
+        }
+        yield Ok(Bytes::from(encoder.finish()));
+    }
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> String {
+        let path = format!(
+            "{}/tests/fixtures/gemini/{name}",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn translates_request() {
+        let anthropic: Value = serde_json::from_str(&fixture("messages_request.json")).unwrap();
+        let expected: Value =
+            serde_json::from_str(&fixture("generate_content_request.json")).unwrap();
+        assert_eq!(request(&anthropic), expected);
+    }
+
+    #[test]
+    fn translates_response() {
+        let response: Value =
+            serde_json::from_str(&fixture("generate_content_response.json")).unwrap();
+        let translated = message(&response);
+
+        assert_eq!(translated["model"], "gemini-2.5-flash");
+        assert_eq!(translated["stop_reason"], "tool_use");
+        assert_eq!(
+            translated["usage"],
+            json!({ "input_tokens": 412, "output_tokens": 187 })
+        );
+        let content = translated["content"].as_array().unwrap();
+        assert_eq!(content[0]["type"], "thinking");
+        assert_eq!(content[1]["type"], "text");
+        assert_eq!(content[2]["signature"], "CtwBAdHtim9w");
+        assert_eq!(content[3]["name"], "read_file");
+        assert_eq!(content[3]["input"], json!({ "path": "src/main.rs" }));
+
+        let blocked: Value = serde_json::from_str(&fixture("blocked_response.json")).unwrap();
+        assert_eq!(message(&blocked)["error"]["type"], "invalid_request_error");
+    }
+
+    #[test]
+    fn translates_stream() {
+        let mut encoder = EventEncoder::default();
+        let mut parser = SseParser::default();
+        let mut events = String::new();
+        for event in parser.push(fixture("stream_generate_content.sse").as_bytes()) {
+            events.push_str(&encoder.translate(&serde_json::from_str(&event.data).unwrap()));
+        }
+        events.push_str(&encoder.finish());
+
+        let events: Vec<Value> = SseParser::default()
+            .push(events.as_bytes())
+            .iter()
+            .map(|event| serde_json::from_str(&event.data).unwrap())
+            .collect();
+        let types: Vec<&str> = events.iter().map(|e| e["type"].as_str().unwrap()).collect();
+        assert_eq!(
+            types,
+            [
+                "message_start",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_stop",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_delta",
+                "content_block_stop",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_stop",
+                "message_delta",
+                "message_stop",
+            ]
+        );
+        assert_eq!(events[1]["content_block"]["type"], "thinking");
+        assert_eq!(events[8]["content_block"]["name"], "read_file");
+        assert_eq!(
+            events[9]["delta"]["partial_json"],
+            r#"{"path":"src/main.rs"}"#
+        );
+        assert_eq!(events[11]["delta"]["stop_reason"], "tool_use");
+        assert_eq!(events[11]["usage"]["output_tokens"], 96);
+    }
+}
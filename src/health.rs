@@ -0,0 +1,330 @@
+use crate::AppState;
+use crate::config::Config;
+use crate::models::{AnthropicResponse, OpenAIMessage, OpenAIRequest, OpenAIResponse};
+use crate::openrouter;
+use crate::providers::{self, Format};
+use crate::routing;
+use axum::{
+    extract::{Json, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use reqwest::Client;
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, RwLock};
+
+/// Outcome of the most recent probe request to a mapped model
+#[derive(Clone, Debug, Serialize)]
+pub struct ProbeResult {
+    pub ok: bool,
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub checked_at: DateTime<Utc>,
+}
+
+/// Latest probe result per upstream model and of the API key check, shared through `AppState`
+#[derive(Default)]
+pub struct ProbeResults {
+    results: RwLock<BTreeMap<String, ProbeResult>>,
+    /// When the key was last checked and how it went
+    key_check: Mutex<Option<(Instant, Result<(), String>)>>,
+}
+
+impl ProbeResults {
+    pub async fn snapshot(&self) -> BTreeMap<String, ProbeResult> {
+        self.results.read().await.clone()
+    }
+
+    /// Check the API key, reusing the last result for `key_check_ttl_secs`. Concurrent
+    /// callers wait for a single check.
+    async fn check_key(&self, client: &Client, config: &Config) -> Result<(), String> {
+        let ttl = Duration::from_secs(config.health.key_check_ttl_secs);
+        let mut key_check = self.key_check.lock().await;
+        if let Some((checked_at, result)) = key_check.as_ref()
+            && checked_at.elapsed() < ttl
+        {
+            return result.clone();
+        }
+        let result = openrouter::check_key(client, config).await;
+        *key_check = Some((Instant::now(), result.clone()));
+        result
+    }
+}
+
+/// Whether requests can be served: OpenRouter and the key work, and every primary
+/// mapping is usable and passed its latest probe. Fallbacks only back the primary
+/// models up, so their failures are reported without failing readiness.
+fn is_ready(
+    upstream: bool,
+    api_key: bool,
+    primary: &[String],
+    mappings: &BTreeMap<String, bool>,
+    probes: &BTreeMap<String, ProbeResult>,
+) -> bool {
+    upstream
+        && api_key
+        && primary.iter().all(|model| {
+            mappings.get(model).copied().unwrap_or(true)
+                && probes.get(model).is_none_or(|probe| probe.ok)
+        })
+}
+
+/// GET /healthz - The process is up and serving requests
+pub async fn healthz() -> Json<Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+/// GET /readyz - Config, upstream, API key and model mappings are all usable
+pub async fn readyz(State(state): State<AppState>) -> Response {
+    let config = state.config.read().await.clone();
+
+    let models = state
+        .models_cache
+        .get(&state.http, &openrouter::ModelSource::new(&config))
+        .await;
+    let upstream = match &models {
+        Ok(models) => json!({ "ok": true, "models": models.len() }),
+        Err(e) => json!({ "ok": false, "error": e.to_string() }),
+    };
+
+    let key_check = state.probes.check_key(&state.http, &config).await;
+    let api_key = match &key_check {
+        Ok(()) => json!({ "ok": true }),
+        Err(e) => json!({ "ok": false, "error": e }),
+    };
+
+    let mut listed = BTreeMap::new();
+    let mut mappings = serde_json::Map::new();
+    for model in routing::targets(&config) {
+        // Other providers are not in the OpenRouter catalog, their probes show whether they work
+        let endpoint = providers::endpoint(&config, &model, &config.api_key);
+        if let Some(provider) = endpoint.provider {
+            mappings.insert(model, json!({ "ok": true, "provider": provider }));
+            continue;
+        }
+        let resolvable = models
+            .as_ref()
+            .is_ok_and(|models| models.iter().any(|m| m.id == model));
+        listed.insert(model.clone(), resolvable);
+        mappings.insert(model, json!({ "ok": resolvable }));
+    }
+
+    let probes = state.probes.snapshot().await;
+    let ready = is_ready(
+        models.is_ok(),
+        key_check.is_ok(),
+        &routing::primary_targets(&config),
+        &listed,
+        &probes,
+    );
+
+    let body = json!({
+        "status": if ready { "ready" } else { "not_ready" },
+        "checks": {
+            "config": { "ok": true, "port": config.port },
+            "upstream": upstream,
+            "api_key": api_key,
+            "mappings": mappings,
+            "probes": probes,
+        }
+    });
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(body)).into_response()
+}
+
+/// Send a minimal completion request to `model` and measure how it went
+async fn probe_model(client: &Client, config: &Config, model: &str) -> ProbeResult {
+    let endpoint = providers::endpoint(config, model, &config.api_key);
+    let request = OpenAIRequest {
+        model: model.to_string(),
+        messages: vec![OpenAIMessage {
+            role: "user".to_string(),
+            content: Some("ping".to_string().into()),
+            tool_calls: None,
+            tool_call_id: None,
+        }],
+        temperature: None,
+        stream: None,
+        tools: None,
+        max_tokens: Some(1),
+        stream_options: None,
+        models: None,
+        provider: (!config.provider.is_default()).then(|| config.provider.clone()),
+        transforms: None,
+    };
+
+    let format = endpoint.backend.format();
+    let body = match format {
+        Format::Chat => serde_json::to_value(request).expect("requests serialize"),
+        Format::Messages => json!({
+            "model": model,
+            "max_tokens": 1,
+            "messages": [{ "role": "user", "content": "ping" }],
+        }),
+    };
+    let body = endpoint.request_body(&body);
+
+    let started = Instant::now();
+    let mut request = client.post(endpoint.url(false));
+    if let Some(timeout) = config.http.request_timeout() {
+        request = request.timeout(timeout);
+    }
+    let response = endpoint.authorize(request).json(&body).send().await;
+
+    let (status, error) = match response {
+        Err(e) => (None, Some(e.to_string())),
+        Ok(res) if !res.status().is_success() => {
+            let status = res.status().as_u16();
+            (Some(status), Some(res.text().await.unwrap_or_default()))
+        }
+        Ok(res) => {
+            let status = res.status().as_u16();
+            let parsed = res
+                .json::<Value>()
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|value| {
+                    let value = endpoint.backend.translate_response(value);
+                    match format {
+                        Format::Chat => serde_json::from_value::<OpenAIResponse>(value).map(drop),
+                        Format::Messages => {
+                            serde_json::from_value::<AnthropicResponse>(value).map(drop)
+                        }
+                    }
+                    .map_err(|e| e.to_string())
+                });
+            match parsed {
+                Ok(()) => (Some(status), None),
+                Err(e) => (Some(status), Some(e)),
+            }
+        }
+    };
+
+    ProbeResult {
+        ok: error.is_none(),
+        latency_ms: started.elapsed().as_millis() as u64,
+        status,
+        error,
+        checked_at: Utc::now(),
+    }
+}
+
+/// Periodically probe every mapped model in the background, if enabled in the config.
+pub fn spawn_probes(state: AppState) {
+    tokio::spawn(async move {
+        loop {
+            let config = state.config.read().await.clone();
+            let interval = config.health.probe_interval_secs;
+            if interval == 0 {
+                tracing::info!(
+                    "Upstream probing is off, set health.probe_interval_secs to turn it on"
+                );
+                return;
+            }
+
+            for model in routing::targets(&config) {
+                let result = probe_model(&state.http, &config, &model).await;
+                if !result.ok {
+                    tracing::warn!(
+                        "Probe of {model} failed: {}",
+                        result.error.as_deref().unwrap_or_default()
+                    );
+                }
+                state.probes.results.write().await.insert(model, result);
+            }
+
+            // Forget models that are no longer mapped
+            let mapped = routing::targets(&config);
+            state
+                .probes
+                .results
+                .write()
+                .await
+                .retain(|model, _| mapped.contains(model));
+
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn probe(ok: bool) -> ProbeResult {
+        ProbeResult {
+            ok,
+            latency_ms: 100,
+            status: Some(if ok { 200 } else { 502 }),
+            error: (!ok).then(|| "upstream down".to_string()),
+            checked_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn readiness_depends_on_primary_mappings_only() {
+        let primary = ["a/sonnet".to_string(), "local/qwen".to_string()];
+        let listed = BTreeMap::from([
+            ("a/sonnet".to_string(), true),
+            ("b/fallback".to_string(), false),
+        ]);
+        let probes = BTreeMap::from([
+            ("a/sonnet".to_string(), probe(true)),
+            ("b/fallback".to_string(), probe(false)),
+        ]);
+        // Provider models are not listed, and models without a probe yet are not judged
+        assert!(is_ready(true, true, &primary, &listed, &probes));
+        assert!(!is_ready(false, true, &primary, &listed, &probes));
+        assert!(!is_ready(true, false, &primary, &listed, &probes));
+
+        let mut failing = probes.clone();
+        failing.insert("local/qwen".to_string(), probe(false));
+        assert!(!is_ready(true, true, &primary, &listed, &failing));
+
+        let mut unlisted = listed.clone();
+        unlisted.insert("a/sonnet".to_string(), false);
+        assert!(!is_ready(true, true, &primary, &unlisted, &probes));
+    }
+
+    #[tokio::test]
+    async fn reuses_the_key_check_until_it_expires() {
+        let checks = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = checks.clone();
+        let app = axum::Router::new().route(
+            "/key",
+            axum::routing::get(move || {
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                async { Json(json!({ "data": {} })) }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let mut config = Config {
+            base_url: format!("http://{addr}"),
+            api_key: "key".to_string(),
+            ..Config::default()
+        };
+        let results = ProbeResults::default();
+        let client = Client::new();
+        assert!(results.check_key(&client, &config).await.is_ok());
+        assert!(results.check_key(&client, &config).await.is_ok());
+        assert_eq!(checks.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        config.health.key_check_ttl_secs = 0;
+        assert!(results.check_key(&client, &config).await.is_ok());
+        assert_eq!(checks.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,177 @@
+use crate::providers::ByteStream;
+use axum::body::Bytes;
+use futures_util::stream::{Stream, StreamExt};
+use reqwest::{Certificate, Client, NoProxy, Proxy};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::time::Duration;
+
+/// HTTP protocol used for upstream connections
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HttpVersion {
+    /// HTTP/2 where the server offers it during the TLS handshake, HTTP/1.1 otherwise
+    #[default]
+    Auto,
+    Http1,
+    /// HTTP/2 without negotiation, also for plain `http://` upstreams
+    Http2,
+}
+
+/// Settings of the HTTP client shared by all upstream requests. Changes take effect
+/// after a restart.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// Seconds to wait for a connection to be established
+    pub connect_timeout_secs: u64,
+    /// Seconds to wait for the upstream to send anything, 0 disables the timeout
+    pub read_timeout_secs: u64,
+    /// Seconds a request without a streamed response may take in total, 0 disables the timeout
+    pub request_timeout_secs: u64,
+    /// Seconds a streamed response may go without a chunk before it is ended, 0 disables the timeout
+    pub stream_idle_timeout_secs: u64,
+    pub http_version: HttpVersion,
+    /// Idle connections kept open per host
+    pub pool_max_idle_per_host: usize,
+    /// Seconds an idle connection is kept open
+    pub pool_idle_timeout_secs: u64,
+    /// Proxy for all upstream requests (e.g. http://proxy.corp:3128). Without one, the
+    /// `HTTPS_PROXY`, `HTTP_PROXY` and `NO_PROXY` environment variables are used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    /// Comma separated hosts reached without the configured `proxy`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub no_proxy: Option<String>,
+    /// PEM file with additional root certificates, e.g. of a TLS-inspecting corporate proxy
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca_bundle: Option<String>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            connect_timeout_secs: 10,
+            read_timeout_secs: 300,
+            request_timeout_secs: 600,
+            stream_idle_timeout_secs: 120,
+            http_version: HttpVersion::Auto,
+            pool_max_idle_per_host: 32,
+            pool_idle_timeout_secs: 90,
+            proxy: None,
+            no_proxy: None,
+            ca_bundle: None,
+        }
+    }
+}
+
+/// `None` for a timeout of 0 seconds, which disables it
+fn timeout(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+impl HttpConfig {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn request_timeout(&self) -> Option<Duration> {
+        timeout(self.request_timeout_secs)
+    }
+
+    pub fn stream_idle_timeout(&self) -> Option<Duration> {
+        timeout(self.stream_idle_timeout_secs)
+    }
+
+    /// Build the client for these settings.
+    pub fn client(&self) -> Result<Client, String> {
+        let mut builder = Client::builder()
+            .connect_timeout(Duration::from_secs(self.connect_timeout_secs))
+            .pool_max_idle_per_host(self.pool_max_idle_per_host)
+            .pool_idle_timeout(timeout(self.pool_idle_timeout_secs));
+        if let Some(read_timeout) = timeout(self.read_timeout_secs) {
+            builder = builder.read_timeout(read_timeout);
+        }
+        builder = match self.http_version {
+            HttpVersion::Auto => builder,
+            HttpVersion::Http1 => builder.http1_only(),
+            HttpVersion::Http2 => builder.http2_prior_knowledge(),
+        };
+        if let Some(url) = &self.proxy {
+            let proxy = Proxy::all(url)
+                .map_err(|e| format!("invalid proxy {url}: {e}"))?
+                .no_proxy(self.no_proxy.as_deref().and_then(NoProxy::from_string));
+            builder = builder.proxy(proxy);
+        }
+        if let Some(path) = &self.ca_bundle {
+            let pem = std::fs::read(path).map_err(|e| format!("could not read {path}: {e}"))?;
+            let certificates = Certificate::from_pem_bundle(&pem)
+                .map_err(|e| format!("invalid certificates in {path}: {e}"))?;
+            for certificate in certificates {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        builder.build().map_err(|e| e.to_string())
+    }
+}
+
+/// The chunks of an upstream response, failing with `TimedOut` when none arrives for
+/// `idle` so consumers treat a stalled upstream like any other failed stream.
+pub fn idle_timeout(
+    body: impl Stream<Item = reqwest::Result<Bytes>> + Send + 'static,
+    idle: Option<Duration>,
+) -> ByteStream {
+    let mut body = body.map(|chunk| chunk.map_err(io::Error::other)).boxed();
+    let Some(idle) = idle else {
+        return body;
+    };
+    async_stream::stream! {
+        loop {
+            match tokio::time::timeout(idle, body.next()).await {
+                Ok(Some(chunk)) => yield chunk,
+                Ok(None) => return,
+                Err(_) => {
+                    yield Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("upstream sent nothing for {idle:?}"),
+                    ));
+                    return;
+                }
+            }
+        }
+    }
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn fails_idle_streams() {
+        let chunks = futures_util::stream::iter([Ok(Bytes::from("data: 1\n\n"))])
+            .chain(futures_util::stream::pending());
+        let mut body = idle_timeout(chunks, Some(Duration::from_millis(20)));
+        assert!(body.next().await.unwrap().is_ok());
+        let error = body.next().await.unwrap().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(body.next().await.is_none());
+    }
+
+    #[test]
+    fn builds_clients_from_settings() {
+        let config: HttpConfig = serde_json::from_value(serde_json::json!({
+            "http_version": "http1",
+            "proxy": "http://proxy.corp:3128",
+            "no_proxy": "localhost,127.0.0.1",
+        }))
+        .unwrap();
+        assert!(config.client().is_ok());
+
+        let missing_bundle = HttpConfig {
+            ca_bundle: Some("/nonexistent/ca.pem".to_string()),
+            ..HttpConfig::default()
+        };
+        assert!(missing_bundle.client().is_err());
+    }
+}
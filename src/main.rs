@@ -1,31 +1,85 @@
 mod anthropic_to_openai;
+mod batches;
+mod capabilities;
+mod chat_completions;
+mod circuit_breaker;
 mod config;
+mod gemini;
+mod health;
+mod http;
+mod metrics;
+mod model_list;
 mod models;
+mod ollama;
 mod openai_to_anthropic;
 mod openrouter;
+mod overflow;
+mod providers;
+mod random;
+mod responses;
+mod reverse;
+mod routing;
+mod selection;
+mod shutdown;
+mod sse;
 mod switch_model;
+mod upstream;
 
 use axum::{
     Router,
-    body::Body,
+    body::{Body, Bytes},
     extract::{Json, State},
     http::{HeaderMap, StatusCode, header},
     response::{IntoResponse, Response},
     routing::{get, post},
 };
 use config::Config;
-use futures_util::stream::StreamExt;
-use models::{AnthropicRequest, OpenAIStreamResponse};
-use reqwest::Client;
+use metrics::METRICS;
+use models::{
+    AnthropicRequest, AnthropicResponse, OpenAIResponse, OpenAIStreamResponse, OpenAIUsage,
+};
+use openai_to_anthropic::EventEncoder;
+use providers::{Backend, Format};
+use routing::{RequestFeatures, Route};
 use serde_json::json;
+use sse::anthropic_event;
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::sync::RwLock;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use upstream::{Original, Payload, UpstreamBody, UpstreamStream};
+
+/// Response header naming the upstream model that served a request
+pub const SERVED_MODEL_HEADER: &str = "x-ccorp-served-model";
 
 #[derive(Clone)]
 pub struct AppState {
     pub config: Arc<RwLock<Config>>,
     pub logging_path: Arc<Option<String>>,
+    pub models_cache: Arc<openrouter::ModelCache>,
+    pub batches: Arc<batches::BatchStore>,
+    pub probes: Arc<health::ProbeResults>,
+    pub breakers: Arc<circuit_breaker::CircuitBreakers>,
+    pub shutdown: Arc<shutdown::Shutdown>,
+    /// Client for all upstream requests, sharing connections between them
+    pub http: reqwest::Client,
+}
+
+#[cfg(test)]
+impl AppState {
+    /// A state for `config` without any background tasks
+    pub fn for_tests(config: Config) -> Self {
+        AppState {
+            logging_path: Arc::new(None),
+            models_cache: Arc::default(),
+            batches: Arc::new(batches::BatchStore::load(&config.batches).unwrap()),
+            probes: Arc::default(),
+            breakers: Arc::default(),
+            shutdown: Arc::default(),
+            http: reqwest::Client::new(),
+            config: Arc::new(RwLock::new(config)),
+        }
+    }
 }
 
 #[tokio::main]
@@ -57,162 +111,688 @@ async fn main() {
     println!("- Haiku: {}", settings.model_haiku);
     println!("- Sonnet: {}", settings.model_sonnet);
     println!("- Opus: {}", settings.model_opus);
+    for (alias, target) in &settings.aliases {
+        println!("- Alias {alias}: {target}");
+    }
+    if !settings.routing.rules.is_empty() {
+        println!("- {} routing rules", settings.routing.rules.len());
+    }
 
     let addr = std::net::SocketAddr::from(([0, 0, 0, 0], settings.port));
 
+    let batches = batches::BatchStore::load(&settings.batches).unwrap_or_else(|e| {
+        eprintln!("Could not load message batches: {e}");
+        std::process::exit(1);
+    });
     let state = AppState {
-        config: Arc::new(RwLock::new(settings)),
         logging_path: Arc::new(logging_path),
+        models_cache: Arc::new(openrouter::ModelCache::default()),
+        batches: Arc::new(batches),
+        http: settings
+            .http
+            .client()
+            .unwrap_or_else(|e| panic!("Invalid http settings: {e}")),
+        config: Arc::new(RwLock::new(settings)),
+        probes: Arc::new(health::ProbeResults::default()),
+        breakers: Arc::new(circuit_breaker::CircuitBreakers::default()),
+        shutdown: Arc::new(shutdown::Shutdown::default()),
     };
+    batches::resume(&state).await;
+    selection::refresh(&state).await;
+    selection::spawn_refresh(state.clone());
+    health::spawn_probes(state.clone());
 
     let app = Router::new()
         .route("/v1/messages", post(messages_handler))
+        .route(
+            "/v1/messages/batches",
+            get(batches::list_batches).post(batches::create_batch),
+        )
+        .route(
+            "/v1/messages/batches/{id}",
+            get(batches::get_batch).delete(batches::delete_batch),
+        )
+        .route(
+            "/v1/messages/batches/{id}/results",
+            get(batches::batch_results),
+        )
+        .route(
+            "/v1/messages/batches/{id}/cancel",
+            post(batches::cancel_batch),
+        )
+        .route(
+            "/v1/chat/completions",
+            post(chat_completions::chat_completions_handler),
+        )
+        .route(
+            "/anthropic/v1/chat/completions",
+            post(reverse::reverse_chat_completions_handler),
+        )
+        .route("/v1/models", get(model_list::list_models))
+        .route("/v1/models/{model_id}", get(model_list::get_model))
+        .route("/metrics", get(metrics::metrics_handler))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
         .route(
             "/switch-model",
             get(switch_model::switch_model_get).post(switch_model::switch_model_post),
         )
-        .with_state(state);
+        .with_state(state.clone());
 
     println!("listening on {addr}");
 
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown::signal(state))
+        .await
+        .unwrap();
 }
 
-async fn messages_handler(
-    State(state): State<AppState>,
-    headers: HeaderMap,
-    Json(payload): Json<AnthropicRequest>,
-) -> impl IntoResponse {
-    let settings_guard = state.config.read().await;
-    let openai_request = anthropic_to_openai::format_anthropic_to_openai(payload, &settings_guard);
+/// Build an error response in Anthropic's error format.
+pub fn error_response(status: StatusCode, message: impl Into<String>) -> Response {
+    let body = json!({
+        "type": "error",
+        "error": {
+            "type": openai_to_anthropic::error_type_for_status(status.as_u16()),
+            "message": message.into(),
+        }
+    });
+    (status, Json(body)).into_response()
+}
+
+/// The upstream API key sent by the client, either as `x-api-key` or as a bearer token.
+pub fn client_api_key(headers: &HeaderMap) -> Option<String> {
+    if let Some(key) = headers.get("x-api-key").and_then(|v| v.to_str().ok()) {
+        return Some(key.to_string());
+    }
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::to_string)
+}
 
+/// Write a request or response log file if `--logging` is enabled.
+fn write_log(state: &AppState, kind: &str, contents: &str) {
     if let Some(path) = state.logging_path.as_ref() {
         let timestamp = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
             .as_millis();
-        let request_path = format!("{path}/{timestamp}-request.json");
-        let request_json = serde_json::to_string_pretty(&openai_request).unwrap();
-        std::fs::write(request_path, request_json).expect("Failed to write request log");
+        let log_path = format!("{path}/{timestamp}-{kind}.json");
+        std::fs::write(log_path, contents).expect("Failed to write log");
     }
-    let client = Client::new();
-    let api_key = headers
-        .get("x-api-key")
-        .and_then(|v| v.to_str().ok())
-        .expect("Please set the ANTHROPIC_AUTH_TOKEN to your OpenRouter Key")
-        .to_string();
-
-    if openai_request.stream.unwrap_or(false) {
-        let base_url = settings_guard.base_url.clone();
-        drop(settings_guard);
-        let stream = async_stream::stream! {
-            let res = client
-                .post(format!(
-                    "{base_url}/chat/completions",
-                ))
-                .bearer_auth(api_key)
-                .json(&openai_request)
-                .send()
-                .await
-                .unwrap();
-
-            if !res.status().is_success() {
-                // This part is tricky because we are in a stream.
-                // We can't easily return a different response.
-                // For now, we'll just log the error and end the stream.
-                tracing::error!("OpenRouter request failed: {}", res.text().await.unwrap_or_default());
-                return;
+}
+
+async fn messages_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    let Some(api_key) = client_api_key(&headers) else {
+        return error_response(
+            StatusCode::UNAUTHORIZED,
+            "Please set the ANTHROPIC_AUTH_TOKEN to your OpenRouter Key",
+        );
+    };
+    // The body is kept as sent for Anthropic backends, which receive it untouched
+    let payload: AnthropicRequest = match serde_json::from_slice(&body) {
+        Ok(payload) => payload,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+    let original = Original::new(body, &headers);
+
+    let config = state.config.read().await;
+    let features = RequestFeatures::from_anthropic(&payload).with_session_header(&headers, &config);
+    let route = match routing::resolve_request(&payload.model, &features, &headers, &config) {
+        Ok(route) => route,
+        Err((status, message)) => return error_response(status, message),
+    };
+    let requested_model = metrics::requested_model_label(&config, &payload.model);
+    let targets = routing::targets(&config);
+    drop(config);
+    routing::record(&payload.model, &route, &features);
+
+    if !payload.stream.unwrap_or(false) {
+        let original = Some(original);
+        return match complete_message(&state, &api_key, payload, &features, original, route).await {
+            Ok(anthropic_response) => (
+                [(SERVED_MODEL_HEADER, anthropic_response.model.clone())],
+                Json(anthropic_response),
+            )
+                .into_response(),
+            Err((status, body)) => (status, body).into_response(),
+        };
+    }
+
+    let started = Instant::now();
+    let upstream_payload =
+        prepare_payload(&state, &api_key, payload, &features, Some(original), &route).await;
+    write_log(
+        &state,
+        "request",
+        &serde_json::to_string_pretty(upstream_payload.logged()).unwrap(),
+    );
+
+    let response = match upstream::send(&state, &api_key, &route, upstream_payload).await {
+        Ok(response) => response,
+        Err(e) => {
+            tracing::error!("OpenRouter request failed: {}", e.message);
+            let upstream_model = metrics::upstream_model_label(&targets, &route.model);
+            METRICS.inc(
+                "ccorp_requests_total",
+                &[
+                    ("requested_model", requested_model.as_str()),
+                    ("upstream_model", upstream_model.as_str()),
+                    ("status", e.status.as_str()),
+                ],
+            );
+            return (e.status, e.message).into_response();
+        }
+    };
+    let UpstreamBody::Stream(upstream_stream) = response.body else {
+        unreachable!("streaming requests get a streaming response")
+    };
+    let served_model = response.model;
+    let upstream_model = metrics::upstream_model_label(&targets, &served_model);
+    if response.format == Format::Messages {
+        return pass_through_stream(
+            state,
+            requested_model,
+            upstream_model,
+            served_model,
+            upstream_stream,
+            started,
+        );
+    }
+
+    let stream = async_stream::stream! {
+        let labels = [
+            ("requested_model", requested_model.as_str()),
+            ("upstream_model", upstream_model.as_str()),
+        ];
+        let mut active = metrics::ActiveStream::start(&labels, started);
+
+        let UpstreamStream { buffered, prefix, mut parser, mut body } = upstream_stream;
+        let mut full_response = String::from_utf8_lossy(&prefix).into_owned();
+        let mut events = buffered;
+        let mut first_token_at = None;
+        let mut usage = None;
+        let mut encoder = EventEncoder::default();
+        // The error event sent instead of the usual end when the stream does not finish
+        let mut failure = None;
+        let mut status = "200";
+
+        'stream: loop {
+            for event in events {
+                if event.data == "[DONE]" {
+                    break 'stream;
+                }
+                let Ok(stream_res) = serde_json::from_str::<OpenAIStreamResponse>(&event.data) else {
+                    METRICS.inc("ccorp_translation_repairs_total", &[("kind", "unparseable_stream_chunk")]);
+                    continue;
+                };
+                if let Some(error) = &stream_res.error {
+                    tracing::error!("OpenRouter stream reported an error: {error}");
+                    let message = error["message"].as_str().map_or_else(|| error.to_string(), str::to_string);
+                    failure = Some(stream_error(message));
+                    status = "502";
+                    break 'stream;
+                }
+                if stream_res.usage.is_some() {
+                    usage = stream_res.usage.clone();
+                }
+                let has_output = stream_res.choices.first().is_some_and(|choice| {
+                    choice.delta.content.is_some() || choice.delta.tool_calls.is_some()
+                });
+                if has_output && first_token_at.is_none() {
+                    first_token_at = Some(Instant::now());
+                    METRICS.observe(
+                        "ccorp_time_to_first_token_seconds",
+                        &labels,
+                        started.elapsed().as_secs_f64(),
+                    );
+                }
+                for sse_event in encoder.translate(&stream_res) {
+                    yield Ok::<_, axum::Error>(sse_event.into_bytes());
+                }
             }
 
-            let mut stream = res.bytes_stream();
+            events = match state.shutdown.next(&mut body).await {
+                Some(Some(Ok(chunk))) => {
+                    full_response.push_str(&String::from_utf8_lossy(&chunk));
+                    parser.push(&chunk)
+                }
+                Some(Some(Err(e))) => {
+                    tracing::error!("OpenRouter stream failed: {e}");
+                    failure = Some(stream_error(format!("The upstream stream failed: {e}")));
+                    status = "502";
+                    break;
+                }
+                Some(None) => break,
+                None => {
+                    failure = Some(shutdown::error());
+                    status = "503";
+                    break;
+                }
+            };
+        }
 
-            let mut full_response = String::new();
-            while let Some(item) = stream.next().await {
-                let chunk = item.unwrap();
-                full_response.push_str(&String::from_utf8_lossy(&chunk));
-                let chunk_str = String::from_utf8_lossy(&chunk);
-                for line in chunk_str.split("
+        if let Some(error) = failure {
+            yield Ok::<_, axum::Error>(anthropic_event(error).into_bytes());
+        } else {
+            for sse_event in encoder.finish() {
+                yield Ok::<_, axum::Error>(sse_event.into_bytes());
+            }
+        }
 
-") {
-                    if let Some(data) = line.strip_prefix("data: ") {
-                        if data == "[DONE]" {
-                            break;
-                        }
-                        if let Ok(stream_res) = serde_json::from_str::<OpenAIStreamResponse>(data) {
-                            let choice = &stream_res.choices[0];
-                            if let Some(content) = &choice.delta.content {
-                                let anthropic_stream_event = json!({
-                                    "type": "content_block_delta",
-                                    "index": 0,
-                                    "delta": {
-                                        "type": "text_delta",
-                                        "text": content
-                                    }
-                                });
-                                let sse_event = format!("event: content_block_delta
-data: {anthropic_stream_event}
-
-");
-                                yield Ok::<_, axum::Error>(sse_event.into_bytes());
-                            }
+        active.finish(status);
+        if let Some(usage) = usage {
+            record_usage(&labels, &usage, first_token_at);
+        }
+
+        write_log(&state, "response", &full_response);
+    };
+
+    let body = Body::from_stream(stream);
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .header(SERVED_MODEL_HEADER, served_model)
+        .body(body)
+        .unwrap()
+}
+
+/// The Anthropic error event ending a stream the upstream broke off
+fn stream_error(message: String) -> serde_json::Value {
+    json!({
+        "type": "error",
+        "error": { "type": "api_error", "message": message },
+    })
+}
+
+/// Stream Anthropic events from a passthrough or natively translated upstream to the client.
+fn pass_through_stream(
+    state: AppState,
+    requested_model: String,
+    upstream_model: String,
+    served_model: String,
+    upstream_stream: UpstreamStream,
+    started: Instant,
+) -> Response {
+    let stream = async_stream::stream! {
+        let labels = [
+            ("requested_model", requested_model.as_str()),
+            ("upstream_model", upstream_model.as_str()),
+        ];
+        let mut active = metrics::ActiveStream::start(&labels, started);
+
+        let UpstreamStream { buffered, prefix, mut parser, mut body } = upstream_stream;
+        let mut full_response = String::from_utf8_lossy(&prefix).into_owned();
+        let mut events = buffered;
+        let mut first_token_at = None;
+        let mut usage = OpenAIUsage::default();
+        let mut status = "200";
+        yield Ok::<_, axum::Error>(prefix);
+
+        loop {
+            for event in events {
+                let Ok(event) = serde_json::from_str::<serde_json::Value>(&event.data) else {
+                    continue;
+                };
+                match event["type"].as_str() {
+                    Some("message_start") => {
+                        usage.prompt_tokens = event["message"]["usage"]["input_tokens"].as_u64().unwrap_or(0);
+                    }
+                    Some("content_block_delta") if first_token_at.is_none() => {
+                        first_token_at = Some(Instant::now());
+                        METRICS.observe(
+                            "ccorp_time_to_first_token_seconds",
+                            &labels,
+                            started.elapsed().as_secs_f64(),
+                        );
+                    }
+                    Some("error") => {
+                        tracing::error!("Anthropic stream reported an error: {}", event["error"]);
+                        status = "502";
+                    }
+                    Some("message_delta") => {
+                        usage.completion_tokens = event["usage"]["output_tokens"].as_u64().unwrap_or(0);
+                        // Translated streams only know the prompt size at the end
+                        if let Some(input_tokens) = event["usage"]["input_tokens"].as_u64() {
+                            usage.prompt_tokens = input_tokens;
                         }
                     }
+                    _ => {}
                 }
             }
 
-            let message_stop = json!({
-                "type": "message_stop"
-            });
-            let sse_event = format!("event: message_stop
-data: {message_stop}
-
-");
-            yield Ok::<_, axum::Error>(sse_event.into_bytes());
-
-            if let Some(path) = state.logging_path.as_ref() {
-                let timestamp = std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_millis();
-                let response_path = format!("{path}/{timestamp}-response.json");
-                std::fs::write(response_path, full_response).expect("Failed to write response log");
-            }
-        };
+            events = match state.shutdown.next(&mut body).await {
+                Some(Some(Ok(chunk))) => {
+                    full_response.push_str(&String::from_utf8_lossy(&chunk));
+                    let events = parser.push(&chunk);
+                    yield Ok(chunk.to_vec());
+                    events
+                }
+                Some(Some(Err(e))) => {
+                    tracing::error!("Anthropic stream failed: {e}");
+                    let error = stream_error(format!("The upstream stream failed: {e}"));
+                    yield Ok(anthropic_event(error).into_bytes());
+                    status = "502";
+                    break;
+                }
+                Some(None) => break,
+                None => {
+                    yield Ok(anthropic_event(shutdown::error()).into_bytes());
+                    status = "503";
+                    break;
+                }
+            };
+        }
 
-        let body = Body::from_stream(stream);
+        active.finish(status);
+        record_usage(&labels, &usage, first_token_at);
 
-        Response::builder()
-            .header(header::CONTENT_TYPE, "text/event-stream")
-            .body(body)
-            .unwrap()
-    } else {
-        let res = client
-            .post(format!("{}/chat/completions", settings_guard.base_url))
-            .bearer_auth(api_key)
-            .json(&openai_request)
-            .send()
-            .await
-            .unwrap();
+        write_log(&state, "response", &full_response);
+    };
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .header(SERVED_MODEL_HEADER, served_model)
+        .body(Body::from_stream(stream))
+        .unwrap()
+}
 
-        if !res.status().is_success() {
-            return (res.status(), res.text().await.unwrap_or_default()).into_response();
+/// Build the bodies the route's upstreams need: the request itself for Anthropic
+/// providers, and otherwise its translation, fit into the context window of the routed model.
+async fn prepare_payload(
+    state: &AppState,
+    api_key: &str,
+    payload: AnthropicRequest,
+    features: &RequestFeatures,
+    original: Option<Original>,
+    route: &Route,
+) -> Payload {
+    let config = state.config.read().await;
+    let mut backends: Vec<(&String, Backend)> = Vec::new();
+    let mut uses_openrouter = false;
+    for model in route.candidates() {
+        if backends.iter().all(|(m, _)| *m != model) {
+            let endpoint = providers::endpoint(&config, model, api_key);
+            uses_openrouter |= endpoint.provider.is_none();
+            backends.push((model, endpoint.backend));
         }
+    }
+    drop(config);
 
-        let openai_response: models::OpenAIResponse = res.json().await.unwrap();
-        let anthropic_response =
-            openai_to_anthropic::format_openai_to_anthropic(openai_response.clone());
-
-        if let Some(path) = state.logging_path.as_ref() {
-            let timestamp = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_millis();
-            let response_path = format!("{path}/{timestamp}-response.json");
-            let response_json = serde_json::to_string_pretty(&anthropic_response).unwrap();
-            std::fs::write(response_path, response_json).expect("Failed to write response log");
+    // Every model gets the request adapted to what it supports. The catalog only lists
+    // OpenRouter models, so routes to other providers do not wait for it.
+    let catalog = if uses_openrouter {
+        capabilities::catalog(state).await
+    } else {
+        Arc::default()
+    };
+    let mut upstream_payload = Payload::default().with_original(original);
+    for (model, backend) in backends {
+        let mut request = payload.clone();
+        // Anthropic backends receive the client's request untouched
+        if backend != Backend::Anthropic
+            && let Err((status, message)) = capabilities::adapt_to_model(
+                &mut request,
+                model,
+                &catalog,
+                features,
+                route.overflow.strategy,
+            )
+        {
+            upstream_payload.reject(model, status, &message);
+            continue;
         }
+        match backend.format() {
+            Format::Chat => {
+                let mut openai_request =
+                    anthropic_to_openai::format_anthropic_to_openai(request, route);
+                overflow::fit(
+                    state,
+                    api_key,
+                    &mut openai_request,
+                    model,
+                    &route.overflow,
+                    &catalog,
+                )
+                .await;
+                upstream_payload.add(model, openai_request);
+            }
+            Format::Messages => upstream_payload.add(model, request),
+        }
+    }
+    upstream_payload
+}
+
+/// Translate a non-streaming Anthropic request, send it upstream and translate the answer back.
+pub async fn complete_message(
+    state: &AppState,
+    api_key: &str,
+    payload: AnthropicRequest,
+    features: &RequestFeatures,
+    original: Option<Original>,
+    route: Route,
+) -> Result<AnthropicResponse, (StatusCode, String)> {
+    let started = Instant::now();
+    let (requested_model, targets) = {
+        let config = state.config.read().await;
+        let requested_model = metrics::requested_model_label(&config, &payload.model);
+        (requested_model, routing::targets(&config))
+    };
+    let upstream_payload =
+        prepare_payload(state, api_key, payload, features, original, &route).await;
+    write_log(
+        state,
+        "request",
+        &serde_json::to_string_pretty(upstream_payload.logged()).unwrap(),
+    );
+
+    let result = upstream::send(state, api_key, &route, upstream_payload)
+        .await
+        .map_err(|e| (e.status, e.message))
+        .and_then(|response| {
+            let UpstreamBody::Complete(value) = response.body else {
+                unreachable!("non-streaming requests get a complete response")
+            };
+            let bad_gateway = |e: serde_json::Error| (StatusCode::BAD_GATEWAY, e.to_string());
+            let anthropic_response = match response.format {
+                Format::Chat => {
+                    let openai_response: OpenAIResponse =
+                        serde_json::from_value(value).map_err(bad_gateway)?;
+                    let usage = openai_response.usage.clone();
+                    (
+                        openai_to_anthropic::format_openai_to_anthropic(openai_response),
+                        usage,
+                    )
+                }
+                Format::Messages => {
+                    let anthropic_response: AnthropicResponse =
+                        serde_json::from_value(value).map_err(bad_gateway)?;
+                    let usage = anthropic_response.usage.as_ref().map(|usage| OpenAIUsage {
+                        prompt_tokens: usage.input_tokens,
+                        completion_tokens: usage.output_tokens,
+                    });
+                    (anthropic_response, usage)
+                }
+            };
+            Ok((response.model, anthropic_response))
+        });
+
+    let (upstream_model, status) = match &result {
+        Ok((model, _)) => (model.as_str(), StatusCode::OK),
+        Err((status, _)) => (route.model.as_str(), *status),
+    };
+    let upstream_model = metrics::upstream_model_label(&targets, upstream_model);
+    let labels = [
+        ("requested_model", requested_model.as_str()),
+        ("upstream_model", upstream_model.as_str()),
+    ];
+    METRICS.inc(
+        "ccorp_requests_total",
+        &[labels[0], labels[1], ("status", status.as_str())],
+    );
+    METRICS.observe(
+        "ccorp_request_duration_seconds",
+        &labels,
+        started.elapsed().as_secs_f64(),
+    );
+    if let Ok((_, (_, Some(usage)))) = &result {
+        record_usage(&labels, usage, None);
+    }
+
+    let (_, (anthropic_response, _)) = result?;
+    write_log(
+        state,
+        "response",
+        &serde_json::to_string_pretty(&anthropic_response).unwrap(),
+    );
+
+    Ok(anthropic_response)
+}
+
+/// Record token usage and, for streams, generation throughput.
+fn record_usage(
+    labels: &[(&'static str, &str)],
+    usage: &OpenAIUsage,
+    first_token_at: Option<Instant>,
+) {
+    let with_type = |kind| [labels[0], labels[1], ("type", kind)];
+    METRICS.add(
+        "ccorp_tokens_total",
+        &with_type("input"),
+        usage.prompt_tokens as f64,
+    );
+    METRICS.add(
+        "ccorp_tokens_total",
+        &with_type("output"),
+        usage.completion_tokens as f64,
+    );
+    if let Some(first_token_at) = first_token_at {
+        let seconds = first_token_at.elapsed().as_secs_f64();
+        if seconds > 0.0 && usage.completion_tokens > 0 {
+            METRICS.observe(
+                "ccorp_tokens_per_second",
+                labels,
+                usage.completion_tokens as f64 / seconds,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::to_bytes;
+    use tempfile::TempDir;
+
+    /// An OpenRouter upstream streaming `chunks` for every request, breaking the
+    /// connection at a `None`
+    async fn upstream(chunks: Vec<Option<String>>) -> String {
+        let app = axum::Router::new()
+            .route(
+                "/models",
+                axum::routing::get(|| async { Json(json!({ "data": [] })) }),
+            )
+            .route(
+                "/chat/completions",
+                axum::routing::post(move || {
+                    let chunks = chunks.clone();
+                    let body = async_stream::stream! {
+                        for chunk in chunks {
+                            match chunk {
+                                Some(data) => yield Ok(format!("data: {data}\n\n")),
+                                None => {
+                                    // Let the chunks so far reach the proxy first
+                                    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                                    yield Err(std::io::Error::other("connection reset"));
+                                }
+                            }
+                        }
+                    };
+                    async move {
+                        Response::builder()
+                            .header(header::CONTENT_TYPE, "text/event-stream")
+                            .body(Body::from_stream(body))
+                            .unwrap()
+                    }
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{addr}")
+    }
 
-        (StatusCode::OK, Json(anthropic_response)).into_response()
+    /// The events the proxy streams for a request to `base_url`
+    async fn streamed_events(base_url: String) -> Vec<serde_json::Value> {
+        let dir = TempDir::new().unwrap();
+        let state = AppState::for_tests(Config {
+            base_url,
+            model_sonnet: "a/b".to_string(),
+            batches: config::BatchConfig {
+                dir: dir.path().to_string_lossy().into_owned(),
+                ..config::BatchConfig::default()
+            },
+            ..Config::default()
+        });
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", "key".parse().unwrap());
+        let body = json!({
+            "model": "claude-sonnet-4-20250514",
+            "max_tokens": 16,
+            "stream": true,
+            "messages": [{ "role": "user", "content": "hello" }],
+        });
+        let response = messages_handler(State(state), headers, Bytes::from(body.to_string()))
+            .await
+            .into_response();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let mut parser = sse::SseParser::default();
+        parser
+            .push(&body)
+            .iter()
+            .map(|event| serde_json::from_str(&event.data).unwrap())
+            .collect()
+    }
+
+    fn content(text: &str) -> Option<String> {
+        Some(json!({ "id": "c1", "model": "a/b", "choices": [{ "index": 0, "delta": { "content": text } }] })
+            .to_string())
+    }
+
+    #[tokio::test]
+    async fn broken_streams_end_with_an_error_event() {
+        let failed = streamed_events(upstream(vec![content("Hel"), None]).await).await;
+        let reported = streamed_events(
+            upstream(vec![
+                content("Hel"),
+                Some(
+                    json!({
+                        "error": { "code": 502, "message": "Provider disconnected" },
+                        "choices": [{ "index": 0, "delta": {}, "finish_reason": "error" }],
+                    })
+                    .to_string(),
+                ),
+                Some("[DONE]".to_string()),
+            ])
+            .await,
+        )
+        .await;
+
+        for events in [&failed, &reported] {
+            let types: Vec<&str> = events.iter().filter_map(|e| e["type"].as_str()).collect();
+            assert!(types.contains(&"content_block_delta"), "{types:?}");
+            assert!(!types.contains(&"message_stop"), "{types:?}");
+            assert_eq!(types.last(), Some(&"error"));
+        }
+        assert_eq!(
+            reported.last().unwrap()["error"]["message"],
+            "Provider disconnected"
+        );
     }
 }
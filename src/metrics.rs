@@ -0,0 +1,370 @@
+use crate::config::Config;
+use crate::model_list;
+use axum::{http::header, response::IntoResponse};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{LazyLock, Mutex};
+use std::time::Instant;
+
+/// Process-wide metrics registry, rendered by `GET /metrics`
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+/// Name, type and help text of every exported metric
+const METRIC_INFO: &[(&str, &str, &str)] = &[
+    (
+        "ccorp_requests_total",
+        "counter",
+        "Requests handled, by requested Claude model, resolved upstream model and response status",
+    ),
+    (
+        "ccorp_request_duration_seconds",
+        "histogram",
+        "Total time to handle a request, including the full stream",
+    ),
+    (
+        "ccorp_time_to_first_token_seconds",
+        "histogram",
+        "Time until the first content was received from the upstream",
+    ),
+    (
+        "ccorp_tokens_per_second",
+        "histogram",
+        "Output tokens per second after the first token",
+    ),
+    (
+        "ccorp_tokens_total",
+        "counter",
+        "Tokens reported by the upstream, by type (input or output)",
+    ),
+    (
+        "ccorp_upstream_responses_total",
+        "counter",
+        "Upstream HTTP responses by status code",
+    ),
+    (
+        "ccorp_upstream_retries_total",
+        "counter",
+        "Retries of failed upstream requests, by upstream model",
+    ),
+    (
+        "ccorp_circuit_state",
+        "gauge",
+        "Circuit breaker state by upstream model: 0 closed, 1 open, 2 half-open",
+    ),
+    (
+        "ccorp_circuit_rejections_total",
+        "counter",
+        "Requests not sent to an upstream model because its circuit was open",
+    ),
+    (
+        "ccorp_fallbacks_total",
+        "counter",
+        "Requests moved from a failing upstream model to a fallback",
+    ),
+    (
+        "ccorp_split_requests_total",
+        "counter",
+        "Requests assigned to each arm of a weighted split, by rule",
+    ),
+    (
+        "ccorp_translation_repairs_total",
+        "counter",
+        "Upstream output that had to be repaired or skipped during translation",
+    ),
+    (
+        "ccorp_request_adaptations_total",
+        "counter",
+        "Requests changed to fit the capabilities of their upstream model, by kind",
+    ),
+    (
+        "ccorp_active_streams",
+        "gauge",
+        "Streaming responses currently in progress",
+    ),
+];
+
+const DURATION_BUCKETS: &[f64] = &[
+    0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+const THROUGHPUT_BUCKETS: &[f64] = &[1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 200.0, 500.0];
+
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Default)]
+struct Histogram {
+    /// Cumulative count per bucket, aligned with the metric's bucket list
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    values: Mutex<BTreeMap<&'static str, BTreeMap<Labels, f64>>>,
+    histograms: Mutex<BTreeMap<&'static str, BTreeMap<Labels, Histogram>>>,
+}
+
+fn owned_labels(labels: &[(&'static str, &str)]) -> Labels {
+    labels.iter().map(|(k, v)| (*k, v.to_string())).collect()
+}
+
+fn buckets_for(name: &str) -> &'static [f64] {
+    if name == "ccorp_tokens_per_second" {
+        THROUGHPUT_BUCKETS
+    } else {
+        DURATION_BUCKETS
+    }
+}
+
+fn format_labels(labels: &Labels, extra: Option<(&str, String)>) -> String {
+    let parts: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| (*k, v.clone()))
+        .chain(extra)
+        .map(|(k, v)| {
+            let escaped = v
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{k}=\"{escaped}\"")
+        })
+        .collect();
+    if parts.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", parts.join(","))
+    }
+}
+
+impl Metrics {
+    /// Add `value` to a counter or gauge.
+    pub fn add(&self, name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+        *self
+            .values
+            .lock()
+            .unwrap()
+            .entry(name)
+            .or_default()
+            .entry(owned_labels(labels))
+            .or_default() += value;
+    }
+
+    /// Set a gauge to `value`.
+    pub fn set(&self, name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+        self.values
+            .lock()
+            .unwrap()
+            .entry(name)
+            .or_default()
+            .insert(owned_labels(labels), value);
+    }
+
+    /// Increment a counter by one.
+    pub fn inc(&self, name: &'static str, labels: &[(&'static str, &str)]) {
+        self.add(name, labels, 1.0);
+    }
+
+    /// Record an observation in a histogram.
+    pub fn observe(&self, name: &'static str, labels: &[(&'static str, &str)], value: f64) {
+        let bounds = buckets_for(name);
+        let mut histograms = self.histograms.lock().unwrap();
+        let histogram = histograms
+            .entry(name)
+            .or_default()
+            .entry(owned_labels(labels))
+            .or_insert_with(|| Histogram {
+                buckets: vec![0; bounds.len()],
+                ..Default::default()
+            });
+        for (bucket, bound) in histogram.buckets.iter_mut().zip(bounds) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        histogram.sum += value;
+        histogram.count += 1;
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let values = self.values.lock().unwrap();
+        let histograms = self.histograms.lock().unwrap();
+        let mut out = String::new();
+
+        for (name, kind, help) in METRIC_INFO {
+            writeln!(out, "# HELP {name} {help}").unwrap();
+            writeln!(out, "# TYPE {name} {kind}").unwrap();
+            for (labels, value) in values.get(name).into_iter().flatten() {
+                writeln!(out, "{name}{} {value}", format_labels(labels, None)).unwrap();
+            }
+            for (labels, histogram) in histograms.get(name).into_iter().flatten() {
+                for (count, bound) in histogram.buckets.iter().zip(buckets_for(name)) {
+                    let labels = format_labels(labels, Some(("le", bound.to_string())));
+                    writeln!(out, "{name}_bucket{labels} {count}").unwrap();
+                }
+                let inf = format_labels(labels, Some(("le", "+Inf".to_string())));
+                writeln!(out, "{name}_bucket{inf} {}", histogram.count).unwrap();
+                let labels = format_labels(labels, None);
+                writeln!(out, "{name}_sum{labels} {}", histogram.sum).unwrap();
+                writeln!(out, "{name}_count{labels} {}", histogram.count).unwrap();
+            }
+        }
+
+        out
+    }
+}
+
+/// The `requested_model` label for a model named by a client. Names the proxy does not
+/// know are counted as `other`, so clients cannot create unbounded label values.
+pub fn requested_model_label(config: &Config, model: &str) -> String {
+    if model_list::is_known(config, model) {
+        model.to_string()
+    } else {
+        "other".to_string()
+    }
+}
+
+/// The `upstream_model` label for a model a request went to or was served by, given the
+/// configured routing `targets`. Dated versions reported by the upstream count as the
+/// target they extend, and models the configuration does not name, e.g. passed through
+/// from clients, are counted as `other`.
+pub fn upstream_model_label(targets: &[String], model: &str) -> String {
+    targets
+        .iter()
+        .filter(|target| model.starts_with(target.as_str()))
+        .max_by_key(|target| target.len())
+        .cloned()
+        .unwrap_or_else(|| "other".to_string())
+}
+
+/// Keeps `ccorp_active_streams` up to date for as long as a stream is alive, and counts
+/// the request in `ccorp_requests_total` and `ccorp_request_duration_seconds` once it ends
+pub struct ActiveStream {
+    labels: Vec<(&'static str, String)>,
+    started: Instant,
+    status: &'static str,
+}
+
+impl ActiveStream {
+    pub fn start(labels: &[(&'static str, &str)], started: Instant) -> Self {
+        METRICS.add("ccorp_active_streams", labels, 1.0);
+        ActiveStream {
+            labels: owned_labels(labels),
+            started,
+            // Streams dropped before they finish were closed by the client
+            status: "499",
+        }
+    }
+
+    /// Record how the stream ended: `200`, `502` for upstream failures and error events,
+    /// or `503` when shutdown cut it off.
+    pub fn finish(&mut self, status: &'static str) {
+        self.status = status;
+    }
+}
+
+impl Drop for ActiveStream {
+    fn drop(&mut self) {
+        let labels: Vec<(&'static str, &str)> =
+            self.labels.iter().map(|(k, v)| (*k, v.as_str())).collect();
+        METRICS.add("ccorp_active_streams", &labels, -1.0);
+        METRICS.inc(
+            "ccorp_requests_total",
+            &[labels[0], labels[1], ("status", self.status)],
+        );
+        METRICS.observe(
+            "ccorp_request_duration_seconds",
+            &labels,
+            self.started.elapsed().as_secs_f64(),
+        );
+    }
+}
+
+/// GET /metrics - Prometheus metrics
+pub async fn metrics_handler() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        METRICS.render(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_the_text_exposition_format() {
+        let metrics = Metrics::default();
+        let labels = [
+            ("requested_model", "claude-sonnet-4"),
+            ("upstream_model", "a/\"b\""),
+        ];
+        metrics.inc(
+            "ccorp_requests_total",
+            &[labels[0], labels[1], ("status", "200")],
+        );
+        metrics.inc(
+            "ccorp_requests_total",
+            &[labels[0], labels[1], ("status", "200")],
+        );
+        metrics.observe("ccorp_request_duration_seconds", &labels, 0.3);
+
+        let text = metrics.render();
+        let lines: Vec<&str> = text.lines().collect();
+        let help = lines
+            .iter()
+            .position(|line| line.starts_with("# HELP ccorp_requests_total "))
+            .unwrap();
+        assert_eq!(lines[help + 1], "# TYPE ccorp_requests_total counter");
+        assert_eq!(
+            lines[help + 2],
+            r#"ccorp_requests_total{requested_model="claude-sonnet-4",upstream_model="a/\"b\"",status="200"} 2"#
+        );
+
+        let labels = r#"requested_model="claude-sonnet-4",upstream_model="a/\"b\"""#;
+        for expected in [
+            "# TYPE ccorp_request_duration_seconds histogram".to_string(),
+            format!(r#"ccorp_request_duration_seconds_bucket{{{labels},le="0.25"}} 0"#),
+            format!(r#"ccorp_request_duration_seconds_bucket{{{labels},le="0.5"}} 1"#),
+            format!(r#"ccorp_request_duration_seconds_bucket{{{labels},le="+Inf"}} 1"#),
+            format!("ccorp_request_duration_seconds_sum{{{labels}}} 0.3"),
+            format!("ccorp_request_duration_seconds_count{{{labels}}} 1"),
+        ] {
+            assert!(lines.contains(&expected.as_str()), "missing {expected}");
+        }
+        // Every metric is described, even before it has values
+        assert!(lines.contains(&"# TYPE ccorp_active_streams gauge"));
+    }
+
+    #[test]
+    fn counts_unknown_requested_models_as_other() {
+        let mut config = Config::default();
+        config
+            .aliases
+            .insert("fast".to_string(), "claude-haiku-4-5-20251001".to_string());
+        let label = |model: &str| requested_model_label(&config, model);
+        assert_eq!(
+            label("claude-sonnet-4-20250514"),
+            "claude-sonnet-4-20250514"
+        );
+        assert_eq!(label("claude-sonnet-4"), "claude-sonnet-4");
+        assert_eq!(label("claude-3-5-haiku-latest"), "claude-3-5-haiku-latest");
+        assert_eq!(label("fast"), "fast");
+        assert_eq!(label("anything/the-client-wants"), "other");
+    }
+
+    #[test]
+    fn counts_unconfigured_upstream_models_as_other() {
+        let targets = [
+            "a/b".to_string(),
+            "a/b-large".to_string(),
+            "local/qwen".to_string(),
+        ];
+        let label = |model: &str| upstream_model_label(&targets, model);
+        assert_eq!(label("a/b"), "a/b");
+        assert_eq!(label("a/b-large"), "a/b-large");
+        assert_eq!(label("a/b-20250514"), "a/b");
+        assert_eq!(label("local/qwen"), "local/qwen");
+        assert_eq!(label("client/chosen-model"), "other");
+    }
+}
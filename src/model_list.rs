@@ -0,0 +1,281 @@
+use crate::config::Config;
+use crate::error_response;
+use crate::openrouter::{Model, ModelSource};
+use crate::routing::{self, RequestFeatures};
+use axum::{
+    extract::{Json, Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+
+/// Claude models the proxy knows how to map: (id, display name, release date)
+const CLAUDE_MODELS: &[(&str, &str, &str)] = &[
+    (
+        "claude-sonnet-4-5-20250929",
+        "Claude Sonnet 4.5",
+        "2025-09-29T00:00:00Z",
+    ),
+    (
+        "claude-haiku-4-5-20251001",
+        "Claude Haiku 4.5",
+        "2025-10-15T00:00:00Z",
+    ),
+    (
+        "claude-opus-4-1-20250805",
+        "Claude Opus 4.1",
+        "2025-08-05T00:00:00Z",
+    ),
+    (
+        "claude-opus-4-20250514",
+        "Claude Opus 4",
+        "2025-05-22T00:00:00Z",
+    ),
+    (
+        "claude-sonnet-4-20250514",
+        "Claude Sonnet 4",
+        "2025-05-22T00:00:00Z",
+    ),
+    (
+        "claude-3-7-sonnet-20250219",
+        "Claude Sonnet 3.7",
+        "2025-02-24T00:00:00Z",
+    ),
+    (
+        "claude-3-5-sonnet-20241022",
+        "Claude Sonnet 3.5 (New)",
+        "2024-10-22T00:00:00Z",
+    ),
+    (
+        "claude-3-5-haiku-20241022",
+        "Claude Haiku 3.5",
+        "2024-10-22T00:00:00Z",
+    ),
+    (
+        "claude-3-haiku-20240307",
+        "Claude Haiku 3",
+        "2024-03-07T00:00:00Z",
+    ),
+];
+
+/// A single entry of the Anthropic model list, extended with the upstream mapping
+#[derive(Debug, Serialize)]
+pub struct ModelInfo {
+    #[serde(rename = "type")]
+    pub info_type: &'static str,
+    pub id: String,
+    pub display_name: String,
+    pub created_at: String,
+    /// The OpenRouter model this name currently resolves to
+    pub resolved_model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolved_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_length: Option<i32>,
+    /// The Claude model an alias stands for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alias_for: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ListParams {
+    limit: Option<usize>,
+    after_id: Option<String>,
+    before_id: Option<String>,
+}
+
+/// Whether clients may name `id`: a listed Claude model, also without its date or with
+/// `-latest` in its place, or a configured alias.
+pub fn is_known(config: &Config, id: &str) -> bool {
+    let undated = |model: &'static str| model.rsplit_once('-').map_or(model, |(name, _)| name);
+    config.aliases.contains_key(id)
+        || CLAUDE_MODELS.iter().any(|(model, _, _)| {
+            *model == id
+                || undated(model) == id
+                || id.strip_suffix("-latest") == Some(undated(model))
+        })
+}
+
+fn model_infos(config: &Config, upstream: &[Model]) -> Vec<ModelInfo> {
+    let entry = |id: &str, display_name: String, created_at: &str, alias_for: Option<String>| {
+        // Models rejected by the routing rules are not accepted, so they are not listed
+        let resolved_model = routing::resolve(id, &RequestFeatures::default(), config)
+            .ok()?
+            .model;
+        let upstream_model = upstream.iter().find(|m| m.id == resolved_model);
+        Some(ModelInfo {
+            info_type: "model",
+            id: id.to_string(),
+            display_name,
+            created_at: created_at.to_string(),
+            resolved_name: upstream_model.map(|m| m.name.clone()),
+            context_length: upstream_model.and_then(|m| m.context_length),
+            resolved_model,
+            alias_for,
+        })
+    };
+
+    let mut infos: Vec<ModelInfo> = CLAUDE_MODELS
+        .iter()
+        .filter_map(|(id, name, created_at)| entry(id, name.to_string(), created_at, None))
+        .collect();
+
+    for (alias, target) in &config.aliases {
+        let (display_name, created_at) = CLAUDE_MODELS
+            .iter()
+            .find(|(id, _, _)| id == target)
+            .map(|(_, name, created_at)| (format!("{name} ({alias})"), *created_at))
+            .unwrap_or_else(|| (alias.clone(), "1970-01-01T00:00:00Z"));
+        infos.extend(entry(alias, display_name, created_at, Some(target.clone())));
+    }
+
+    infos
+}
+
+/// GET /v1/models - List the Claude model names the proxy accepts
+pub async fn list_models(
+    State(state): State<crate::AppState>,
+    Query(params): Query<ListParams>,
+) -> impl IntoResponse {
+    // Cloned so the configuration is not locked while the model list is fetched
+    let config = state.config.read().await.clone();
+    let upstream = state
+        .models_cache
+        .get(&state.http, &ModelSource::new(&config))
+        .await
+        .unwrap_or_else(|e| {
+            tracing::warn!("Could not fetch OpenRouter models: {e}");
+            Arc::default()
+        });
+    let mut infos = model_infos(&config, &upstream);
+
+    if let Some(after_id) = &params.after_id
+        && let Some(index) = infos.iter().position(|m| &m.id == after_id)
+    {
+        infos.drain(..=index);
+    }
+    if let Some(before_id) = &params.before_id
+        && let Some(index) = infos.iter().position(|m| &m.id == before_id)
+    {
+        infos.truncate(index);
+    }
+    let limit = params.limit.unwrap_or(20).clamp(1, 1000);
+    let has_more = infos.len() > limit;
+    infos.truncate(limit);
+
+    Json(json!({
+        "first_id": infos.first().map(|m| m.id.clone()),
+        "last_id": infos.last().map(|m| m.id.clone()),
+        "has_more": has_more,
+        "data": infos,
+    }))
+}
+
+/// GET /v1/models/{model_id} - Describe a single accepted model name
+pub async fn get_model(
+    State(state): State<crate::AppState>,
+    Path(model_id): Path<String>,
+) -> Response {
+    let config = state.config.read().await.clone();
+    let upstream = state
+        .models_cache
+        .get(&state.http, &ModelSource::new(&config))
+        .await
+        .unwrap_or_default();
+
+    match model_infos(&config, &upstream)
+        .into_iter()
+        .find(|m| m.id == model_id)
+    {
+        Some(info) => Json(info).into_response(),
+        None => error_response(StatusCode::NOT_FOUND, format!("model: {model_id}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(extra: serde_json::Value) -> Config {
+        let mut config = Config {
+            model_haiku: "a/small".to_string(),
+            model_sonnet: "b/medium".to_string(),
+            model_opus: "c/large".to_string(),
+            ..Config::default()
+        };
+        if let Some(aliases) = extra.get("aliases") {
+            config.aliases = serde_json::from_value(aliases.clone()).unwrap();
+        }
+        if let Some(routing) = extra.get("routing") {
+            config.routing = serde_json::from_value(routing.clone()).unwrap();
+        }
+        config
+    }
+
+    fn upstream() -> Vec<Model> {
+        serde_json::from_value(json!([
+            { "id": "b/medium", "name": "Medium", "context_length": 128000 },
+        ]))
+        .unwrap()
+    }
+
+    #[test]
+    fn lists_claude_models_with_their_mappings() {
+        let infos = model_infos(&config(json!({})), &upstream());
+        assert_eq!(infos.len(), CLAUDE_MODELS.len());
+
+        let sonnet = infos
+            .iter()
+            .find(|m| m.id == "claude-sonnet-4-5-20250929")
+            .unwrap();
+        assert_eq!(sonnet.resolved_model, "b/medium");
+        assert_eq!(sonnet.resolved_name.as_deref(), Some("Medium"));
+        assert_eq!(sonnet.context_length, Some(128000));
+
+        let haiku = infos
+            .iter()
+            .find(|m| m.id == "claude-3-5-haiku-20241022")
+            .unwrap();
+        assert_eq!(haiku.resolved_model, "a/small");
+        assert!(haiku.resolved_name.is_none());
+    }
+
+    #[test]
+    fn lists_aliases_and_leaves_out_rejected_models() {
+        let config = config(json!({
+            "aliases": { "fast": "claude-haiku-4-5-20251001", "house": "d/custom" },
+            "routing": {
+                "rules": [
+                    { "match": { "glob": "*haiku*" }, "mapping": "haiku" },
+                    { "match": { "exact": "d/custom" }, "model": "d/custom" },
+                ],
+                "reject_unmapped": true,
+            },
+        }));
+        let infos = model_infos(&config, &upstream());
+        let ids: Vec<&str> = infos.iter().map(|m| m.id.as_str()).collect();
+        assert_eq!(
+            ids,
+            [
+                "claude-haiku-4-5-20251001",
+                "claude-3-5-haiku-20241022",
+                "claude-3-haiku-20240307",
+                "fast",
+                "house",
+            ]
+        );
+
+        let fast = &infos[3];
+        assert_eq!(fast.display_name, "Claude Haiku 4.5 (fast)");
+        assert_eq!(fast.created_at, "2025-10-15T00:00:00Z");
+        assert_eq!(fast.alias_for.as_deref(), Some("claude-haiku-4-5-20251001"));
+        assert_eq!(fast.resolved_model, "a/small");
+
+        let house = &infos[4];
+        assert_eq!(house.display_name, "house");
+        assert_eq!(house.created_at, "1970-01-01T00:00:00Z");
+        assert_eq!(house.resolved_model, "d/custom");
+    }
+}
@@ -2,13 +2,13 @@ use serde::{Deserialize, Serialize};
 
 // Anthropic API Structs
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AnthropicMessage {
     pub role: String,
     pub content: serde_json::Value,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AnthropicRequest {
     pub model: String,
     pub messages: Vec<AnthropicMessage>,
@@ -20,6 +20,24 @@ pub struct AnthropicRequest {
     pub tools: Option<Vec<serde_json::Value>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub stream: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_sequences: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thinking: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct AnthropicUsage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -32,6 +50,8 @@ pub struct AnthropicResponse {
     pub stop_reason: String,
     pub stop_sequence: Option<String>,
     pub model: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<AnthropicUsage>,
 }
 
 // OpenAI API Structs
@@ -40,13 +60,27 @@ pub struct AnthropicResponse {
 pub struct OpenAIMessage {
     pub role: String,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub content: Option<String>,
+    pub content: Option<OpenAIContent>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tool_calls: Option<Vec<OpenAIToolCall>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tool_call_id: Option<String>,
 }
 
+/// Message content, either plain text or a list of parts when images are attached
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum OpenAIContent {
+    Text(String),
+    Parts(Vec<serde_json::Value>),
+}
+
+impl From<String> for OpenAIContent {
+    fn from(text: String) -> Self {
+        OpenAIContent::Text(text)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct OpenAIToolCall {
     pub id: String,
@@ -71,6 +105,24 @@ pub struct OpenAIRequest {
     pub stream: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tools: Option<Vec<serde_json::Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<serde_json::Value>,
+    /// OpenRouter fallback models, tried in order when `model` fails
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub models: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider: Option<crate::openrouter::ProviderPreferences>,
+    /// OpenRouter prompt transforms, e.g. `middle-out`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transforms: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct OpenAIUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -78,6 +130,8 @@ pub struct OpenAIResponse {
     pub id: String,
     pub choices: Vec<OpenAIChoice>,
     pub model: String,
+    #[serde(default)]
+    pub usage: Option<OpenAIUsage>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -87,16 +141,53 @@ pub struct OpenAIChoice {
     pub finish_reason: String,
 }
 
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct OpenAIFunctionDelta {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub arguments: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OpenAIToolCallDelta {
+    pub index: u32,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub function: Option<OpenAIFunctionDelta>,
+}
+
+/// Incremental message content; unlike `OpenAIMessage` every field is optional
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct OpenAIDelta {
+    #[serde(default)]
+    pub role: Option<String>,
+    #[serde(default)]
+    pub content: Option<String>,
+    #[serde(default)]
+    pub tool_calls: Option<Vec<OpenAIToolCallDelta>>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OpenAIStreamChoice {
     pub index: u32,
-    pub delta: OpenAIMessage,
+    #[serde(default)]
+    pub delta: OpenAIDelta,
     pub finish_reason: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct OpenAIStreamResponse {
+    #[serde(default)]
     pub id: String,
+    #[serde(default)]
     pub choices: Vec<OpenAIStreamChoice>,
+    #[serde(default)]
     pub model: String,
+    #[serde(default)]
+    pub usage: Option<OpenAIUsage>,
+    /// Set by OpenRouter when the upstream fails after the stream started
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<serde_json::Value>,
 }
@@ -0,0 +1,426 @@
+use crate::metrics::METRICS;
+use axum::body::Bytes;
+use futures_util::stream::{BoxStream, StreamExt};
+use serde_json::{Value, json};
+use std::collections::HashMap;
+
+/// The text of OpenAI message content, a string or a list of parts.
+fn text(content: &Value) -> String {
+    match content {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts
+            .iter()
+            .filter_map(|part| part["text"].as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
+
+/// Base64 data of the inline images in OpenAI message content; Ollama cannot fetch URLs.
+fn images(content: &Value) -> Vec<String> {
+    let mut images = Vec::new();
+    for part in content.as_array().into_iter().flatten() {
+        if part["type"] != "image_url" {
+            continue;
+        }
+        let url = part["image_url"]["url"].as_str().unwrap_or_default();
+        match url.split_once(";base64,") {
+            Some((_, data)) => images.push(data.to_string()),
+            None => METRICS.inc(
+                "ccorp_translation_repairs_total",
+                &[("kind", "unsupported_image_url")],
+            ),
+        }
+    }
+    images
+}
+
+/// Convert an OpenAI Chat Completions request into an Ollama `/api/chat` request.
+pub fn chat_request(req: &Value) -> Value {
+    // Ollama matches tool results to calls by function name instead of id
+    let mut tool_names = HashMap::new();
+    let mut messages = Vec::new();
+    for message in req["messages"].as_array().into_iter().flatten() {
+        let mut converted = json!({
+            "role": message["role"],
+            "content": text(&message["content"]),
+        });
+        let images = images(&message["content"]);
+        if !images.is_empty() {
+            converted["images"] = json!(images);
+        }
+        if let Some(calls) = message["tool_calls"].as_array() {
+            let calls: Vec<Value> = calls
+                .iter()
+                .map(|call| {
+                    let function = &call["function"];
+                    tool_names.insert(call["id"].as_str().unwrap_or_default(), &function["name"]);
+                    let arguments = function["arguments"].as_str().unwrap_or("{}");
+                    json!({
+                        "function": {
+                            "name": function["name"],
+                            "arguments": serde_json::from_str::<Value>(arguments).unwrap_or(json!({})),
+                        }
+                    })
+                })
+                .collect();
+            converted["tool_calls"] = json!(calls);
+        }
+        if let Some(name) = message["tool_call_id"]
+            .as_str()
+            .and_then(|id| tool_names.get(id))
+        {
+            converted["tool_name"] = (*name).clone();
+        }
+        messages.push(converted);
+    }
+
+    let mut options = serde_json::Map::new();
+    if let Some(temperature) = req["temperature"].as_f64() {
+        options.insert("temperature".to_string(), json!(temperature));
+    }
+    if let Some(top_p) = req["top_p"].as_f64() {
+        options.insert("top_p".to_string(), json!(top_p));
+    }
+    if let Some(max_tokens) = req["max_completion_tokens"]
+        .as_u64()
+        .or(req["max_tokens"].as_u64())
+    {
+        options.insert("num_predict".to_string(), json!(max_tokens));
+    }
+    match &req["stop"] {
+        Value::String(stop) => {
+            options.insert("stop".to_string(), json!([stop]));
+        }
+        Value::Array(stops) => {
+            options.insert("stop".to_string(), json!(stops));
+        }
+        _ => {}
+    }
+
+    let mut request = json!({
+        "model": req["model"],
+        "messages": messages,
+        // Ollama streams unless told otherwise
+        "stream": req["stream"].as_bool().unwrap_or(false),
+    });
+    if req["tools"].is_array() {
+        request["tools"] = req["tools"].clone();
+    }
+    if !options.is_empty() {
+        request["options"] = Value::Object(options);
+    }
+    request
+}
+
+/// A unique prefix for the ids of one response, as Ollama does not assign any
+fn response_id() -> String {
+    format!(
+        "ollama-{}",
+        chrono::Utc::now().timestamp_nanos_opt().unwrap_or(0)
+    )
+}
+
+fn tool_call(response_id: &str, index: usize, call: &Value) -> Value {
+    let id = call["id"]
+        .as_str()
+        .map_or_else(|| format!("call_{response_id}_{index}"), str::to_string);
+    json!({
+        "index": index,
+        "id": id,
+        "type": "function",
+        "function": {
+            "name": call["function"]["name"],
+            "arguments": call["function"]["arguments"].to_string(),
+        }
+    })
+}
+
+fn finish_reason(response: &Value, called_tools: bool) -> &'static str {
+    if called_tools {
+        "tool_calls"
+    } else if response["done_reason"] == "length" {
+        "length"
+    } else {
+        "stop"
+    }
+}
+
+fn usage(response: &Value) -> Value {
+    let prompt_tokens = response["prompt_eval_count"].as_u64().unwrap_or(0);
+    let completion_tokens = response["eval_count"].as_u64().unwrap_or(0);
+    json!({
+        "prompt_tokens": prompt_tokens,
+        "completion_tokens": completion_tokens,
+        "total_tokens": prompt_tokens + completion_tokens,
+    })
+}
+
+/// An error reported by Ollama, in the shape OpenRouter uses inside successful responses
+fn error(response: &Value) -> Option<Value> {
+    let message = response["error"].as_str()?;
+    Some(json!({ "error": { "message": message } }))
+}
+
+/// Convert a complete Ollama chat response into an OpenAI chat completion.
+pub fn chat_completion(response: &Value) -> Value {
+    if let Some(error) = error(response) {
+        return error;
+    }
+    let id = response_id();
+    let message = &response["message"];
+    let tool_calls: Vec<Value> = message["tool_calls"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .enumerate()
+        .map(|(index, call)| tool_call(&id, index, call))
+        .collect();
+
+    let mut converted = json!({ "role": "assistant", "content": message["content"] });
+    if !tool_calls.is_empty() {
+        converted["tool_calls"] = json!(tool_calls);
+    }
+    json!({
+        "id": format!("chatcmpl-{id}"),
+        "object": "chat.completion",
+        "model": response["model"],
+        "choices": [{
+            "index": 0,
+            "message": converted,
+            "finish_reason": finish_reason(response, !tool_calls.is_empty()),
+        }],
+        "usage": usage(response),
+    })
+}
+
+/// Builds OpenAI `chat.completion.chunk` events from Ollama stream lines
+struct ChunkEncoder {
+    id: String,
+    started: bool,
+    tool_calls: usize,
+}
+
+impl ChunkEncoder {
+    fn chunk(&self, model: &Value, delta: Value, finish_reason: Option<&str>) -> String {
+        let chunk = json!({
+            "id": format!("chatcmpl-{}", self.id),
+            "object": "chat.completion.chunk",
+            "model": model,
+            "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+        });
+        format!("data: {chunk}\n\n")
+    }
+
+    /// Translate one line of the stream into zero or more OpenAI SSE chunks
+    fn translate(&mut self, line: &Value) -> String {
+        if let Some(error) = error(line) {
+            return format!("data: {error}\n\n");
+        }
+        let model = &line["model"];
+        let message = &line["message"];
+        let mut chunks = String::new();
+
+        let content = message["content"].as_str().unwrap_or_default();
+        if !content.is_empty() || !self.started {
+            let mut delta = json!({ "content": content });
+            if !self.started {
+                delta["role"] = json!("assistant");
+                self.started = true;
+            }
+            chunks.push_str(&self.chunk(model, delta, None));
+        }
+
+        // Ollama sends each tool call complete, in a single line
+        let calls: Vec<Value> = message["tool_calls"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|call| {
+                self.tool_calls += 1;
+                tool_call(&self.id, self.tool_calls - 1, call)
+            })
+            .collect();
+        if !calls.is_empty() {
+            chunks.push_str(&self.chunk(model, json!({ "tool_calls": calls }), None));
+        }
+
+        if line["done"] == true {
+            let finish_reason = finish_reason(line, self.tool_calls > 0);
+            chunks.push_str(&self.chunk(model, json!({}), Some(finish_reason)));
+            let usage = json!({
+                "id": format!("chatcmpl-{}", self.id),
+                "object": "chat.completion.chunk",
+                "model": model,
+                "choices": [],
+                "usage": usage(line),
+            });
+            chunks.push_str(&format!("data: {usage}\n\ndata: [DONE]\n\n"));
+        }
+        chunks
+    }
+}
+
+/// Re-encode an Ollama NDJSON stream as an OpenAI chat completions event stream.
+pub fn chunk_stream(
+    mut body: BoxStream<'static, std::io::Result<Bytes>>,
+) -> BoxStream<'static, std::io::Result<Bytes>> {
+    async_stream::stream! {
+        let mut encoder = ChunkEncoder { id: response_id(), started: false, tool_calls: 0 };
+        let mut buffer = Vec::new();
+        while let Some(chunk) = body.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            };
+            buffer.extend_from_slice(&chunk);
+            let mut events = String::new();
+            while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                match serde_json::from_slice::<Value>(&line) {
+                    Ok(line) => events.push_str(&encoder.translate(&line)),
+                    Err(_) if line.trim_ascii().is_empty() => {}
+                    Err(_) => METRICS.inc(
+                        "ccorp_translation_repairs_total",
+                        &[("kind", "unparseable_stream_chunk")],
+                    ),
+                }
+            }
+            if !events.is_empty() {
+                yield Ok(Bytes::from(events));
+            }
+        }
+        if let Ok(line) = serde_json::from_slice::<Value>(&buffer) {
+            yield Ok(Bytes::from(encoder.translate(&line)));
+        }
+    }
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sse::SseParser;
+
+    #[test]
+    fn translates_request() {
+        let request = chat_request(&json!({
+            "model": "qwen3:8b",
+            "max_tokens": 512,
+            "temperature": 0.2,
+            "stop": "END",
+            "stream": true,
+            "tools": [{ "type": "function", "function": { "name": "read", "parameters": {} } }],
+            "messages": [
+                { "role": "user", "content": [
+                    { "type": "text", "text": "look" },
+                    { "type": "image_url", "image_url": { "url": "data:image/png;base64,AAAA" } }
+                ]},
+                { "role": "assistant", "tool_calls": [
+                    { "id": "t1", "type": "function", "function": { "name": "read", "arguments": "{\"path\":\"a\"}" } }
+                ]},
+                { "role": "tool", "tool_call_id": "t1", "content": "ok" }
+            ]
+        }));
+
+        assert_eq!(
+            request["options"],
+            json!({ "temperature": 0.2, "num_predict": 512, "stop": ["END"] })
+        );
+        assert_eq!(request["stream"], true);
+        assert_eq!(request["tools"][0]["function"]["name"], "read");
+        assert_eq!(request["messages"][0]["images"], json!(["AAAA"]));
+        assert_eq!(
+            request["messages"][1]["tool_calls"][0]["function"]["arguments"],
+            json!({ "path": "a" })
+        );
+        assert_eq!(request["messages"][2]["tool_name"], "read");
+    }
+
+    #[test]
+    fn translates_complete_response() {
+        let completion = chat_completion(&json!({
+            "model": "qwen3:8b",
+            "message": { "role": "assistant", "content": "", "tool_calls": [
+                { "function": { "name": "read", "arguments": { "path": "a" } } }
+            ]},
+            "done": true,
+            "done_reason": "stop",
+            "prompt_eval_count": 12,
+            "eval_count": 4
+        }));
+        let choice = &completion["choices"][0];
+        assert_eq!(choice["finish_reason"], "tool_calls");
+        assert_eq!(
+            choice["message"]["tool_calls"][0]["function"]["arguments"],
+            "{\"path\":\"a\"}"
+        );
+        assert_eq!(completion["usage"]["completion_tokens"], 4);
+    }
+
+    /// Stream NDJSON from a local mock server, split at arbitrary points, through the
+    /// translation.
+    #[tokio::test]
+    async fn translates_stream_from_server() {
+        let lines = [
+            r#"{"model":"qwen3:8b","message":{"role":"assistant","content":"Hel"},"done":false}"#,
+            r#"{"model":"qwen3:8b","message":{"role":"assistant","content":"lo"},"done":false}"#,
+            r#"{"model":"qwen3:8b","message":{"role":"assistant","content":"","tool_calls":[{"function":{"name":"read","arguments":{"path":"a"}}}]},"done":false}"#,
+            r#"{"model":"qwen3:8b","message":{"role":"assistant","content":""},"done":true,"done_reason":"stop","prompt_eval_count":12,"eval_count":4}"#,
+        ];
+        let ndjson = lines.join("\n") + "\n";
+        let app = axum::Router::new().route(
+            "/api/chat",
+            axum::routing::post(move || {
+                let (head, tail) = ndjson.split_at(50);
+                let parts = [
+                    Ok::<_, std::io::Error>(head.to_string()),
+                    Ok(tail.to_string()),
+                ];
+                async move { axum::body::Body::from_stream(futures_util::stream::iter(parts)) }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let response = reqwest::Client::new()
+            .post(format!("http://{addr}/api/chat"))
+            .send()
+            .await
+            .unwrap();
+        let mut stream = chunk_stream(
+            response
+                .bytes_stream()
+                .map(|chunk| chunk.map_err(std::io::Error::other))
+                .boxed(),
+        );
+        let mut parser = SseParser::default();
+        let mut events = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            events.extend(parser.push(&chunk.unwrap()));
+        }
+
+        let data: Vec<Value> = events
+            .iter()
+            .filter_map(|event| serde_json::from_str(&event.data).ok())
+            .collect();
+        let text: String = data
+            .iter()
+            .filter_map(|chunk| chunk["choices"][0]["delta"]["content"].as_str())
+            .collect();
+        assert_eq!(text, "Hello");
+        assert_eq!(data[0]["choices"][0]["delta"]["role"], "assistant");
+        assert_eq!(
+            data[2]["choices"][0]["delta"]["tool_calls"][0]["function"]["name"],
+            "read"
+        );
+        assert_eq!(data[3]["choices"][0]["finish_reason"], "tool_calls");
+        assert_eq!(data[4]["usage"]["prompt_tokens"], 12);
+        assert_eq!(events.last().unwrap().data, "[DONE]");
+    }
+}
@@ -1,4 +1,6 @@
+use crate::metrics::METRICS;
 use crate::models::*;
+use crate::sse::anthropic_event;
 use serde_json::json;
 
 pub fn format_openai_to_anthropic(resp: OpenAIResponse) -> AnthropicResponse {
@@ -11,11 +13,19 @@ pub fn format_openai_to_anthropic(resp: OpenAIResponse) -> AnthropicResponse {
 
     if let Some(tool_calls) = &choice.message.tool_calls {
         for tool_call in tool_calls {
+            let input = serde_json::from_str::<serde_json::Value>(&tool_call.function.arguments)
+                .unwrap_or_else(|_| {
+                    METRICS.inc(
+                        "ccorp_translation_repairs_total",
+                        &[("kind", "invalid_tool_arguments")],
+                    );
+                    json!({})
+                });
             content.push(json!({
                 "type": "tool_use",
                 "id": tool_call.id,
                 "name": tool_call.function.name,
-                "input": serde_json::from_str::<serde_json::Value>(&tool_call.function.arguments).unwrap_or(json!({})),
+                "input": input,
             }));
         }
     }
@@ -25,12 +35,600 @@ pub fn format_openai_to_anthropic(resp: OpenAIResponse) -> AnthropicResponse {
         response_type: "message".to_string(),
         role: "assistant".to_string(),
         content,
-        stop_reason: if choice.finish_reason == "tool_calls" {
-            "tool_use".to_string()
-        } else {
-            "end_turn".to_string()
-        },
+        stop_reason: stop_reason_for_finish_reason(&choice.finish_reason).to_string(),
         stop_sequence: None,
         model: resp.model,
+        usage: resp.usage.map(|usage| AnthropicUsage {
+            input_tokens: usage.prompt_tokens,
+            output_tokens: usage.completion_tokens,
+        }),
+    }
+}
+
+/// The Anthropic stop reason for an OpenAI finish reason
+pub fn stop_reason_for_finish_reason(finish_reason: &str) -> &'static str {
+    match finish_reason {
+        "tool_calls" => "tool_use",
+        "length" => "max_tokens",
+        _ => "end_turn",
+    }
+}
+
+/// The content block an encoder is currently writing
+#[derive(Clone, Copy, PartialEq)]
+enum OpenBlock {
+    Text,
+    /// A tool call, by its OpenAI tool call index
+    ToolUse(u32),
+}
+
+/// Builds an Anthropic event stream from OpenAI `chat.completion.chunk` events
+#[derive(Default)]
+pub struct EventEncoder {
+    started: bool,
+    /// Anthropic index and kind of the open content block
+    open_block: Option<(usize, OpenBlock)>,
+    next_index: usize,
+    stop_reason: Option<&'static str>,
+    usage: OpenAIUsage,
+}
+
+impl EventEncoder {
+    fn start_block(&mut self, kind: OpenBlock, block: serde_json::Value) -> Vec<String> {
+        let mut events = self.close_block();
+        let index = self.next_index;
+        self.next_index += 1;
+        self.open_block = Some((index, kind));
+        events.push(anthropic_event(json!({
+            "type": "content_block_start",
+            "index": index,
+            "content_block": block,
+        })));
+        events
+    }
+
+    fn close_block(&mut self) -> Vec<String> {
+        match self.open_block.take() {
+            Some((index, _)) => vec![anthropic_event(
+                json!({ "type": "content_block_stop", "index": index }),
+            )],
+            None => Vec::new(),
+        }
+    }
+
+    fn delta(&self, delta: serde_json::Value) -> String {
+        let (index, _) = self.open_block.expect("a delta belongs to an open block");
+        anthropic_event(json!({ "type": "content_block_delta", "index": index, "delta": delta }))
+    }
+
+    /// Translate one OpenAI chunk into zero or more Anthropic events
+    pub fn translate(&mut self, chunk: &OpenAIStreamResponse) -> Vec<String> {
+        let mut events = Vec::new();
+        if !self.started {
+            self.started = true;
+            events.push(anthropic_event(json!({
+                "type": "message_start",
+                "message": {
+                    "id": chunk.id,
+                    "type": "message",
+                    "role": "assistant",
+                    "model": chunk.model,
+                    "content": [],
+                    "stop_reason": null,
+                    "stop_sequence": null,
+                    "usage": { "input_tokens": 0, "output_tokens": 0 },
+                }
+            })));
+        }
+        if let Some(usage) = &chunk.usage {
+            self.usage = usage.clone();
+        }
+        let Some(choice) = chunk.choices.first() else {
+            return events;
+        };
+
+        if let Some(text) = choice.delta.content.as_deref().filter(|t| !t.is_empty()) {
+            if self
+                .open_block
+                .is_none_or(|(_, kind)| kind != OpenBlock::Text)
+            {
+                events.extend(
+                    self.start_block(OpenBlock::Text, json!({ "type": "text", "text": "" })),
+                );
+            }
+            events.push(self.delta(json!({ "type": "text_delta", "text": text })));
+        }
+
+        for call in choice.delta.tool_calls.iter().flatten() {
+            // Some providers repeat the id on every delta, so only the index tells calls apart
+            let kind = OpenBlock::ToolUse(call.index);
+            let function = call.function.as_ref();
+            if self.open_block.is_none_or(|(_, open)| open != kind) {
+                events.extend(self.start_block(
+                    kind,
+                    json!({
+                        "type": "tool_use",
+                        "id": call.id.clone().unwrap_or_default(),
+                        "name": function.and_then(|f| f.name.clone()).unwrap_or_default(),
+                        "input": {},
+                    }),
+                ));
+            }
+            if let Some(arguments) = function
+                .and_then(|f| f.arguments.as_deref())
+                .filter(|a| !a.is_empty())
+            {
+                events.push(
+                    self.delta(json!({ "type": "input_json_delta", "partial_json": arguments })),
+                );
+            }
+        }
+
+        if let Some(finish_reason) = &choice.finish_reason {
+            self.stop_reason = Some(stop_reason_for_finish_reason(finish_reason));
+        }
+        events
+    }
+
+    /// Close the message once the upstream stream has ended
+    pub fn finish(&mut self) -> Vec<String> {
+        let mut events = self.close_block();
+        if self.started {
+            events.push(anthropic_event(json!({
+                "type": "message_delta",
+                "delta": {
+                    "stop_reason": self.stop_reason.unwrap_or("end_turn"),
+                    "stop_sequence": null,
+                },
+                "usage": {
+                    "input_tokens": self.usage.prompt_tokens,
+                    "output_tokens": self.usage.completion_tokens,
+                },
+            })));
+        }
+        events.push(anthropic_event(json!({ "type": "message_stop" })));
+        events
+    }
+}
+
+/// The Anthropic error type corresponding to an upstream HTTP status code
+pub fn error_type_for_status(status: u16) -> &'static str {
+    match status {
+        400 => "invalid_request_error",
+        401 => "authentication_error",
+        403 => "permission_error",
+        404 => "not_found_error",
+        413 => "request_too_large",
+        429 => "rate_limit_error",
+        503 | 529 => "overloaded_error",
+        _ => "api_error",
+    }
+}
+
+/// Default `max_tokens` for OpenAI requests that do not set one, as Anthropic requires it
+const DEFAULT_MAX_TOKENS: u32 = 4096;
+
+/// Convert an OpenAI `image_url` content part into an Anthropic image block
+fn image_block(image_url: &str) -> serde_json::Value {
+    if let Some((media_type, data)) = image_url
+        .strip_prefix("data:")
+        .and_then(|rest| rest.split_once(";base64,"))
+    {
+        json!({
+            "type": "image",
+            "source": { "type": "base64", "media_type": media_type, "data": data }
+        })
+    } else {
+        json!({
+            "type": "image",
+            "source": { "type": "url", "url": image_url }
+        })
+    }
+}
+
+/// Convert OpenAI message content (a string or an array of parts) into Anthropic content blocks
+fn content_blocks(content: &serde_json::Value) -> Vec<serde_json::Value> {
+    let mut blocks = Vec::new();
+    if let Some(text) = content.as_str() {
+        if !text.is_empty() {
+            blocks.push(json!({ "type": "text", "text": text }));
+        }
+    } else if let Some(parts) = content.as_array() {
+        for part in parts {
+            match part["type"].as_str() {
+                Some("text") => {
+                    blocks.push(json!({ "type": "text", "text": part["text"] }));
+                }
+                Some("image_url") => {
+                    let url = part["image_url"]["url"]
+                        .as_str()
+                        .or(part["image_url"].as_str())
+                        .unwrap_or("");
+                    blocks.push(image_block(url));
+                }
+                _ => {}
+            }
+        }
+    }
+    blocks
+}
+
+/// Append content to the conversation, merging consecutive messages of the same role
+fn push_message(messages: &mut Vec<AnthropicMessage>, role: &str, blocks: Vec<serde_json::Value>) {
+    if blocks.is_empty() {
+        return;
+    }
+    if let Some(last) = messages.last_mut()
+        && last.role == role
+        && let Some(content) = last.content.as_array_mut()
+    {
+        content.extend(blocks);
+        return;
+    }
+    messages.push(AnthropicMessage {
+        role: role.to_string(),
+        content: serde_json::Value::Array(blocks),
+    });
+}
+
+/// Convert an OpenAI Chat Completions request into an Anthropic Messages request.
+pub fn format_openai_request_to_anthropic(req: &serde_json::Value) -> AnthropicRequest {
+    let mut system = Vec::new();
+    let mut messages = Vec::new();
+
+    for message in req["messages"].as_array().into_iter().flatten() {
+        match message["role"].as_str().unwrap_or("") {
+            "system" | "developer" => {
+                for block in content_blocks(&message["content"]) {
+                    if let Some(text) = block["text"].as_str() {
+                        system.push(text.to_string());
+                    }
+                }
+            }
+            "user" => push_message(&mut messages, "user", content_blocks(&message["content"])),
+            "assistant" => {
+                let mut blocks = content_blocks(&message["content"]);
+                for tool_call in message["tool_calls"].as_array().into_iter().flatten() {
+                    let arguments = tool_call["function"]["arguments"].as_str().unwrap_or("{}");
+                    blocks.push(json!({
+                        "type": "tool_use",
+                        "id": tool_call["id"],
+                        "name": tool_call["function"]["name"],
+                        "input": serde_json::from_str::<serde_json::Value>(arguments).unwrap_or(json!({})),
+                    }));
+                }
+                push_message(&mut messages, "assistant", blocks);
+            }
+            "tool" => {
+                let content = match &message["content"] {
+                    serde_json::Value::String(text) => json!(text),
+                    other => json!(content_blocks(other)),
+                };
+                push_message(
+                    &mut messages,
+                    "user",
+                    vec![json!({
+                        "type": "tool_result",
+                        "tool_use_id": message["tool_call_id"],
+                        "content": content,
+                    })],
+                );
+            }
+            _ => {}
+        }
+    }
+
+    let tools = req["tools"].as_array().map(|tools| {
+        tools
+            .iter()
+            .map(|t| {
+                let mut tool = json!({
+                    "name": t["function"]["name"],
+                    "input_schema": t["function"]["parameters"],
+                });
+                if t["function"]["description"].is_string() {
+                    tool["description"] = t["function"]["description"].clone();
+                }
+                tool
+            })
+            .collect()
+    });
+
+    let tool_choice = match &req["tool_choice"] {
+        serde_json::Value::String(choice) => match choice.as_str() {
+            "required" => Some(json!({ "type": "any" })),
+            "none" => Some(json!({ "type": "none" })),
+            _ => Some(json!({ "type": "auto" })),
+        },
+        serde_json::Value::Object(_) => Some(json!({
+            "type": "tool",
+            "name": req["tool_choice"]["function"]["name"],
+        })),
+        _ => None,
+    };
+
+    let stop_sequences = match &req["stop"] {
+        serde_json::Value::String(stop) => Some(vec![stop.clone()]),
+        serde_json::Value::Array(stops) => Some(
+            stops
+                .iter()
+                .filter_map(|s| s.as_str().map(str::to_string))
+                .collect(),
+        ),
+        _ => None,
+    };
+
+    AnthropicRequest {
+        model: req["model"].as_str().unwrap_or("").to_string(),
+        messages,
+        system: (!system.is_empty()).then(|| json!(system.join("\n\n"))),
+        temperature: req["temperature"].as_f64(),
+        tools,
+        stream: req["stream"].as_bool(),
+        max_tokens: Some(
+            req["max_completion_tokens"]
+                .as_u64()
+                .or(req["max_tokens"].as_u64())
+                .map(|n| n as u32)
+                .unwrap_or(DEFAULT_MAX_TOKENS),
+        ),
+        stop_sequences,
+        top_p: req["top_p"].as_f64(),
+        tool_choice,
+        metadata: req["user"].as_str().map(|user| json!({ "user_id": user })),
+        thinking: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(delta: serde_json::Value, finish_reason: Option<&str>) -> OpenAIStreamResponse {
+        serde_json::from_value(json!({
+            "id": "c1",
+            "model": "a/b",
+            "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn encodes_text_and_tool_calls_as_blocks() {
+        let mut encoder = EventEncoder::default();
+        let mut events = Vec::new();
+        for chunk in [
+            chunk(json!({ "role": "assistant", "content": "" }), None),
+            chunk(json!({ "content": "Hi" }), None),
+            chunk(
+                json!({ "tool_calls": [{ "index": 0, "id": "t1", "function": { "name": "read", "arguments": "{\"a\":" } }] }),
+                None,
+            ),
+            chunk(
+                json!({ "tool_calls": [{ "index": 0, "function": { "arguments": "1}" } }] }),
+                Some("tool_calls"),
+            ),
+        ] {
+            events.extend(encoder.translate(&chunk));
+        }
+        events.extend(encoder.finish());
+
+        let types: Vec<serde_json::Value> = events
+            .iter()
+            .map(|event| {
+                let data = event.split_once("data: ").unwrap().1;
+                serde_json::from_str::<serde_json::Value>(data).unwrap()["type"].clone()
+            })
+            .collect();
+        assert_eq!(
+            types,
+            json!([
+                "message_start",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_stop",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_delta",
+                "content_block_stop",
+                "message_delta",
+                "message_stop"
+            ])
+            .as_array()
+            .unwrap()
+            .clone()
+        );
+        assert!(events[4].contains(r#""name":"read""#));
+        assert!(events[8].contains(r#""stop_reason":"tool_use""#));
+    }
+
+    /// The data of every event, for checking whole streams
+    fn event_data(events: &[String]) -> Vec<serde_json::Value> {
+        events
+            .iter()
+            .map(|event| serde_json::from_str(event.split_once("data: ").unwrap().1).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn keeps_tool_calls_with_repeated_ids_in_one_block() {
+        let mut encoder = EventEncoder::default();
+        let mut events = Vec::new();
+        for arguments in ["{\"path\":", "\"a\"}"] {
+            events.extend(encoder.translate(&chunk(
+                json!({ "tool_calls": [{ "index": 0, "id": "t1", "function": { "name": "read", "arguments": arguments } }] }),
+                None,
+            )));
+        }
+        events.extend(encoder.finish());
+
+        let data = event_data(&events);
+        let starts: Vec<_> = data
+            .iter()
+            .filter(|event| event["type"] == "content_block_start")
+            .collect();
+        assert_eq!(starts.len(), 1);
+        assert_eq!(starts[0]["content_block"]["id"], "t1");
+        let arguments: String = data
+            .iter()
+            .filter_map(|event| event["delta"]["partial_json"].as_str())
+            .collect();
+        assert_eq!(arguments, r#"{"path":"a"}"#);
+    }
+
+    #[test]
+    fn opens_a_block_per_parallel_tool_call() {
+        let mut encoder = EventEncoder::default();
+        let mut events = Vec::new();
+        for chunk in [
+            chunk(
+                json!({ "tool_calls": [{ "index": 0, "id": "t1", "function": { "name": "read", "arguments": "{}" } }] }),
+                None,
+            ),
+            chunk(
+                json!({ "tool_calls": [{ "index": 1, "id": "t2", "function": { "name": "grep", "arguments": "" } }] }),
+                None,
+            ),
+            chunk(
+                json!({ "tool_calls": [{ "index": 1, "function": { "arguments": "{\"q\":1}" } }] }),
+                Some("tool_calls"),
+            ),
+        ] {
+            events.extend(encoder.translate(&chunk));
+        }
+        let mut last = chunk(json!({}), None);
+        last.choices.clear();
+        last.usage = Some(OpenAIUsage {
+            prompt_tokens: 12,
+            completion_tokens: 7,
+        });
+        events.extend(encoder.translate(&last));
+        events.extend(encoder.finish());
+
+        let data = event_data(&events);
+        let blocks: Vec<(u64, &str)> = data
+            .iter()
+            .filter(|event| event["type"] == "content_block_start")
+            .map(|event| {
+                (
+                    event["index"].as_u64().unwrap(),
+                    event["content_block"]["name"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(blocks, [(0, "read"), (1, "grep")]);
+        let grep_arguments: Vec<_> = data
+            .iter()
+            .filter(|event| event["index"] == 1)
+            .filter_map(|event| event["delta"]["partial_json"].as_str())
+            .collect();
+        assert_eq!(grep_arguments, [r#"{"q":1}"#]);
+        let message_delta = &data[data.len() - 2];
+        assert_eq!(message_delta["delta"]["stop_reason"], "tool_use");
+        assert_eq!(message_delta["usage"]["input_tokens"], 12);
+        assert_eq!(message_delta["usage"]["output_tokens"], 7);
+    }
+
+    #[test]
+    fn translates_chat_requests() {
+        let request = format_openai_request_to_anthropic(&json!({
+            "model": "claude-sonnet-4",
+            "max_completion_tokens": 256,
+            "stop": "END",
+            "user": "u1",
+            "messages": [
+                { "role": "system", "content": "Be brief." },
+                { "role": "developer", "content": [{ "type": "text", "text": "Use tools." }] },
+                { "role": "user", "content": [
+                    { "type": "text", "text": "What is this?" },
+                    { "type": "image_url", "image_url": { "url": "data:image/png;base64,AAAA" } },
+                    { "type": "image_url", "image_url": "https://example.com/a.png" },
+                ] },
+                { "role": "assistant", "content": null, "tool_calls": [{
+                    "id": "t1",
+                    "type": "function",
+                    "function": { "name": "look", "arguments": "{\"zoom\":2}" },
+                }] },
+                { "role": "tool", "tool_call_id": "t1", "content": "a cat" },
+                { "role": "user", "content": "Thanks" },
+            ],
+            "tools": [
+                { "type": "function", "function": {
+                    "name": "look",
+                    "description": "Inspect the image",
+                    "parameters": { "type": "object" },
+                } },
+                { "type": "function", "function": { "name": "other", "parameters": {} } },
+            ],
+            "tool_choice": { "type": "function", "function": { "name": "look" } },
+        }));
+
+        assert_eq!(request.model, "claude-sonnet-4");
+        assert_eq!(request.max_tokens, Some(256));
+        assert_eq!(request.stop_sequences, Some(vec!["END".to_string()]));
+        assert_eq!(request.metadata, Some(json!({ "user_id": "u1" })));
+        assert_eq!(request.system, Some(json!("Be brief.\n\nUse tools.")));
+
+        let messages = serde_json::to_value(&request.messages).unwrap();
+        assert_eq!(
+            messages,
+            json!([
+                { "role": "user", "content": [
+                    { "type": "text", "text": "What is this?" },
+                    { "type": "image", "source": { "type": "base64", "media_type": "image/png", "data": "AAAA" } },
+                    { "type": "image", "source": { "type": "url", "url": "https://example.com/a.png" } },
+                ] },
+                { "role": "assistant", "content": [
+                    { "type": "tool_use", "id": "t1", "name": "look", "input": { "zoom": 2 } },
+                ] },
+                // Tool results and the following user turn become one user message
+                { "role": "user", "content": [
+                    { "type": "tool_result", "tool_use_id": "t1", "content": "a cat" },
+                    { "type": "text", "text": "Thanks" },
+                ] },
+            ])
+        );
+
+        assert_eq!(
+            request.tools,
+            Some(vec![
+                json!({ "name": "look", "description": "Inspect the image", "input_schema": { "type": "object" } }),
+                json!({ "name": "other", "input_schema": {} }),
+            ])
+        );
+        assert_eq!(
+            request.tool_choice,
+            Some(json!({ "type": "tool", "name": "look" }))
+        );
+    }
+
+    #[test]
+    fn translates_tool_choices_and_defaults() {
+        let tool_choice = |choice: serde_json::Value| {
+            format_openai_request_to_anthropic(&json!({ "messages": [], "tool_choice": choice }))
+                .tool_choice
+        };
+        assert_eq!(
+            tool_choice(json!("required")),
+            Some(json!({ "type": "any" }))
+        );
+        assert_eq!(tool_choice(json!("none")), Some(json!({ "type": "none" })));
+        assert_eq!(tool_choice(json!("auto")), Some(json!({ "type": "auto" })));
+        assert_eq!(tool_choice(json!(null)), None);
+
+        let request = format_openai_request_to_anthropic(&json!({
+            "messages": [{ "role": "user", "content": "hi" }],
+            "max_tokens": 100,
+            "stop": ["a", "b"],
+        }));
+        assert!(request.system.is_none());
+        assert!(request.tools.is_none());
+        assert_eq!(request.max_tokens, Some(100));
+        assert_eq!(
+            request.stop_sequences,
+            Some(vec!["a".to_string(), "b".to_string()])
+        );
+        let request = format_openai_request_to_anthropic(&json!({ "messages": [] }));
+        assert_eq!(request.max_tokens, Some(DEFAULT_MAX_TOKENS));
     }
 }
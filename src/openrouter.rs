@@ -1,5 +1,15 @@
 use crate::config::Config;
 use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, RwLock};
+
+/// How long a fetched model list is reused before asking OpenRouter again
+const MODEL_CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// How long a failed fetch is reported before OpenRouter is asked again, so an
+/// unreachable OpenRouter does not delay every request
+const MODEL_FETCH_BACKOFF: Duration = Duration::from_secs(15);
 
 /// Response structure for the OpenRouter models list API
 #[derive(Debug, Deserialize, Serialize)]
@@ -19,6 +29,9 @@ pub struct Model {
     pub supported_generation_methods: Option<Vec<String>>,
     pub top_provider: Option<TopProvider>,
     pub per_request_limits: Option<PerRequestLimits>,
+    /// Request parameters the model accepts (e.g., `tools`, `max_tokens`)
+    #[serde(default)]
+    pub supported_parameters: Option<Vec<String>>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -49,19 +62,232 @@ pub struct PerRequestLimits {
     pub completion_tokens: Option<String>,
 }
 
+/// OpenRouter provider routing preferences, sent as the `provider` request field
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct ProviderPreferences {
+    /// Providers to try first, in order
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub order: Vec<String>,
+    /// Whether OpenRouter may use providers outside `order`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allow_fallbacks: Option<bool>,
+    /// `deny` restricts requests to providers that do not store or train on prompts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data_collection: Option<DataCollection>,
+    /// Accepted quantization levels (e.g., `fp8`, `bf16`)
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub quantizations: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sort: Option<ProviderSort>,
+    /// Providers never to use
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ignore: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DataCollection {
+    Allow,
+    Deny,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderSort {
+    Price,
+    Throughput,
+    Latency,
+}
+
+impl ProviderPreferences {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// These preferences with every field set in `overrides` replaced.
+    pub fn overridden_by(&self, overrides: &ProviderPreferences) -> ProviderPreferences {
+        let list = |own: &Vec<String>, other: &Vec<String>| {
+            if other.is_empty() { own } else { other }.clone()
+        };
+        ProviderPreferences {
+            order: list(&self.order, &overrides.order),
+            allow_fallbacks: overrides.allow_fallbacks.or(self.allow_fallbacks),
+            data_collection: overrides.data_collection.or(self.data_collection),
+            quantizations: list(&self.quantizations, &overrides.quantizations),
+            sort: overrides.sort.or(self.sort),
+            ignore: list(&self.ignore, &overrides.ignore),
+        }
+    }
+}
+
+/// The configuration needed to fetch the model list, so it can be fetched without
+/// holding the configuration lock
+#[derive(Clone, Debug)]
+pub struct ModelSource {
+    base_url: String,
+    api_key: String,
+    timeout: Option<Duration>,
+}
+
+impl ModelSource {
+    pub fn new(config: &Config) -> Self {
+        ModelSource {
+            base_url: config.base_url.clone(),
+            api_key: config.api_key.clone(),
+            timeout: config.http.request_timeout(),
+        }
+    }
+}
+
 /// Fetch the list of available models from OpenRouter
-pub async fn fetch_models(config: &Config) -> Result<ModelsResponse, reqwest::Error> {
-    let client = reqwest::Client::new();
-    let url = format!("{}/models", config.base_url);
+pub async fn fetch_models(
+    client: &reqwest::Client,
+    source: &ModelSource,
+) -> Result<ModelsResponse, reqwest::Error> {
+    let url = format!("{}/models", source.base_url);
 
-    let response = client
-        .get(&url)
-        .header("Authorization", format!("Bearer {}", config.api_key))
+    let mut request = client.get(&url);
+    if let Some(timeout) = source.timeout {
+        request = request.timeout(timeout);
+    }
+    let response = request
+        .header("Authorization", format!("Bearer {}", source.api_key))
         .header("HTTP-Referer", "https://github.com/yourusername/ccor")
         .header("X-Title", "CCOR - Claude Connector for OpenRouter")
         .send()
-        .await?;
+        .await?
+        .error_for_status()?;
 
     let models = response.json::<ModelsResponse>().await?;
     Ok(models)
 }
+
+/// Check that the configured API key is accepted by OpenRouter
+pub async fn check_key(client: &reqwest::Client, config: &Config) -> Result<(), String> {
+    let mut request = client.get(format!("{}/key", config.base_url));
+    if let Some(timeout) = config.http.request_timeout() {
+        request = request.timeout(timeout);
+    }
+    let response = request
+        .bearer_auth(&config.api_key)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!(
+            "OpenRouter rejected the API key ({})",
+            response.status()
+        ))
+    }
+}
+
+/// The outcome of the last model list fetch
+type Fetched = Result<Arc<Vec<Model>>, String>;
+
+/// In-memory cache of the OpenRouter model list, shared through `AppState`
+#[derive(Default)]
+pub struct ModelCache {
+    entry: RwLock<Option<(Instant, Fetched)>>,
+    /// Held while fetching, so concurrent requests share one fetch
+    fetching: Mutex<()>,
+}
+
+impl ModelCache {
+    /// Return the cached models, fetching them again once the cache has expired.
+    /// Failures are remembered for a short while instead of being retried right away.
+    pub async fn get(&self, client: &reqwest::Client, source: &ModelSource) -> Fetched {
+        if let Some(fetched) = self.current().await {
+            return fetched;
+        }
+        let _fetching = self.fetching.lock().await;
+        // Another request may have fetched the list while this one waited
+        if let Some(fetched) = self.current().await {
+            return fetched;
+        }
+
+        let fetched = fetch_models(client, source)
+            .await
+            .map(|models| Arc::new(models.data))
+            .map_err(|e| e.to_string());
+        *self.entry.write().await = Some((Instant::now(), fetched.clone()));
+        fetched
+    }
+
+    /// The last fetch's outcome while it is still valid
+    async fn current(&self) -> Option<Fetched> {
+        let entry = self.entry.read().await;
+        let (fetched_at, fetched) = entry.as_ref()?;
+        let valid_for = match fetched {
+            Ok(_) => MODEL_CACHE_TTL,
+            Err(_) => MODEL_FETCH_BACKOFF,
+        };
+        (fetched_at.elapsed() < valid_for).then(|| fetched.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn shares_fetches_and_remembers_failures() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let available = Arc::new(AtomicBool::new(false));
+        let app = axum::Router::new().route(
+            "/models",
+            axum::routing::get({
+                let requests = requests.clone();
+                let available = available.clone();
+                move || {
+                    requests.fetch_add(1, Ordering::SeqCst);
+                    let available = available.load(Ordering::SeqCst);
+                    async move {
+                        tokio::time::sleep(Duration::from_millis(20)).await;
+                        if !available {
+                            return Err(StatusCode::SERVICE_UNAVAILABLE);
+                        }
+                        Ok(axum::Json(serde_json::json!({
+                            "data": [{ "id": "a/b", "name": "B" }],
+                        })))
+                    }
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let client = reqwest::Client::new();
+        let source = ModelSource::new(&Config {
+            base_url: format!("http://{addr}"),
+            ..Config::default()
+        });
+        let cache = ModelCache::default();
+
+        let results =
+            futures_util::future::join_all((0..5).map(|_| cache.get(&client, &source))).await;
+        assert!(results.iter().all(Result::is_err));
+        assert!(cache.get(&client, &source).await.is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // Once the failure has expired the list is fetched again, once for all requests
+        available.store(true, Ordering::SeqCst);
+        cache.entry.write().await.as_mut().unwrap().0 -= MODEL_FETCH_BACKOFF;
+        let results =
+            futures_util::future::join_all((0..5).map(|_| cache.get(&client, &source))).await;
+        let first = results[0].as_ref().unwrap();
+        assert_eq!(first[0].id, "a/b");
+        assert!(
+            results
+                .iter()
+                .all(|models| Arc::ptr_eq(models.as_ref().unwrap(), first))
+        );
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+}
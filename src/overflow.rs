@@ -0,0 +1,384 @@
+use crate::AppState;
+use crate::metrics::METRICS;
+use crate::models::{OpenAIContent, OpenAIMessage, OpenAIRequest};
+use crate::openrouter::Model;
+use crate::routing::Route;
+use crate::upstream::{self, Payload, UpstreamBody};
+use serde::{Deserialize, Serialize};
+
+/// How a translated prompt that does not fit the model's context window is shortened
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowStrategy {
+    /// Let OpenRouter drop messages from the middle of the prompt
+    MiddleOut,
+    /// Replace the content of the oldest tool results
+    TruncateToolResults,
+    /// Replace the oldest turns with a summary written by `summary_model`
+    Summarize,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct OverflowConfig {
+    /// Without a strategy, prompts that are too long are rejected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strategy: Option<OverflowStrategy>,
+    /// Cheap model used by the `summarize` strategy
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summary_model: Option<String>,
+    /// Number of most recent messages that are never shortened
+    pub keep_recent: usize,
+}
+
+impl Default for OverflowConfig {
+    fn default() -> Self {
+        OverflowConfig {
+            strategy: None,
+            summary_model: None,
+            keep_recent: 6,
+        }
+    }
+}
+
+impl OverflowConfig {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+const TRUNCATED_TOOL_RESULT: &str = "[Tool result removed to fit the context window]";
+
+const SUMMARY_PROMPT: &str = "Summarize the following conversation between a user and a coding \
+assistant. Keep every decision, file name, command and open task needed to continue the work. \
+Answer with the summary only.";
+
+/// Estimated prompt tokens of a message, at four characters per token.
+fn message_tokens(message: &OpenAIMessage) -> u64 {
+    serde_json::to_string(message)
+        .map_or(0, |json| json.len())
+        .div_ceil(4) as u64
+}
+
+fn prompt_tokens(messages: &[OpenAIMessage]) -> u64 {
+    messages.iter().map(message_tokens).sum()
+}
+
+/// Index of the first message after the leading system messages.
+fn conversation_start(messages: &[OpenAIMessage]) -> usize {
+    messages
+        .iter()
+        .position(|m| m.role != "system")
+        .unwrap_or(messages.len())
+}
+
+/// Blank the oldest tool results until the prompt fits `budget`. Messages are kept, so
+/// every tool call still has its result.
+pub fn truncate_tool_results(messages: &mut [OpenAIMessage], budget: u64, keep_recent: usize) {
+    let mut tokens = prompt_tokens(messages);
+    let end = messages.len().saturating_sub(keep_recent);
+    for message in &mut messages[..end] {
+        if tokens <= budget {
+            break;
+        }
+        if message.role != "tool" {
+            continue;
+        }
+        let before = message_tokens(message);
+        message.content = Some(TRUNCATED_TOOL_RESULT.to_string().into());
+        tokens -= before.saturating_sub(message_tokens(message));
+    }
+}
+
+/// The end of the oldest turns to summarize so the rest fits `budget`. The cut never
+/// separates tool results from the assistant message that called the tools.
+pub fn summary_cut(messages: &[OpenAIMessage], budget: u64, keep_recent: usize) -> Option<usize> {
+    let start = conversation_start(messages);
+    let end = messages.len().saturating_sub(keep_recent);
+    let mut remaining = prompt_tokens(messages);
+    let mut cut_at = None;
+    for cut in start + 1..=end {
+        remaining -= message_tokens(&messages[cut - 1]);
+        if messages.get(cut).is_some_and(|m| m.role == "tool") {
+            continue;
+        }
+        cut_at = Some(cut);
+        if remaining <= budget {
+            break;
+        }
+    }
+    cut_at
+}
+
+/// Render messages as plain text for the summary model.
+fn transcript(messages: &[OpenAIMessage]) -> String {
+    let mut text = String::new();
+    for message in messages {
+        match &message.content {
+            Some(OpenAIContent::Text(content)) => {
+                text.push_str(&format!("{}: {content}\n\n", message.role));
+            }
+            Some(OpenAIContent::Parts(parts)) => {
+                for part in parts.iter().filter_map(|p| p["text"].as_str()) {
+                    text.push_str(&format!("{}: {part}\n\n", message.role));
+                }
+            }
+            None => {}
+        }
+        for call in message.tool_calls.iter().flatten() {
+            text.push_str(&format!(
+                "assistant called {}({})\n\n",
+                call.function.name, call.function.arguments
+            ));
+        }
+    }
+    text
+}
+
+async fn summarize(
+    state: &AppState,
+    api_key: &str,
+    model: &str,
+    messages: &[OpenAIMessage],
+) -> Result<String, String> {
+    let route = Route::direct(model, "summary", &*state.config.read().await);
+
+    let request = OpenAIRequest {
+        model: model.to_string(),
+        messages: vec![
+            OpenAIMessage {
+                role: "system".to_string(),
+                content: Some(SUMMARY_PROMPT.to_string().into()),
+                tool_calls: None,
+                tool_call_id: None,
+            },
+            OpenAIMessage {
+                role: "user".to_string(),
+                content: Some(transcript(messages).into()),
+                tool_calls: None,
+                tool_call_id: None,
+            },
+        ],
+        temperature: None,
+        stream: None,
+        tools: None,
+        max_tokens: None,
+        stream_options: None,
+        models: None,
+        provider: (!route.provider.is_default()).then(|| route.provider.clone()),
+        transforms: None,
+    };
+    let response = upstream::send(state, api_key, &route, Payload::chat(request))
+        .await
+        .map_err(|e| e.message)?;
+    let UpstreamBody::Complete(body) = response.body else {
+        unreachable!("non-streaming requests get a complete response")
+    };
+    body["choices"][0]["message"]["content"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| "the summary response has no content".to_string())
+}
+
+/// Apply the overflow strategy to a translated request that does not fit the context
+/// window of `model`, as listed in `catalog`.
+pub async fn fit(
+    state: &AppState,
+    api_key: &str,
+    request: &mut OpenAIRequest,
+    model: &str,
+    overflow: &OverflowConfig,
+    catalog: &[Model],
+) {
+    let Some(strategy) = overflow.strategy else {
+        return;
+    };
+    if strategy == OverflowStrategy::MiddleOut {
+        // OpenRouter only compresses prompts that would not fit
+        request.transforms = Some(vec!["middle-out".to_string()]);
+        return;
+    }
+
+    let Some(context_length) = catalog
+        .iter()
+        .find(|m| m.id == model)
+        .and_then(|m| m.context_length)
+        .and_then(|c| u64::try_from(c).ok())
+    else {
+        return;
+    };
+    // Leave room for the answer
+    let reserve = u64::from(request.max_tokens.unwrap_or(0)).min(context_length / 4);
+    let budget = context_length - reserve;
+    let tokens = prompt_tokens(&request.messages);
+    if tokens <= budget {
+        return;
+    }
+
+    tracing::info!(
+        "Prompt of ~{tokens} tokens exceeds the {budget} token budget of {model}, applying {strategy:?}"
+    );
+    METRICS.inc(
+        "ccorp_request_adaptations_total",
+        &[("kind", "context_overflow")],
+    );
+    request.max_tokens = request
+        .max_tokens
+        .map(|max_tokens| max_tokens.min(reserve as u32));
+    let keep_recent = overflow.keep_recent;
+    if strategy == OverflowStrategy::Summarize {
+        let Some(summary_model) = &overflow.summary_model else {
+            tracing::warn!("The summarize overflow strategy needs a summary_model");
+            return;
+        };
+        if let Some(cut) = summary_cut(&request.messages, budget, keep_recent) {
+            let start = conversation_start(&request.messages);
+            match summarize(state, api_key, summary_model, &request.messages[start..cut]).await {
+                Ok(summary) => {
+                    let message = OpenAIMessage {
+                        role: "user".to_string(),
+                        content: Some(
+                            format!("[Summary of the earlier conversation]\n{summary}").into(),
+                        ),
+                        tool_calls: None,
+                        tool_call_id: None,
+                    };
+                    request.messages.splice(start..cut, [message]);
+                    return;
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        "Summarizing with {summary_model} failed, truncating instead: {e}"
+                    );
+                }
+            }
+        }
+    }
+    truncate_tool_results(&mut request.messages, budget, keep_recent);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{OpenAIFunction, OpenAIToolCall};
+
+    fn message(role: &str, content: &str) -> OpenAIMessage {
+        OpenAIMessage {
+            role: role.to_string(),
+            content: Some(content.to_string().into()),
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+
+    fn tool_call(id: &str) -> OpenAIMessage {
+        OpenAIMessage {
+            role: "assistant".to_string(),
+            content: None,
+            tool_calls: Some(vec![OpenAIToolCall {
+                id: id.to_string(),
+                tool_type: "function".to_string(),
+                function: OpenAIFunction {
+                    name: "read".to_string(),
+                    arguments: "{}".to_string(),
+                },
+            }]),
+            tool_call_id: None,
+        }
+    }
+
+    fn tool_result(id: &str, content: &str) -> OpenAIMessage {
+        OpenAIMessage {
+            tool_call_id: Some(id.to_string()),
+            ..message("tool", content)
+        }
+    }
+
+    fn conversation() -> Vec<OpenAIMessage> {
+        let big = "x".repeat(4000);
+        vec![
+            message("system", "be helpful"),
+            message("user", "start"),
+            tool_call("a"),
+            tool_result("a", &big),
+            tool_call("b"),
+            tool_result("b", &big),
+            message("user", "next"),
+            tool_call("c"),
+            tool_result("c", &big),
+        ]
+    }
+
+    #[test]
+    fn truncation_keeps_recent_messages_and_pairs() {
+        let mut messages = conversation();
+        truncate_tool_results(&mut messages, 1500, 2);
+
+        assert_eq!(messages.len(), 9);
+        let text = |m: &OpenAIMessage| serde_json::to_value(&m.content).unwrap();
+        assert_eq!(text(&messages[3]), TRUNCATED_TOOL_RESULT);
+        assert_eq!(text(&messages[5]), TRUNCATED_TOOL_RESULT);
+        assert_eq!(text(&messages[8]).as_str().unwrap().len(), 4000);
+    }
+
+    #[test]
+    fn summary_cut_does_not_split_tool_pairs() {
+        let messages = conversation();
+        // Dropping the first tool result is enough, but the cut may not land before it
+        let cut = summary_cut(&messages, 3000, 2).unwrap();
+        assert_eq!(cut, 4);
+        assert_ne!(messages[cut].role, "tool");
+
+        // Nothing fits, so everything before the recent messages is summarized
+        assert_eq!(summary_cut(&messages, 10, 2), Some(7));
+        assert_eq!(summary_cut(&messages, 10, 8), None);
+    }
+
+    #[tokio::test]
+    async fn fits_the_prompt_to_each_model() {
+        let dir = std::env::temp_dir().join(format!("ccorp-overflow-{}", std::process::id()));
+        let mut config = crate::config::Config::default();
+        config.batches.dir = dir.to_string_lossy().into_owned();
+        let state = AppState::for_tests(config);
+        let catalog: Vec<Model> = serde_json::from_value(serde_json::json!([
+            { "id": "a/small", "name": "Small", "context_length": 2000 },
+            { "id": "b/large", "name": "Large", "context_length": 200000 },
+        ]))
+        .unwrap();
+        let overflow = OverflowConfig {
+            strategy: Some(OverflowStrategy::TruncateToolResults),
+            keep_recent: 2,
+            ..OverflowConfig::default()
+        };
+        let request = || OpenAIRequest {
+            model: "a/small".to_string(),
+            messages: conversation(),
+            temperature: None,
+            stream: None,
+            tools: None,
+            max_tokens: Some(1000),
+            stream_options: None,
+            models: None,
+            provider: None,
+            transforms: None,
+        };
+        let truncated = |request: &OpenAIRequest| {
+            request
+                .messages
+                .iter()
+                .filter(|m| serde_json::to_value(&m.content).unwrap() == TRUNCATED_TOOL_RESULT)
+                .count()
+        };
+
+        let mut small = request();
+        fit(&state, "key", &mut small, "a/small", &overflow, &catalog).await;
+        assert_eq!(truncated(&small), 2);
+        assert_eq!(small.max_tokens, Some(500));
+
+        let mut large = request();
+        fit(&state, "key", &mut large, "b/large", &overflow, &catalog).await;
+        assert_eq!(truncated(&large), 0);
+        assert_eq!(large.max_tokens, Some(1000));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,391 @@
+use crate::config::Config;
+use crate::{gemini, ollama, responses};
+use axum::body::Bytes;
+use axum::http::HeaderMap;
+use futures_util::stream::BoxStream;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, value::RawValue};
+use std::collections::BTreeMap;
+
+/// The API an upstream speaks
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    /// OpenAI chat completions, requests are translated from the Anthropic format
+    #[default]
+    #[serde(rename = "openai")]
+    OpenAI,
+    /// Anthropic messages, requests and responses are passed through unchanged
+    Anthropic,
+    /// Ollama's native chat API with newline-delimited JSON streams
+    Ollama,
+    /// Gemini `generateContent`, requests are translated from the Anthropic format
+    Gemini,
+    /// OpenAI Responses API, requests are translated from the Anthropic format
+    Responses,
+    /// Azure OpenAI chat completions, models name deployments
+    Azure,
+}
+
+impl Backend {
+    pub fn format(self) -> Format {
+        match self {
+            Backend::OpenAI | Backend::Ollama | Backend::Azure => Format::Chat,
+            Backend::Anthropic | Backend::Gemini | Backend::Responses => Format::Messages,
+        }
+    }
+
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn auth_header(self) -> &'static str {
+        match self {
+            Backend::OpenAI | Backend::Ollama | Backend::Responses => "authorization",
+            Backend::Anthropic => "x-api-key",
+            Backend::Gemini => "x-goog-api-key",
+            Backend::Azure => "api-key",
+        }
+    }
+
+    /// Convert a complete response into the backend's format, an OpenAI chat
+    /// completion for backends that are translated.
+    pub fn translate_response(self, response: Value) -> Value {
+        match self {
+            Backend::OpenAI | Backend::Anthropic | Backend::Azure => response,
+            Backend::Ollama => ollama::chat_completion(&response),
+            Backend::Gemini => gemini::message(&response),
+            Backend::Responses => responses::message(&response),
+        }
+    }
+
+    /// Convert a response stream into server-sent events of the backend's format.
+    pub fn translate_stream(self, body: ByteStream) -> ByteStream {
+        match self {
+            Backend::OpenAI | Backend::Anthropic | Backend::Azure => body,
+            Backend::Ollama => ollama::chunk_stream(body),
+            Backend::Gemini => gemini::event_stream(body),
+            Backend::Responses => responses::event_stream(body),
+        }
+    }
+}
+
+/// `api-version` of Azure requests when a provider sets none
+const AZURE_API_VERSION: &str = "2024-10-21";
+
+pub type ByteStream = BoxStream<'static, std::io::Result<Bytes>>;
+
+/// The shape of a request or response body
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// OpenAI chat completions
+    Chat,
+    /// Anthropic messages
+    Messages,
+}
+
+/// An upstream besides OpenRouter, e.g. a local vLLM or llama.cpp server
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct ProviderConfig {
+    /// Base URL of the API (e.g., http://localhost:8000/v1)
+    pub base_url: String,
+    #[serde(default, skip_serializing_if = "Backend::is_default")]
+    pub backend: Backend,
+    /// Environment variable holding the API key, requests are unauthenticated without one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key_env: Option<String>,
+    /// `api-version` query parameter of Azure requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_version: Option<String>,
+    /// Header carrying the API key, `authorization` sends it as a bearer token. Defaults
+    /// to the header the backend expects.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth_header: Option<String>,
+    /// Headers added to every request
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    /// Fields added to every request body
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub body: Map<String, Value>,
+}
+
+/// Where a request for one upstream model is sent
+#[derive(Clone, Debug, PartialEq)]
+pub struct Endpoint {
+    /// Name of the configured provider, `None` for OpenRouter
+    pub provider: Option<String>,
+    pub backend: Backend,
+    pub base_url: String,
+    /// The model id as the provider knows it
+    pub model: String,
+    pub api_key: Option<String>,
+    pub auth_header: String,
+    pub api_version: Option<String>,
+    pub headers: BTreeMap<String, String>,
+    pub body: Map<String, Value>,
+}
+
+impl Endpoint {
+    /// Body fields only OpenRouter understands
+    const OPENROUTER_FIELDS: &[&str] = &["models", "provider", "transforms"];
+
+    /// The request body for this endpoint, built from a chat completions or messages body.
+    pub fn request_body(&self, body: &Value) -> Value {
+        let mut body = match self.backend {
+            Backend::OpenAI | Backend::Anthropic | Backend::Azure => body.clone(),
+            Backend::Ollama => ollama::chat_request(body),
+            // Gemini takes the model from the URL
+            Backend::Gemini => gemini::request(body),
+            Backend::Responses => responses::request(body),
+        };
+        if self.backend != Backend::Gemini {
+            body["model"] = Value::String(self.model.clone());
+        }
+        if self.provider.is_none() {
+            return body;
+        }
+        if let Some(fields) = body.as_object_mut() {
+            for field in Self::OPENROUTER_FIELDS {
+                fields.remove(*field);
+            }
+            fields.extend(self.body.clone());
+        }
+        body
+    }
+
+    /// The client's request body with only the model replaced, so fields the proxy does
+    /// not know reach the upstream as they were sent. `None` when the body cannot be
+    /// spliced or the provider adds `body` fields.
+    pub fn passthrough_body(&self, original: &[u8]) -> Option<Vec<u8>> {
+        #[derive(Deserialize)]
+        struct ModelField<'a> {
+            #[serde(borrow)]
+            model: &'a RawValue,
+        }
+
+        if !self.body.is_empty() {
+            return None;
+        }
+        let field: ModelField = serde_json::from_slice(original).ok()?;
+        let start = field.model.get().as_ptr() as usize - original.as_ptr() as usize;
+        let end = start + field.model.get().len();
+        let model = serde_json::to_vec(&self.model).ok()?;
+        Some([&original[..start], &model, &original[end..]].concat())
+    }
+
+    /// This endpoint with the client's `anthropic-*` headers, which describe the
+    /// forwarded body and so take precedence over the configured ones.
+    pub fn forwarding(&self, headers: &BTreeMap<String, String>) -> Endpoint {
+        let mut endpoint = self.clone();
+        endpoint.headers.extend(headers.clone());
+        endpoint
+    }
+
+    pub fn url(&self, stream: bool) -> String {
+        let path = match self.backend {
+            Backend::OpenAI => "chat/completions",
+            Backend::Anthropic => "messages",
+            Backend::Ollama => "api/chat",
+            Backend::Responses => "responses",
+            Backend::Gemini if stream => {
+                return format!(
+                    "{}/models/{}:streamGenerateContent?alt=sse",
+                    self.base_url, self.model
+                );
+            }
+            Backend::Gemini => {
+                return format!("{}/models/{}:generateContent", self.base_url, self.model);
+            }
+            Backend::Azure => {
+                return format!(
+                    "{}/openai/deployments/{}/chat/completions?api-version={}",
+                    self.base_url,
+                    self.model,
+                    self.api_version.as_deref().unwrap_or(AZURE_API_VERSION)
+                );
+            }
+        };
+        format!("{}/{path}", self.base_url)
+    }
+
+    /// Add authentication and the configured headers to a request.
+    pub fn authorize(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        let request = match &self.api_key {
+            Some(key) if self.auth_header.eq_ignore_ascii_case("authorization") => {
+                request.bearer_auth(key)
+            }
+            Some(key) => request.header(&self.auth_header, key),
+            None => request,
+        };
+        self.headers.iter().fold(request, |request, (name, value)| {
+            request.header(name, value)
+        })
+    }
+}
+
+/// The client headers forwarded to Anthropic backends, e.g. `anthropic-beta`.
+/// Repeated headers are joined with commas.
+pub fn forwarded_headers(headers: &HeaderMap) -> BTreeMap<String, String> {
+    headers
+        .keys()
+        .filter(|name| name.as_str().starts_with("anthropic-"))
+        .map(|name| {
+            let values: Vec<&str> = headers
+                .get_all(name)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .collect();
+            (name.to_string(), values.join(","))
+        })
+        .collect()
+}
+
+/// Resolve `model` to an endpoint. Models named `provider/model` with a configured
+/// provider go there, everything else goes to OpenRouter with `openrouter_key`.
+pub fn endpoint(config: &Config, model: &str, openrouter_key: &str) -> Endpoint {
+    if let Some((name, provider_model)) = model.split_once('/')
+        && let Some(provider) = config.providers.get(name)
+    {
+        let mut headers = provider.headers.clone();
+        if provider.backend == Backend::Anthropic {
+            headers
+                .entry("anthropic-version".to_string())
+                .or_insert_with(|| config.anthropic_upstream.version.clone());
+        }
+        return Endpoint {
+            provider: Some(name.to_string()),
+            backend: provider.backend,
+            base_url: provider.base_url.trim_end_matches('/').to_string(),
+            model: provider_model.to_string(),
+            api_key: provider
+                .api_key_env
+                .as_ref()
+                .and_then(|var| std::env::var(var).ok()),
+            auth_header: provider
+                .auth_header
+                .clone()
+                .unwrap_or_else(|| provider.backend.auth_header().to_string()),
+            api_version: provider.api_version.clone(),
+            headers,
+            body: provider.body.clone(),
+        };
+    }
+
+    Endpoint {
+        provider: None,
+        backend: Backend::OpenAI,
+        base_url: config.base_url.clone(),
+        model: model.to_string(),
+        api_key: Some(openrouter_key.to_string()),
+        auth_header: Backend::OpenAI.auth_header().to_string(),
+        api_version: None,
+        headers: BTreeMap::new(),
+        body: Map::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn provider_models_go_to_their_provider() {
+        let mut config = Config {
+            base_url: "https://openrouter.ai/api/v1".to_string(),
+            ..Config::default()
+        };
+        config.providers.insert(
+            "local".to_string(),
+            serde_json::from_value(json!({
+                "base_url": "http://localhost:8000/v1/",
+                "headers": { "x-team": "infra" },
+                "body": { "top_k": 20 },
+            }))
+            .unwrap(),
+        );
+
+        let local = endpoint(&config, "local/qwen/qwen3-coder", "sk-or");
+        assert_eq!(local.provider.as_deref(), Some("local"));
+        assert_eq!(local.base_url, "http://localhost:8000/v1");
+        assert_eq!(local.model, "qwen/qwen3-coder");
+        assert_eq!(local.api_key, None);
+
+        let body = json!({ "model": "local/qwen/qwen3-coder", "transforms": [], "provider": {} });
+        assert_eq!(
+            local.request_body(&body),
+            json!({ "model": "qwen/qwen3-coder", "top_k": 20 })
+        );
+
+        config.providers.insert(
+            "claude".to_string(),
+            serde_json::from_value(json!({
+                "base_url": "https://api.anthropic.com/v1",
+                "backend": "anthropic",
+            }))
+            .unwrap(),
+        );
+        let claude = endpoint(&config, "claude/claude-sonnet-4", "sk-or");
+        assert_eq!(claude.url(true), "https://api.anthropic.com/v1/messages");
+        assert_eq!(claude.auth_header, "x-api-key");
+        assert_eq!(claude.headers["anthropic-version"], "2023-06-01");
+
+        let openrouter = endpoint(&config, "qwen/qwen3-coder", "sk-or");
+        assert_eq!(openrouter.provider, None);
+        assert_eq!(
+            openrouter.url(false),
+            "https://openrouter.ai/api/v1/chat/completions"
+        );
+        assert_eq!(openrouter.api_key.as_deref(), Some("sk-or"));
+    }
+
+    #[test]
+    fn azure_models_name_deployments() {
+        let mut config = Config::default();
+        config.providers.insert(
+            "azure".to_string(),
+            serde_json::from_value(json!({
+                "base_url": "https://example.openai.azure.com/",
+                "backend": "azure",
+            }))
+            .unwrap(),
+        );
+        config.providers.insert(
+            "azure-preview".to_string(),
+            serde_json::from_value(json!({
+                "base_url": "https://example.openai.azure.com",
+                "backend": "azure",
+                "api_version": "2025-01-01-preview",
+            }))
+            .unwrap(),
+        );
+
+        let mut azure = endpoint(&config, "azure/gpt-4o-prod", "sk-or");
+        assert_eq!(
+            azure.url(true),
+            "https://example.openai.azure.com/openai/deployments/gpt-4o-prod/chat/completions?api-version=2024-10-21"
+        );
+        let preview = endpoint(&config, "azure-preview/gpt-4o-prod", "sk-or");
+        assert_eq!(
+            preview.url(false),
+            "https://example.openai.azure.com/openai/deployments/gpt-4o-prod/chat/completions?api-version=2025-01-01-preview"
+        );
+
+        // The provider prefix and OpenRouter-only fields are not sent to Azure
+        let body =
+            json!({ "model": "azure/gpt-4o-prod", "models": [], "provider": {}, "messages": [] });
+        assert_eq!(
+            azure.request_body(&body),
+            json!({ "model": "gpt-4o-prod", "messages": [] })
+        );
+
+        assert_eq!(azure.auth_header, "api-key");
+        azure.api_key = Some("azure-key".to_string());
+        let request = azure
+            .authorize(reqwest::Client::new().post(azure.url(false)))
+            .build()
+            .unwrap();
+        assert_eq!(request.headers()["api-key"], "azure-key");
+        assert!(request.headers().get("authorization").is_none());
+    }
+}
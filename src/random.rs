@@ -0,0 +1,42 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// State of the SplitMix64 generator, seeded differently by every process
+static STATE: LazyLock<AtomicU64> =
+    LazyLock::new(|| AtomicU64::new(RandomState::new().build_hasher().finish()));
+
+/// The next pseudo-random number. Good enough for spreading load and jitter, not for secrets.
+fn next() -> u64 {
+    let mut z = STATE
+        .fetch_add(0x9e3779b97f4a7c15, Ordering::Relaxed)
+        .wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// A uniformly distributed number in `0..bound`, or 0 when `bound` is 0.
+pub fn below(bound: u64) -> u64 {
+    ((u128::from(next()) * u128::from(bound)) >> 64) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn covers_the_range_below_the_bound() {
+        let mut seen = [0u32; 4];
+        for _ in 0..4000 {
+            seen[below(4) as usize] += 1;
+        }
+        assert!(
+            seen.iter().all(|count| (800..1200).contains(count)),
+            "{seen:?}"
+        );
+        assert_eq!(below(0), 0);
+        assert_eq!(below(1), 0);
+    }
+}
@@ -0,0 +1,574 @@
+use crate::metrics::METRICS;
+use crate::openai_to_anthropic::error_type_for_status;
+use crate::sse::{SseParser, anthropic_event};
+use axum::body::Bytes;
+use futures_util::stream::{BoxStream, StreamExt};
+use serde_json::{Value, json};
+
+/// Reasoning items travel through Anthropic clients as thinking blocks whose signature
+/// holds the item id and its encrypted content, joined by this separator
+const SIGNATURE_SEPARATOR: char = ':';
+
+fn signature(item: &Value) -> Option<String> {
+    let encrypted = item["encrypted_content"].as_str()?;
+    let id = item["id"].as_str().unwrap_or_default();
+    Some(format!("{id}{SIGNATURE_SEPARATOR}{encrypted}"))
+}
+
+fn summary(item: &Value) -> String {
+    item["summary"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|part| part["text"].as_str())
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+fn input_image(source: &Value) -> Value {
+    let url = match source["type"].as_str() {
+        Some("base64") => format!(
+            "data:{};base64,{}",
+            source["media_type"].as_str().unwrap_or("image/png"),
+            source["data"].as_str().unwrap_or_default()
+        ),
+        _ => source["url"].as_str().unwrap_or_default().to_string(),
+    };
+    json!({ "type": "input_image", "image_url": url })
+}
+
+/// The output of a tool result, which the Responses API takes as text
+fn tool_output(content: &Value) -> String {
+    match content {
+        Value::String(text) => text.clone(),
+        Value::Array(blocks) => blocks
+            .iter()
+            .filter_map(|block| block["text"].as_str())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
+
+/// Append the input items for one Anthropic message.
+fn push_items(items: &mut Vec<Value>, role: &str, content: &Value) {
+    let text_type = if role == "assistant" {
+        "output_text"
+    } else {
+        "input_text"
+    };
+    let blocks = match content {
+        Value::String(text) => {
+            items.push(json!({ "role": role, "content": [{ "type": text_type, "text": text }] }));
+            return;
+        }
+        Value::Array(blocks) => blocks,
+        _ => return,
+    };
+
+    // Consecutive text and images form one message, other blocks become items of their own
+    let mut parts = Vec::new();
+    let flush = |items: &mut Vec<Value>, parts: &mut Vec<Value>| {
+        if !parts.is_empty() {
+            items.push(json!({ "role": role, "content": std::mem::take(parts) }));
+        }
+    };
+    for block in blocks {
+        match block["type"].as_str() {
+            Some("text") => parts.push(json!({ "type": text_type, "text": block["text"] })),
+            Some("image") => parts.push(input_image(&block["source"])),
+            Some("thinking") => {
+                let Some((id, encrypted)) = block["signature"]
+                    .as_str()
+                    .and_then(|signature| signature.split_once(SIGNATURE_SEPARATOR))
+                else {
+                    continue;
+                };
+                flush(items, &mut parts);
+                let summary = block["thinking"]
+                    .as_str()
+                    .filter(|text| !text.is_empty())
+                    .map(|text| json!([{ "type": "summary_text", "text": text }]))
+                    .unwrap_or(json!([]));
+                items.push(json!({
+                    "type": "reasoning",
+                    "id": id,
+                    "summary": summary,
+                    "encrypted_content": encrypted,
+                }));
+            }
+            Some("tool_use") => {
+                flush(items, &mut parts);
+                items.push(json!({
+                    "type": "function_call",
+                    "call_id": block["id"],
+                    "name": block["name"],
+                    "arguments": block["input"].to_string(),
+                }));
+            }
+            Some("tool_result") => {
+                flush(items, &mut parts);
+                items.push(json!({
+                    "type": "function_call_output",
+                    "call_id": block["tool_use_id"],
+                    "output": tool_output(&block["content"]),
+                }));
+            }
+            _ => {}
+        }
+    }
+    flush(items, &mut parts);
+}
+
+/// The reasoning effort closest to an Anthropic thinking budget
+fn effort(budget_tokens: u64) -> &'static str {
+    match budget_tokens {
+        ..4096 => "low",
+        4096..16384 => "medium",
+        _ => "high",
+    }
+}
+
+/// Convert an Anthropic Messages request into a Responses API request.
+pub fn request(req: &Value) -> Value {
+    let mut input = Vec::new();
+    for message in req["messages"].as_array().into_iter().flatten() {
+        let role = message["role"].as_str().unwrap_or("user");
+        push_items(&mut input, role, &message["content"]);
+    }
+    let carries_reasoning = input.iter().any(|item| item["type"] == "reasoning");
+
+    let mut request = json!({
+        "model": req["model"],
+        "input": input,
+        // Reasoning is carried over in the conversation instead of being stored upstream
+        "store": false,
+    });
+    let instructions = match &req["system"] {
+        Value::String(text) => text.clone(),
+        Value::Array(blocks) => blocks
+            .iter()
+            .filter_map(|block| block["text"].as_str())
+            .collect::<Vec<_>>()
+            .join("\n\n"),
+        _ => String::new(),
+    };
+    if !instructions.is_empty() {
+        request["instructions"] = json!(instructions);
+    }
+    for (from, to) in [
+        ("max_tokens", "max_output_tokens"),
+        ("temperature", "temperature"),
+        ("top_p", "top_p"),
+        ("stream", "stream"),
+    ] {
+        if !req[from].is_null() {
+            request[to] = req[from].clone();
+        }
+    }
+
+    if let Some(tools) = req["tools"].as_array() {
+        let tools: Vec<Value> = tools
+            .iter()
+            .map(|tool| {
+                json!({
+                    "type": "function",
+                    "name": tool["name"],
+                    "description": tool["description"],
+                    "parameters": tool["input_schema"],
+                    "strict": false,
+                })
+            })
+            .collect();
+        request["tools"] = json!(tools);
+    }
+    let tool_choice = &req["tool_choice"];
+    match tool_choice["type"].as_str() {
+        Some("any") => request["tool_choice"] = json!("required"),
+        Some("none") => request["tool_choice"] = json!("none"),
+        Some("tool") => {
+            request["tool_choice"] = json!({ "type": "function", "name": tool_choice["name"] });
+        }
+        Some(_) => request["tool_choice"] = json!("auto"),
+        None => {}
+    }
+
+    let thinking = req["thinking"]["type"] == "enabled";
+    if thinking {
+        let budget = req["thinking"]["budget_tokens"].as_u64().unwrap_or(0);
+        request["reasoning"] = json!({ "effort": effort(budget), "summary": "auto" });
+    }
+    if thinking || carries_reasoning {
+        request["include"] = json!(["reasoning.encrypted_content"]);
+    }
+    request
+}
+
+fn usage(response: &Value) -> Value {
+    json!({
+        "input_tokens": response["usage"]["input_tokens"].as_u64().unwrap_or(0),
+        "output_tokens": response["usage"]["output_tokens"].as_u64().unwrap_or(0),
+    })
+}
+
+fn stop_reason(response: &Value, called_tools: bool) -> &'static str {
+    if called_tools {
+        "tool_use"
+    } else if response["incomplete_details"]["reason"] == "max_output_tokens" {
+        "max_tokens"
+    } else {
+        "end_turn"
+    }
+}
+
+/// An Anthropic error event for a failed response or an `error` stream event
+fn error(error: &Value) -> Value {
+    let status = match error["code"].as_str() {
+        Some("rate_limit_exceeded") => 429,
+        Some("server_error") => 500,
+        Some("invalid_prompt") => 400,
+        _ => 502,
+    };
+    json!({
+        "type": "error",
+        "error": {
+            "type": error_type_for_status(status),
+            "message": error["message"],
+        },
+    })
+}
+
+/// Convert a complete Responses API response into an Anthropic message.
+pub fn message(response: &Value) -> Value {
+    if response["status"] == "failed" {
+        return error(&response["error"]);
+    }
+    let mut content = Vec::new();
+    let mut called_tools = false;
+    for item in response["output"].as_array().into_iter().flatten() {
+        match item["type"].as_str() {
+            Some("reasoning") => {
+                if let Some(signature) = signature(item) {
+                    content.push(json!({
+                        "type": "thinking",
+                        "thinking": summary(item),
+                        "signature": signature,
+                    }));
+                }
+            }
+            Some("message") => {
+                for part in item["content"].as_array().into_iter().flatten() {
+                    if part["type"] == "output_text" {
+                        content.push(json!({ "type": "text", "text": part["text"] }));
+                    }
+                }
+            }
+            Some("function_call") => {
+                called_tools = true;
+                let arguments = item["arguments"].as_str().unwrap_or("{}");
+                content.push(json!({
+                    "type": "tool_use",
+                    "id": item["call_id"],
+                    "name": item["name"],
+                    "input": serde_json::from_str::<Value>(arguments).unwrap_or(json!({})),
+                }));
+            }
+            _ => {}
+        }
+    }
+    json!({
+        "id": response["id"],
+        "type": "message",
+        "role": "assistant",
+        "model": response["model"],
+        "content": content,
+        "stop_reason": stop_reason(response, called_tools),
+        "stop_sequence": null,
+        "usage": usage(response),
+    })
+}
+
+/// The content block an encoder is currently writing
+#[derive(Clone, Copy, PartialEq)]
+enum OpenBlock {
+    Text,
+    Thinking,
+    ToolUse,
+}
+
+/// Builds an Anthropic event stream from Responses API stream events
+#[derive(Default)]
+struct EventEncoder {
+    started: bool,
+    open_block: Option<(usize, OpenBlock)>,
+    next_index: usize,
+    called_tools: bool,
+    stop_reason: Option<&'static str>,
+    usage: Option<Value>,
+}
+
+impl EventEncoder {
+    fn start_block(&mut self, events: &mut String, kind: OpenBlock, block: Value) -> usize {
+        self.close_block(events);
+        let index = self.next_index;
+        self.next_index += 1;
+        self.open_block = Some((index, kind));
+        events.push_str(&anthropic_event(json!({
+            "type": "content_block_start",
+            "index": index,
+            "content_block": block,
+        })));
+        index
+    }
+
+    fn close_block(&mut self, events: &mut String) {
+        if let Some((index, _)) = self.open_block.take() {
+            events.push_str(&anthropic_event(
+                json!({ "type": "content_block_stop", "index": index }),
+            ));
+        }
+    }
+
+    /// Continue the open block of `kind`, or start one
+    fn block(&mut self, events: &mut String, kind: OpenBlock) -> usize {
+        match self.open_block {
+            Some((index, open)) if open == kind => index,
+            _ => {
+                let block = match kind {
+                    OpenBlock::Thinking => json!({ "type": "thinking", "thinking": "" }),
+                    _ => json!({ "type": "text", "text": "" }),
+                };
+                self.start_block(events, kind, block)
+            }
+        }
+    }
+
+    fn delta(events: &mut String, index: usize, delta: Value) {
+        events.push_str(&anthropic_event(
+            json!({ "type": "content_block_delta", "index": index, "delta": delta }),
+        ));
+    }
+
+    /// Translate one stream event into zero or more Anthropic events
+    fn translate(&mut self, event: &Value) -> String {
+        let mut events = String::new();
+        match event["type"].as_str().unwrap_or_default() {
+            "response.created" => {
+                self.started = true;
+                let response = &event["response"];
+                events.push_str(&anthropic_event(json!({
+                    "type": "message_start",
+                    "message": {
+                        "id": response["id"],
+                        "type": "message",
+                        "role": "assistant",
+                        "model": response["model"],
+                        "content": [],
+                        "stop_reason": null,
+                        "stop_sequence": null,
+                        "usage": { "input_tokens": 0, "output_tokens": 0 },
+                    }
+                })));
+            }
+            "response.output_item.added" if event["item"]["type"] == "function_call" => {
+                self.called_tools = true;
+                let item = &event["item"];
+                self.start_block(
+                    &mut events,
+                    OpenBlock::ToolUse,
+                    json!({ "type": "tool_use", "id": item["call_id"], "name": item["name"], "input": {} }),
+                );
+            }
+            "response.output_text.delta" => {
+                let index = self.block(&mut events, OpenBlock::Text);
+                Self::delta(
+                    &mut events,
+                    index,
+                    json!({ "type": "text_delta", "text": event["delta"] }),
+                );
+            }
+            "response.reasoning_summary_text.delta" => {
+                let index = self.block(&mut events, OpenBlock::Thinking);
+                Self::delta(
+                    &mut events,
+                    index,
+                    json!({ "type": "thinking_delta", "thinking": event["delta"] }),
+                );
+            }
+            "response.function_call_arguments.delta" => {
+                if let Some((index, OpenBlock::ToolUse)) = self.open_block {
+                    Self::delta(
+                        &mut events,
+                        index,
+                        json!({ "type": "input_json_delta", "partial_json": event["delta"] }),
+                    );
+                }
+            }
+            "response.output_item.done" => {
+                let item = &event["item"];
+                // The encrypted reasoning only arrives with the finished item
+                if item["type"] == "reasoning"
+                    && let Some(signature) = signature(item)
+                {
+                    let index = self.block(&mut events, OpenBlock::Thinking);
+                    Self::delta(
+                        &mut events,
+                        index,
+                        json!({ "type": "signature_delta", "signature": signature }),
+                    );
+                }
+                self.close_block(&mut events);
+            }
+            "response.completed" | "response.incomplete" => {
+                let response = &event["response"];
+                self.usage = Some(usage(response));
+                self.stop_reason = Some(stop_reason(response, self.called_tools));
+            }
+            "response.failed" => {
+                events.push_str(&anthropic_event(error(&event["response"]["error"])));
+            }
+            "error" => events.push_str(&anthropic_event(error(event))),
+            _ => {}
+        }
+        events
+    }
+
+    /// Close the message once the upstream stream has ended
+    fn finish(&mut self) -> String {
+        let mut events = String::new();
+        if !self.started {
+            return events;
+        }
+        self.close_block(&mut events);
+        let usage = self
+            .usage
+            .take()
+            .unwrap_or_else(|| json!({ "output_tokens": 0 }));
+        events.push_str(&anthropic_event(json!({
+            "type": "message_delta",
+            "delta": { "stop_reason": self.stop_reason.unwrap_or("end_turn"), "stop_sequence": null },
+            "usage": usage,
+        })));
+        events.push_str(&anthropic_event(json!({ "type": "message_stop" })));
+        events
+    }
+}
+
+/// Re-encode a Responses API event stream as Anthropic events.
+pub fn event_stream(
+    mut body: BoxStream<'static, std::io::Result<Bytes>>,
+) -> BoxStream<'static, std::io::Result<Bytes>> {
+    async_stream::stream! {
+        let mut encoder = EventEncoder::default();
+        let mut parser = SseParser::default();
+        while let Some(chunk) = body.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            };
+            let mut events = String::new();
+            for event in parser.push(&chunk) {
+                match serde_json::from_str::<Value>(&event.data) {
+                    Ok(event) => events.push_str(&encoder.translate(&event)),
+                    Err(_) => METRICS.inc(
+                        "ccorp_translation_repairs_total",
+                        &[("kind", "unparseable_stream_chunk")],
+                    ),
+                }
+            }
+            if !events.is_empty() {
+                yield Ok(Bytes::from(events));
+            }
+        }
+        yield Ok(Bytes::from(encoder.finish()));
+    }
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> String {
+        let path = format!(
+            "{}/tests/fixtures/responses/{name}",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn translates_request_with_reasoning_carry_over() {
+        let anthropic: Value = serde_json::from_str(&fixture("messages_request.json")).unwrap();
+        let expected: Value = serde_json::from_str(&fixture("responses_request.json")).unwrap();
+        assert_eq!(request(&anthropic), expected);
+    }
+
+    #[test]
+    fn translates_response() {
+        let response: Value = serde_json::from_str(&fixture("response.json")).unwrap();
+        let translated = message(&response);
+
+        assert_eq!(translated["stop_reason"], "tool_use");
+        assert_eq!(
+            translated["usage"],
+            json!({ "input_tokens": 328, "output_tokens": 211 })
+        );
+        let content = translated["content"].as_array().unwrap();
+        assert_eq!(content[0]["type"], "thinking");
+        assert_eq!(content[0]["signature"], "rs_68b9a1:gAAAAABouaHx");
+        assert_eq!(content[1]["text"], "Let me check the file.");
+        assert_eq!(content[2]["id"], "call_Xa9");
+        assert_eq!(content[2]["input"], json!({ "path": "src/main.rs" }));
+
+        // The thinking block round-trips into a reasoning item
+        let mut items = Vec::new();
+        push_items(&mut items, "assistant", &json!([content[0]]));
+        assert_eq!(items[0]["id"], "rs_68b9a1");
+        assert_eq!(items[0]["encrypted_content"], "gAAAAABouaHx");
+    }
+
+    #[test]
+    fn translates_stream() {
+        let mut encoder = EventEncoder::default();
+        let mut events = String::new();
+        for event in SseParser::default().push(fixture("stream.sse").as_bytes()) {
+            events.push_str(&encoder.translate(&serde_json::from_str(&event.data).unwrap()));
+        }
+        events.push_str(&encoder.finish());
+
+        let events: Vec<Value> = SseParser::default()
+            .push(events.as_bytes())
+            .iter()
+            .map(|event| serde_json::from_str(&event.data).unwrap())
+            .collect();
+        let types: Vec<&str> = events.iter().map(|e| e["type"].as_str().unwrap()).collect();
+        assert_eq!(
+            types,
+            [
+                "message_start",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_delta",
+                "content_block_stop",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_delta",
+                "content_block_stop",
+                "content_block_start",
+                "content_block_delta",
+                "content_block_delta",
+                "content_block_stop",
+                "message_delta",
+                "message_stop",
+            ]
+        );
+        assert_eq!(events[3]["delta"]["signature"], "rs_68b9a1:gAAAAABouaHx");
+        assert_eq!(events[9]["content_block"]["id"], "call_Xa9");
+        assert_eq!(events[13]["delta"]["stop_reason"], "tool_use");
+        assert_eq!(events[13]["usage"]["output_tokens"], 211);
+    }
+}
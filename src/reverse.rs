@@ -0,0 +1,458 @@
+use crate::anthropic_to_openai::{
+    finish_reason_for_stop_reason, format_anthropic_response_to_openai,
+};
+use crate::chat_completions::openai_error_response;
+use crate::http;
+use crate::models::AnthropicResponse;
+use crate::openai_to_anthropic::format_openai_request_to_anthropic;
+use crate::shutdown;
+use crate::sse::SseParser;
+use crate::{AppState, client_api_key, write_log};
+use axum::{
+    body::Body,
+    extract::{Json, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use futures_util::stream::StreamExt;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+
+/// Builds OpenAI `chat.completion.chunk` events from an Anthropic event stream
+struct ChunkEncoder {
+    id: String,
+    model: String,
+    created: u64,
+    /// Maps Anthropic content block indices to OpenAI tool call indices
+    tool_indices: HashMap<u64, usize>,
+    input_tokens: u64,
+    output_tokens: u64,
+}
+
+impl ChunkEncoder {
+    fn chunk(&self, delta: Value, finish_reason: Option<&str>) -> String {
+        let chunk = json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": [{
+                "index": 0,
+                "delta": delta,
+                "finish_reason": finish_reason,
+            }]
+        });
+        format!("data: {chunk}\n\n")
+    }
+
+    fn usage_chunk(&self) -> String {
+        let chunk = json!({
+            "id": self.id,
+            "object": "chat.completion.chunk",
+            "created": self.created,
+            "model": self.model,
+            "choices": [],
+            "usage": {
+                "prompt_tokens": self.input_tokens,
+                "completion_tokens": self.output_tokens,
+                "total_tokens": self.input_tokens + self.output_tokens,
+            }
+        });
+        format!("data: {chunk}\n\n")
+    }
+
+    /// Translate one Anthropic event into zero or more OpenAI SSE chunks
+    fn translate(&mut self, event: &Value) -> Option<String> {
+        match event["type"].as_str()? {
+            "message_start" => {
+                let message = &event["message"];
+                self.id = message["id"].as_str().unwrap_or_default().to_string();
+                self.model = message["model"].as_str().unwrap_or_default().to_string();
+                self.input_tokens = message["usage"]["input_tokens"].as_u64().unwrap_or(0);
+                Some(self.chunk(json!({ "role": "assistant", "content": "" }), None))
+            }
+            "content_block_start" if event["content_block"]["type"] == "tool_use" => {
+                let tool_index = self.tool_indices.len();
+                self.tool_indices
+                    .insert(event["index"].as_u64().unwrap_or(0), tool_index);
+                Some(self.chunk(
+                    json!({
+                        "tool_calls": [{
+                            "index": tool_index,
+                            "id": event["content_block"]["id"],
+                            "type": "function",
+                            "function": {
+                                "name": event["content_block"]["name"],
+                                "arguments": "",
+                            }
+                        }]
+                    }),
+                    None,
+                ))
+            }
+            "content_block_delta" => {
+                let delta = &event["delta"];
+                match delta["type"].as_str()? {
+                    "text_delta" => Some(self.chunk(json!({ "content": delta["text"] }), None)),
+                    "input_json_delta" => {
+                        let tool_index = self.tool_indices.get(&event["index"].as_u64()?)?;
+                        Some(self.chunk(
+                            json!({
+                                "tool_calls": [{
+                                    "index": tool_index,
+                                    "function": { "arguments": delta["partial_json"] }
+                                }]
+                            }),
+                            None,
+                        ))
+                    }
+                    _ => None,
+                }
+            }
+            "message_delta" => {
+                if let Some(output_tokens) = event["usage"]["output_tokens"].as_u64() {
+                    self.output_tokens = output_tokens;
+                }
+                let stop_reason = event["delta"]["stop_reason"].as_str()?;
+                Some(self.chunk(json!({}), Some(finish_reason_for_stop_reason(stop_reason))))
+            }
+            "error" => {
+                let error = json!({ "error": event["error"] });
+                Some(format!("data: {error}\n\n"))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// POST /anthropic/v1/chat/completions - OpenAI requests served by an Anthropic-compatible upstream
+pub async fn reverse_chat_completions_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<Value>,
+) -> Response {
+    let settings_guard = state.config.read().await;
+    let upstream = settings_guard.anthropic_upstream.clone();
+    let http_settings = settings_guard.http.clone();
+    let mut anthropic_request = format_openai_request_to_anthropic(&payload);
+    if let Some(target) = settings_guard.aliases.get(&anthropic_request.model) {
+        anthropic_request.model = target.clone();
+    }
+    drop(settings_guard);
+
+    let Some(api_key) =
+        client_api_key(&headers).or_else(|| std::env::var(&upstream.api_key_env).ok())
+    else {
+        return openai_error_response(
+            StatusCode::UNAUTHORIZED,
+            format!(
+                "Missing API key, send it as a bearer token or set {}",
+                upstream.api_key_env
+            ),
+        );
+    };
+    let include_usage = payload["stream_options"]["include_usage"]
+        .as_bool()
+        .unwrap_or(false);
+
+    write_log(
+        &state,
+        "request",
+        &serde_json::to_string_pretty(&anthropic_request).unwrap(),
+    );
+
+    let stream = anthropic_request.stream.unwrap_or(false);
+    let mut request = state.http.post(format!("{}/messages", upstream.base_url));
+    if !stream && let Some(timeout) = http_settings.request_timeout() {
+        request = request.timeout(timeout);
+    }
+    let res = match request
+        .header("x-api-key", api_key)
+        .header("anthropic-version", upstream.version)
+        .json(&anthropic_request)
+        .send()
+        .await
+    {
+        Ok(res) => res,
+        Err(e) => return openai_error_response(StatusCode::BAD_GATEWAY, e.to_string()),
+    };
+    let status = res.status();
+
+    if !status.is_success() {
+        let body = res.text().await.unwrap_or_default();
+        write_log(&state, "response", &body);
+        let message = serde_json::from_str::<Value>(&body)
+            .ok()
+            .and_then(|error| error["error"]["message"].as_str().map(str::to_string))
+            .unwrap_or(body);
+        return openai_error_response(status, message);
+    }
+
+    if !stream {
+        let anthropic_response: AnthropicResponse = match res.json().await {
+            Ok(response) => response,
+            Err(e) => return openai_error_response(StatusCode::BAD_GATEWAY, e.to_string()),
+        };
+        write_log(
+            &state,
+            "response",
+            &serde_json::to_string_pretty(&anthropic_response).unwrap(),
+        );
+        return (
+            StatusCode::OK,
+            Json(format_anthropic_response_to_openai(anthropic_response)),
+        )
+            .into_response();
+    }
+
+    let stream = async_stream::stream! {
+        let mut stream = http::idle_timeout(
+            res.bytes_stream().boxed(),
+            http_settings.stream_idle_timeout(),
+        );
+        let mut parser = SseParser::default();
+        let mut encoder = ChunkEncoder {
+            id: String::new(),
+            model: String::new(),
+            created: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            tool_indices: HashMap::new(),
+            input_tokens: 0,
+            output_tokens: 0,
+        };
+        let mut full_response = String::new();
+
+        loop {
+            let Some(item) = state.shutdown.next(&mut stream).await else {
+                if let Some(sse_event) = encoder.translate(&shutdown::error()) {
+                    yield Ok::<_, axum::Error>(sse_event.into_bytes());
+                }
+                break;
+            };
+            let Some(item) = item else {
+                break;
+            };
+            let chunk = match item {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    tracing::error!("Anthropic stream failed: {e}");
+                    break;
+                }
+            };
+            full_response.push_str(&String::from_utf8_lossy(&chunk));
+            for event in parser.push(&chunk) {
+                let Ok(event) = serde_json::from_str::<Value>(&event.data) else {
+                    continue;
+                };
+                if let Some(sse_event) = encoder.translate(&event) {
+                    yield Ok::<_, axum::Error>(sse_event.into_bytes());
+                }
+            }
+        }
+
+        if include_usage {
+            yield Ok::<_, axum::Error>(encoder.usage_chunk().into_bytes());
+        }
+        yield Ok::<_, axum::Error>(b"data: [DONE]\n\n".to_vec());
+
+        write_log(&state, "response", &full_response);
+    };
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .body(Body::from_stream(stream))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AnthropicUpstreamConfig, BatchConfig, Config};
+    use axum::body::to_bytes;
+    use std::sync::{Arc, Mutex};
+    use tempfile::TempDir;
+
+    /// An Anthropic upstream answering with `response`, keeping the last request it received
+    async fn upstream(response: Value) -> (String, Arc<Mutex<Option<(HeaderMap, Value)>>>) {
+        let received = Arc::new(Mutex::new(None));
+        let seen = received.clone();
+        let app = axum::Router::new().route(
+            "/messages",
+            axum::routing::post(move |headers: HeaderMap, Json(body): Json<Value>| {
+                *seen.lock().unwrap() = Some((headers, body));
+                let response = response.clone();
+                async move { Json(response) }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (format!("http://{addr}"), received)
+    }
+
+    /// A state using `base_url` as the Anthropic upstream, with batches kept in `dir`
+    fn state(base_url: String, dir: &TempDir) -> AppState {
+        AppState::for_tests(Config {
+            anthropic_upstream: AnthropicUpstreamConfig {
+                base_url,
+                ..AnthropicUpstreamConfig::default()
+            },
+            batches: BatchConfig {
+                dir: dir.path().to_string_lossy().into_owned(),
+                ..BatchConfig::default()
+            },
+            ..Config::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn serves_chat_requests_from_an_anthropic_upstream() {
+        let (base_url, received) = upstream(json!({
+            "id": "msg_1",
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-sonnet-4-20250514",
+            "content": [
+                { "type": "text", "text": "Let me look." },
+                { "type": "tool_use", "id": "t1", "name": "look", "input": { "zoom": 2 } },
+            ],
+            "stop_reason": "tool_use",
+            "usage": { "input_tokens": 20, "output_tokens": 5 },
+        }))
+        .await;
+        let dir = TempDir::new().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Bearer client-key".parse().unwrap());
+
+        let response = reverse_chat_completions_handler(
+            State(state(base_url, &dir)),
+            headers,
+            Json(json!({
+                "model": "claude-sonnet-4-20250514",
+                "messages": [
+                    { "role": "system", "content": "Be brief." },
+                    { "role": "user", "content": [
+                        { "type": "text", "text": "What is this?" },
+                        { "type": "image_url", "image_url": { "url": "https://example.com/a.png" } },
+                    ] },
+                ],
+                "tools": [{ "type": "function", "function": { "name": "look", "parameters": { "type": "object" } } }],
+                "tool_choice": "required",
+            })),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let completion: Value = serde_json::from_slice(&body).unwrap();
+
+        let (upstream_headers, request) = received.lock().unwrap().take().unwrap();
+        assert_eq!(upstream_headers["x-api-key"], "client-key");
+        assert_eq!(upstream_headers["anthropic-version"], "2023-06-01");
+        assert_eq!(request["system"], "Be brief.");
+        assert_eq!(
+            request["messages"][0]["content"][1]["source"]["url"],
+            "https://example.com/a.png"
+        );
+        assert_eq!(request["tools"][0]["name"], "look");
+        assert_eq!(request["tool_choice"], json!({ "type": "any" }));
+
+        assert_eq!(completion["object"], "chat.completion");
+        let choice = &completion["choices"][0];
+        assert_eq!(choice["message"]["content"], "Let me look.");
+        assert_eq!(choice["message"]["tool_calls"][0]["id"], "t1");
+        assert_eq!(
+            choice["message"]["tool_calls"][0]["function"]["arguments"],
+            r#"{"zoom":2}"#
+        );
+        assert_eq!(choice["finish_reason"], "tool_calls");
+        assert_eq!(completion["usage"]["total_tokens"], 25);
+    }
+
+    #[test]
+    fn maps_stop_reasons_to_finish_reasons() {
+        for (stop_reason, finish_reason) in [
+            ("end_turn", "stop"),
+            ("stop_sequence", "stop"),
+            ("max_tokens", "length"),
+            ("tool_use", "tool_calls"),
+            ("refusal", "content_filter"),
+        ] {
+            let response: AnthropicResponse = serde_json::from_value(json!({
+                "id": "msg_1",
+                "type": "message",
+                "role": "assistant",
+                "model": "m",
+                "content": [{ "type": "text", "text": "hi" }],
+                "stop_reason": stop_reason,
+            }))
+            .unwrap();
+            let completion = format_anthropic_response_to_openai(response);
+            assert_eq!(completion["choices"][0]["finish_reason"], finish_reason);
+        }
+    }
+
+    #[test]
+    fn encodes_anthropic_events_as_chunks() {
+        let mut encoder = ChunkEncoder {
+            id: String::new(),
+            model: String::new(),
+            created: 1,
+            tool_indices: HashMap::new(),
+            input_tokens: 0,
+            output_tokens: 0,
+        };
+        let events = [
+            json!({ "type": "message_start", "message": {
+                "id": "msg_1", "model": "claude-sonnet-4", "usage": { "input_tokens": 30, "output_tokens": 1 },
+            } }),
+            json!({ "type": "content_block_start", "index": 0, "content_block": { "type": "text", "text": "" } }),
+            json!({ "type": "content_block_delta", "index": 0, "delta": { "type": "text_delta", "text": "Looking" } }),
+            json!({ "type": "content_block_stop", "index": 0 }),
+            json!({ "type": "content_block_start", "index": 1, "content_block": {
+                "type": "tool_use", "id": "t1", "name": "look", "input": {},
+            } }),
+            json!({ "type": "content_block_delta", "index": 1, "delta": { "type": "input_json_delta", "partial_json": "{\"zoom\":" } }),
+            json!({ "type": "content_block_delta", "index": 1, "delta": { "type": "input_json_delta", "partial_json": "2}" } }),
+            json!({ "type": "content_block_stop", "index": 1 }),
+            json!({ "type": "message_delta", "delta": { "stop_reason": "tool_use" }, "usage": { "output_tokens": 12 } }),
+            json!({ "type": "message_stop" }),
+        ];
+        let mut chunks: Vec<Value> = events
+            .iter()
+            .filter_map(|event| encoder.translate(event))
+            .map(|chunk| serde_json::from_str(chunk.strip_prefix("data: ").unwrap()).unwrap())
+            .collect();
+        let usage: Value =
+            serde_json::from_str(encoder.usage_chunk().strip_prefix("data: ").unwrap()).unwrap();
+
+        assert!(chunks.iter().all(|chunk| chunk["id"] == "msg_1"
+            && chunk["model"] == "claude-sonnet-4"
+            && chunk["object"] == "chat.completion.chunk"));
+        let deltas: Vec<Value> = chunks
+            .iter_mut()
+            .map(|chunk| chunk["choices"][0]["delta"].take())
+            .collect();
+        assert_eq!(
+            deltas,
+            [
+                json!({ "role": "assistant", "content": "" }),
+                json!({ "content": "Looking" }),
+                json!({ "tool_calls": [{
+                    "index": 0, "id": "t1", "type": "function", "function": { "name": "look", "arguments": "" },
+                }] }),
+                json!({ "tool_calls": [{ "index": 0, "function": { "arguments": "{\"zoom\":" } }] }),
+                json!({ "tool_calls": [{ "index": 0, "function": { "arguments": "2}" } }] }),
+                json!({}),
+            ]
+        );
+        assert_eq!(chunks[5]["choices"][0]["finish_reason"], "tool_calls");
+
+        assert_eq!(usage["choices"], json!([]));
+        assert_eq!(
+            usage["usage"],
+            json!({ "prompt_tokens": 30, "completion_tokens": 12, "total_tokens": 42 })
+        );
+    }
+}
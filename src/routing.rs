@@ -0,0 +1,1049 @@
+use crate::config::Config;
+use crate::metrics::METRICS;
+use crate::models::AnthropicRequest;
+use crate::openrouter::ProviderPreferences;
+use crate::overflow::OverflowConfig;
+use crate::random;
+use crate::selection::AutoTarget;
+use axum::http::{HeaderMap, StatusCode};
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Ordered model routing rules, evaluated top to bottom
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct RoutingConfig {
+    /// The first rule whose pattern matches the requested model wins
+    pub rules: Vec<RouteRule>,
+    /// Target for requests that match no rule
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default: Option<RouteAction>,
+    /// Reject requests that match no rule (and no default) instead of passing the name through
+    pub reject_unmapped: bool,
+    /// Header identifying a session for split targets, used instead of `metadata.user_id`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_header: Option<String>,
+    /// Per-request model overrides sent as headers
+    #[serde(skip_serializing_if = "OverrideConfig::is_default")]
+    pub overrides: OverrideConfig,
+    /// Seconds between re-evaluations of `auto` targets, 0 selects only at startup
+    pub auto_refresh_secs: u64,
+}
+
+impl Default for RoutingConfig {
+    fn default() -> Self {
+        RoutingConfig {
+            rules: Vec::new(),
+            default: None,
+            reject_unmapped: false,
+            session_header: None,
+            overrides: OverrideConfig::default(),
+            auto_refresh_secs: 3600,
+        }
+    }
+}
+
+impl RoutingConfig {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Lets a client pick the upstream model for its own requests
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct OverrideConfig {
+    /// Header naming an upstream model directly
+    pub header: String,
+    /// Header naming one of the `profiles`
+    pub profile_header: String,
+    /// Glob patterns of the models the `header` may name, overrides are ignored when empty
+    pub allowed: Vec<String>,
+    /// Named upstream models, always allowed
+    pub profiles: BTreeMap<String, String>,
+}
+
+impl Default for OverrideConfig {
+    fn default() -> Self {
+        OverrideConfig {
+            header: "x-ccorp-model".to_string(),
+            profile_header: "x-ccorp-profile".to_string(),
+            allowed: Vec::new(),
+            profiles: BTreeMap::new(),
+        }
+    }
+}
+
+impl OverrideConfig {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The model requested through the override headers, if any.
+    fn requested_model(&self, headers: &HeaderMap) -> Result<Option<String>, String> {
+        let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+        if let Some(profile) = header(&self.profile_header) {
+            return match self.profiles.get(profile) {
+                Some(model) => Ok(Some(model.clone())),
+                None => Err(format!("unknown model profile: {profile}")),
+            };
+        }
+        match header(&self.header) {
+            None => Ok(None),
+            Some(_) if self.allowed.is_empty() => Ok(None),
+            Some(model) if self.allowed.iter().any(|p| glob_matches(p, model)) => {
+                Ok(Some(model.to_string()))
+            }
+            Some(model) => Err(format!("model override {model} is not allowed")),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct RouteRule {
+    /// Name used in logs, defaults to the rule's position
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Pattern for the requested model name, a rule without one matches every name
+    #[serde(rename = "match", default, skip_serializing_if = "Option::is_none")]
+    pub matcher: Option<ModelMatch>,
+    /// Conditions on the request content that must all hold for the rule to match
+    #[serde(default, skip_serializing_if = "RouteConditions::is_empty")]
+    pub when: RouteConditions,
+    #[serde(flatten)]
+    pub action: RouteAction,
+}
+
+/// Where a matching request goes and what to try when that fails
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct RouteAction {
+    #[serde(flatten)]
+    pub target: RouteTarget,
+    /// Models tried in order when the target fails before anything was streamed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fallbacks: Vec<String>,
+    /// Let OpenRouter walk the fallbacks through its `models` parameter instead of retrying locally
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub native_fallbacks: bool,
+    /// Provider preferences overriding the global ones field by field
+    #[serde(default, skip_serializing_if = "ProviderPreferences::is_default")]
+    pub provider: ProviderPreferences,
+    /// Overflow handling replacing the global one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overflow: Option<OverflowConfig>,
+}
+
+impl From<RouteTarget> for RouteAction {
+    fn from(target: RouteTarget) -> Self {
+        RouteAction {
+            target,
+            fallbacks: Vec::new(),
+            native_fallbacks: false,
+            provider: ProviderPreferences::default(),
+            overflow: None,
+        }
+    }
+}
+
+impl RouteAction {
+    /// The route for a matching request. An `auto` target without a selected model
+    /// falls back to the rule's `fallbacks`, and fails without any.
+    fn route(
+        &self,
+        rule: &str,
+        config: &Config,
+        features: &RequestFeatures,
+    ) -> Result<Route, Unroutable> {
+        let mut fallbacks = self.fallbacks.clone();
+        let (model, arm) = match self.target.pick(config, features.session.as_deref()) {
+            Some(picked) => picked,
+            None if !fallbacks.is_empty() => (fallbacks.remove(0), None),
+            None => return Err(Unroutable::Unselected(rule.to_string())),
+        };
+        Ok(Route {
+            model,
+            arm,
+            rule: rule.to_string(),
+            fallbacks,
+            native_fallbacks: self.native_fallbacks,
+            provider: config.provider.overridden_by(&self.provider),
+            overflow: self.overflow.as_ref().unwrap_or(&config.overflow).clone(),
+        })
+    }
+}
+
+/// Conditions on the request itself; unset conditions are ignored
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RouteConditions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub images: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thinking: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_message_tool_result: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_prompt_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_prompt_tokens: Option<u64>,
+}
+
+impl RouteConditions {
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn matches(&self, features: &RequestFeatures) -> bool {
+        let flag = |condition: Option<bool>, value: bool| condition.is_none_or(|c| c == value);
+        flag(self.tools, features.tools)
+            && flag(self.images, features.images)
+            && flag(self.thinking, features.thinking)
+            && flag(
+                self.last_message_tool_result,
+                features.last_message_tool_result,
+            )
+            && self
+                .min_prompt_tokens
+                .is_none_or(|min| features.prompt_tokens >= min)
+            && self
+                .max_prompt_tokens
+                .is_none_or(|max| features.prompt_tokens <= max)
+    }
+}
+
+/// Characteristics of a request that routing conditions can look at
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RequestFeatures {
+    pub tools: bool,
+    pub images: bool,
+    pub thinking: bool,
+    pub last_message_tool_result: bool,
+    /// Rough prompt size, estimated at four characters per token
+    pub prompt_tokens: u64,
+    /// Key keeping a conversation on the same arm of a split target
+    pub session: Option<String>,
+}
+
+/// Estimate the number of tokens of a piece of JSON content.
+pub fn estimate_tokens(value: &Value) -> u64 {
+    let chars = match value {
+        Value::Null => 0,
+        Value::String(text) => text.len(),
+        other => other.to_string().len(),
+    };
+    chars.div_ceil(4) as u64
+}
+
+fn has_block_type(content: &Value, block_type: &str) -> bool {
+    content.as_array().is_some_and(|blocks| {
+        blocks.iter().any(|block| {
+            block["type"] == block_type
+                // Images can also be nested inside tool results
+                || (block["type"] == "tool_result" && has_block_type(&block["content"], block_type))
+        })
+    })
+}
+
+impl RequestFeatures {
+    /// Features of an Anthropic Messages request.
+    pub fn from_anthropic(req: &AnthropicRequest) -> Self {
+        let system = req.system.as_ref().map_or(0, estimate_tokens);
+        let tools = req
+            .tools
+            .as_ref()
+            .map_or(0, |tools| tools.iter().map(estimate_tokens).sum());
+        let messages: u64 = req
+            .messages
+            .iter()
+            .map(|m| estimate_tokens(&m.content))
+            .sum();
+
+        RequestFeatures {
+            tools: req.tools.as_ref().is_some_and(|tools| !tools.is_empty()),
+            images: req
+                .messages
+                .iter()
+                .any(|m| has_block_type(&m.content, "image")),
+            thinking: req
+                .thinking
+                .as_ref()
+                .is_some_and(|thinking| thinking["type"] == "enabled"),
+            last_message_tool_result: req
+                .messages
+                .last()
+                .is_some_and(|m| has_block_type(&m.content, "tool_result")),
+            prompt_tokens: system + tools + messages,
+            session: req
+                .metadata
+                .as_ref()
+                .and_then(|metadata| metadata["user_id"].as_str())
+                .map(str::to_string),
+        }
+    }
+
+    /// Features of an OpenAI Chat Completions request.
+    pub fn from_openai(req: &Value) -> Self {
+        let messages = req["messages"]
+            .as_array()
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let tools = req["tools"]
+            .as_array()
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+
+        RequestFeatures {
+            tools: !tools.is_empty(),
+            images: messages
+                .iter()
+                .any(|m| has_block_type(&m["content"], "image_url")),
+            thinking: !req["reasoning_effort"].is_null() || !req["reasoning"].is_null(),
+            last_message_tool_result: messages.last().is_some_and(|m| m["role"] == "tool"),
+            prompt_tokens: messages
+                .iter()
+                .map(|m| estimate_tokens(&m["content"]))
+                .chain(tools.iter().map(estimate_tokens))
+                .sum(),
+            session: req["user"].as_str().map(str::to_string),
+        }
+    }
+
+    /// Prefer the configured session header, when sent, over the session from the body.
+    pub fn with_session_header(mut self, headers: &HeaderMap, config: &Config) -> Self {
+        if let Some(name) = &config.routing.session_header
+            && let Some(value) = headers.get(name).and_then(|v| v.to_str().ok())
+        {
+            self.session = Some(value.to_string());
+        }
+        self
+    }
+}
+
+/// How a rule matches the requested model name
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ModelMatch {
+    Exact(String),
+    /// `*` matches any sequence of characters, `?` a single character
+    Glob(String),
+    Regex(ModelRegex),
+}
+
+/// Where a matching request is sent
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RouteTarget {
+    /// A fixed upstream model id
+    Model(String),
+    /// One of the mappings from the `models` section
+    Mapping(ModelSlot),
+    /// Traffic split between models by weight, sticky per session
+    Split(#[serde(deserialize_with = "non_empty_arms")] Vec<SplitArm>),
+    /// The cheapest catalog model meeting some requirements
+    Auto(AutoTarget),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct SplitArm {
+    pub model: String,
+    pub weight: u32,
+}
+
+fn non_empty_arms<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<SplitArm>, D::Error> {
+    let arms = Vec::<SplitArm>::deserialize(deserializer)?;
+    if arms.iter().all(|arm| arm.weight == 0) {
+        return Err(serde::de::Error::custom(
+            "a split needs at least one arm with a weight",
+        ));
+    }
+    Ok(arms)
+}
+
+/// The model mappings editable through `/switch-model`
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ModelSlot {
+    Haiku,
+    Sonnet,
+    Opus,
+}
+
+/// A regex compiled while the configuration is loaded
+#[derive(Clone, Debug)]
+pub struct ModelRegex(pub Regex);
+
+impl PartialEq for ModelRegex {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Serialize for ModelRegex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for ModelRegex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Regex::new(&pattern)
+            .map(ModelRegex)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// The outcome of routing a request
+#[derive(Clone, Debug, PartialEq)]
+pub struct Route {
+    /// The upstream model to send the request to
+    pub model: String,
+    /// Index of the chosen arm when the target is a split
+    pub arm: Option<usize>,
+    /// Which rule decided, for logging
+    pub rule: String,
+    /// Models to try, in order, when `model` fails
+    pub fallbacks: Vec<String>,
+    pub native_fallbacks: bool,
+    /// OpenRouter provider preferences for the request
+    pub provider: ProviderPreferences,
+    /// How prompts larger than the model's context window are handled
+    pub overflow: OverflowConfig,
+}
+
+impl Route {
+    /// A route straight to `model`, without fallbacks.
+    pub fn direct(model: &str, rule: &str, config: &Config) -> Self {
+        let target = RouteTarget::Model(model.to_string());
+        RouteAction::from(target)
+            .route(rule, config, &RequestFeatures::default())
+            .expect("a fixed model is always routable")
+    }
+
+    /// The target model followed by its fallbacks.
+    pub fn candidates(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.model).chain(&self.fallbacks)
+    }
+}
+
+/// Why a request cannot be routed
+#[derive(Debug, PartialEq)]
+pub enum Unroutable {
+    /// The requested model matched no rule and unmapped models are rejected
+    Unmapped(String),
+    /// The matching rule's `auto` target has not selected a model yet and the rule
+    /// has no fallbacks
+    Unselected(String),
+}
+
+impl Unroutable {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Unroutable::Unmapped(_) => StatusCode::NOT_FOUND,
+            Unroutable::Unselected(_) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+impl fmt::Display for Unroutable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unroutable::Unmapped(model) => write!(f, "model: {model} is not mapped by this proxy"),
+            Unroutable::Unselected(rule) => write!(
+                f,
+                "rule {rule} has not selected a model yet because the OpenRouter model list is unavailable"
+            ),
+        }
+    }
+}
+
+/// Match `name` against a glob pattern supporting `*` and `?`.
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Position of the last `*` and the name position it was tried at
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            n = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+impl ModelMatch {
+    pub fn matches(&self, model: &str) -> bool {
+        match self {
+            ModelMatch::Exact(name) => name == model,
+            ModelMatch::Glob(pattern) => glob_matches(pattern, model),
+            ModelMatch::Regex(regex) => regex.0.is_match(model),
+        }
+    }
+}
+
+/// 64-bit FNV-1a, stable across restarts so sessions keep their split arm
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+impl RouteTarget {
+    /// The model to use, and for splits which arm it came from. `None` for an `auto`
+    /// target that has not selected a model yet.
+    fn pick(&self, config: &Config, session: Option<&str>) -> Option<(String, Option<usize>)> {
+        let model = match self {
+            RouteTarget::Model(model) => model.clone(),
+            RouteTarget::Mapping(ModelSlot::Haiku) => config.model_haiku.clone(),
+            RouteTarget::Mapping(ModelSlot::Sonnet) => config.model_sonnet.clone(),
+            RouteTarget::Mapping(ModelSlot::Opus) => config.model_opus.clone(),
+            RouteTarget::Split(arms) => {
+                let total: u64 = arms.iter().map(|arm| u64::from(arm.weight)).sum();
+                // Requests without a session are spread randomly
+                let mut point = match session {
+                    Some(session) => fnv1a(session) % total,
+                    None => random::below(total),
+                };
+                for (index, arm) in arms.iter().enumerate() {
+                    if point < u64::from(arm.weight) {
+                        return Some((arm.model.clone(), Some(index)));
+                    }
+                    point -= u64::from(arm.weight);
+                }
+                unreachable!("the point is below the total weight")
+            }
+            RouteTarget::Auto(auto) => auto.model()?,
+        };
+        Some((model, None))
+    }
+
+    /// Every model the target can send requests to.
+    fn models(&self, config: &Config) -> Vec<String> {
+        match self {
+            RouteTarget::Split(arms) => arms.iter().map(|arm| arm.model.clone()).collect(),
+            target => target
+                .pick(config, None)
+                .into_iter()
+                .map(|(model, _)| model)
+                .collect(),
+        }
+    }
+}
+
+/// The rules used when none are configured, matching model names by substring
+fn builtin_rules() -> Vec<RouteRule> {
+    [
+        ("haiku", ModelSlot::Haiku),
+        ("sonnet", ModelSlot::Sonnet),
+        ("opus", ModelSlot::Opus),
+    ]
+    .into_iter()
+    .map(|(name, slot)| RouteRule {
+        name: Some(name.to_string()),
+        matcher: Some(ModelMatch::Glob(format!("*{name}*"))),
+        when: RouteConditions::default(),
+        action: RouteTarget::Mapping(slot).into(),
+    })
+    .collect()
+}
+
+/// Decide which upstream model serves a request for `requested` with the given features.
+pub fn resolve(
+    requested: &str,
+    features: &RequestFeatures,
+    config: &Config,
+) -> Result<Route, Unroutable> {
+    let requested = config
+        .aliases
+        .get(requested)
+        .map(String::as_str)
+        .unwrap_or(requested);
+
+    let builtin;
+    let rules = if config.routing.rules.is_empty() {
+        builtin = builtin_rules();
+        &builtin
+    } else {
+        &config.routing.rules
+    };
+
+    for (index, rule) in rules.iter().enumerate() {
+        let name_matches = rule.matcher.as_ref().is_none_or(|m| m.matches(requested));
+        if name_matches && rule.when.matches(features) {
+            let name = rule.name.clone().unwrap_or_else(|| format!("#{index}"));
+            return rule.action.route(&name, config, features);
+        }
+    }
+
+    if let Some(default) = &config.routing.default {
+        default.route("default", config, features)
+    } else if config.routing.reject_unmapped {
+        Err(Unroutable::Unmapped(requested.to_string()))
+    } else {
+        Ok(Route::direct(requested, "passthrough", config))
+    }
+}
+
+/// Resolve a request, honoring the override headers where the configuration allows.
+pub fn resolve_request(
+    requested: &str,
+    features: &RequestFeatures,
+    headers: &HeaderMap,
+    config: &Config,
+) -> Result<Route, (StatusCode, String)> {
+    match config.routing.overrides.requested_model(headers) {
+        Ok(Some(model)) => Ok(Route::direct(&model, "override", config)),
+        Ok(None) => resolve(requested, features, config).map_err(|e| (e.status(), e.to_string())),
+        Err(message) => Err((StatusCode::FORBIDDEN, message)),
+    }
+}
+
+/// Log the routing decision for a request and count split assignments.
+pub fn record(requested: &str, route: &Route, features: &RequestFeatures) {
+    match route.arm {
+        Some(arm) => {
+            tracing::info!(
+                "Routing {requested} to {} (rule {}, split arm {arm}, ~{} prompt tokens)",
+                route.model,
+                route.rule,
+                features.prompt_tokens
+            );
+            METRICS.inc(
+                "ccorp_split_requests_total",
+                &[("rule", route.rule.as_str()), ("arm", route.model.as_str())],
+            );
+        }
+        None => tracing::info!(
+            "Routing {requested} to {} (rule {}, ~{} prompt tokens)",
+            route.model,
+            route.rule,
+            features.prompt_tokens
+        ),
+    }
+}
+
+/// All upstream models the routing configuration can send requests to.
+pub fn targets(config: &Config) -> Vec<String> {
+    collect_targets(config, true)
+}
+
+/// The upstream models requests are sent to first, leaving out fallbacks.
+pub fn primary_targets(config: &Config) -> Vec<String> {
+    collect_targets(config, false)
+}
+
+fn collect_targets(config: &Config, fallbacks: bool) -> Vec<String> {
+    let mut models = vec![
+        config.model_haiku.clone(),
+        config.model_sonnet.clone(),
+        config.model_opus.clone(),
+    ];
+    for action in config
+        .routing
+        .rules
+        .iter()
+        .map(|rule| &rule.action)
+        .chain(&config.routing.default)
+    {
+        models.extend(action.target.models(config));
+        if fallbacks {
+            models.extend(action.fallbacks.iter().cloned());
+        }
+    }
+
+    let mut unique = Vec::new();
+    for model in models {
+        if !unique.contains(&model) {
+            unique.push(model);
+        }
+    }
+    unique
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(routing: serde_json::Value) -> Config {
+        Config {
+            model_haiku: "small/model".to_string(),
+            model_sonnet: "medium/model".to_string(),
+            model_opus: "large/model".to_string(),
+            routing: serde_json::from_value(routing).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    fn model(requested: &str, config: &Config) -> String {
+        resolve(requested, &RequestFeatures::default(), config)
+            .unwrap()
+            .model
+    }
+
+    #[test]
+    fn glob_matching() {
+        assert!(glob_matches(
+            "claude-3-5-haiku-*",
+            "claude-3-5-haiku-20241022"
+        ));
+        assert!(!glob_matches(
+            "claude-3-5-haiku-*",
+            "claude-haiku-4-5-20251001"
+        ));
+        assert!(glob_matches("*haiku*", "claude-haiku-4-5"));
+        assert!(glob_matches("claude-?-opus", "claude-3-opus"));
+        assert!(glob_matches("a*b*c", "aXbYbZc"));
+        assert!(!glob_matches("a*b*c", "aXbYbZ"));
+        assert!(glob_matches("*", ""));
+        assert!(!glob_matches("", "a"));
+    }
+
+    #[test]
+    fn builtin_rules_match_by_substring() {
+        let config = config(serde_json::json!({}));
+        assert_eq!(model("claude-3-5-haiku-20241022", &config), "small/model");
+        assert_eq!(model("claude-sonnet-4-20250514", &config), "medium/model");
+        assert_eq!(model("claude-opus-4-1", &config), "large/model");
+        assert_eq!(model("other/model", &config), "other/model");
+    }
+
+    #[test]
+    fn rules_are_evaluated_in_order() {
+        let config = config(serde_json::json!({
+            "rules": [
+                { "name": "old-haiku", "match": { "glob": "claude-3-5-haiku-*" }, "model": "old/haiku" },
+                { "match": { "regex": "^claude-haiku-4-" }, "mapping": "haiku" },
+                { "match": { "exact": "claude-opus-4-20250514" }, "mapping": "opus" },
+                { "match": { "glob": "claude-*" }, "mapping": "sonnet" },
+            ]
+        }));
+
+        let route = resolve(
+            "claude-3-5-haiku-20241022",
+            &RequestFeatures::default(),
+            &config,
+        )
+        .unwrap();
+        assert_eq!(route.model, "old/haiku");
+        assert_eq!(route.rule, "old-haiku");
+        let route = resolve(
+            "claude-haiku-4-5-20251001",
+            &RequestFeatures::default(),
+            &config,
+        )
+        .unwrap();
+        assert_eq!(route.model, "small/model");
+        assert_eq!(route.rule, "#1");
+        assert_eq!(model("claude-opus-4-20250514", &config), "large/model");
+        assert_eq!(model("claude-opus-4-1-20250805", &config), "medium/model");
+    }
+
+    #[test]
+    fn configured_rules_replace_builtin_rules() {
+        let config = config(serde_json::json!({
+            "rules": [{ "match": { "exact": "claude-opus-4" }, "model": "x/opus" }]
+        }));
+        assert_eq!(model("claude-sonnet-4", &config), "claude-sonnet-4");
+    }
+
+    #[test]
+    fn default_rule_and_rejection() {
+        let config_with_default = config(serde_json::json!({
+            "rules": [{ "match": { "glob": "*haiku*" }, "mapping": "haiku" }],
+            "default": { "mapping": "opus" },
+            "reject_unmapped": true,
+        }));
+        let route = resolve("gpt-4o", &RequestFeatures::default(), &config_with_default).unwrap();
+        assert_eq!(route.model, "large/model");
+        assert_eq!(route.rule, "default");
+
+        let rejecting = config(serde_json::json!({
+            "rules": [{ "match": { "glob": "*haiku*" }, "mapping": "haiku" }],
+            "reject_unmapped": true,
+        }));
+        assert_eq!(
+            resolve("gpt-4o", &RequestFeatures::default(), &rejecting),
+            Err(Unroutable::Unmapped("gpt-4o".to_string()))
+        );
+        assert_eq!(model("claude-haiku-4-5", &rejecting), "small/model");
+    }
+
+    #[test]
+    fn unselected_auto_targets_use_fallbacks_or_fail() {
+        let config = config(serde_json::json!({
+            "rules": [
+                { "name": "cheap", "match": { "glob": "*haiku*" }, "auto": { "tools": true } },
+                { "match": { "glob": "*sonnet*" }, "auto": {}, "fallbacks": ["a/b", "c/d"] },
+            ],
+        }));
+        let error = resolve("claude-haiku-4-5", &RequestFeatures::default(), &config).unwrap_err();
+        assert_eq!(error, Unroutable::Unselected("cheap".to_string()));
+        assert_eq!(error.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let route = resolve("claude-sonnet-4", &RequestFeatures::default(), &config).unwrap();
+        assert_eq!(route.candidates().collect::<Vec<_>>(), ["a/b", "c/d"]);
+    }
+
+    #[test]
+    fn fallbacks_follow_the_target() {
+        let config = config(serde_json::json!({
+            "rules": [{ "match": { "glob": "*sonnet*" }, "mapping": "sonnet", "fallbacks": ["a/b", "c/d"] }],
+            "default": { "model": "x/y", "fallbacks": ["z/z"], "native_fallbacks": true },
+        }));
+        let route = resolve("claude-sonnet-4", &RequestFeatures::default(), &config).unwrap();
+        assert_eq!(
+            route.candidates().collect::<Vec<_>>(),
+            ["medium/model", "a/b", "c/d"]
+        );
+        assert!(!route.native_fallbacks);
+        let route = resolve("gpt-4o", &RequestFeatures::default(), &config).unwrap();
+        assert_eq!(route.candidates().collect::<Vec<_>>(), ["x/y", "z/z"]);
+        assert!(route.native_fallbacks);
+    }
+
+    #[test]
+    fn rule_provider_preferences_override_global_ones() {
+        let mut config = config(serde_json::json!({
+            "rules": [{ "match": { "glob": "*opus*" }, "mapping": "opus", "provider": { "order": ["Groq"], "sort": "throughput" } }],
+        }));
+        config.provider = serde_json::from_value(serde_json::json!({
+            "data_collection": "deny",
+            "order": ["Together"],
+            "quantizations": ["fp8", "bf16"],
+        }))
+        .unwrap();
+
+        let route = resolve("claude-opus-4", &RequestFeatures::default(), &config).unwrap();
+        assert_eq!(
+            serde_json::to_value(&route.provider).unwrap(),
+            serde_json::json!({
+                "order": ["Groq"],
+                "data_collection": "deny",
+                "quantizations": ["fp8", "bf16"],
+                "sort": "throughput",
+            })
+        );
+        let route = resolve("other", &RequestFeatures::default(), &config).unwrap();
+        assert_eq!(route.provider, config.provider);
+    }
+
+    #[test]
+    fn split_targets_are_sticky_per_session() {
+        let config = config(serde_json::json!({
+            "rules": [{ "match": { "glob": "*sonnet*" }, "split": [
+                { "model": "a/main", "weight": 80 },
+                { "model": "b/candidate", "weight": 20 },
+            ] }],
+        }));
+        let route_for = |session: &str| {
+            let features = RequestFeatures {
+                session: Some(session.to_string()),
+                ..Default::default()
+            };
+            resolve("claude-sonnet-4", &features, &config).unwrap()
+        };
+
+        let mut candidate = 0;
+        for n in 0..1000 {
+            let session = format!("session-{n}");
+            let route = route_for(&session);
+            assert_eq!(route_for(&session), route);
+            if route.arm == Some(1) {
+                assert_eq!(route.model, "b/candidate");
+                candidate += 1;
+            }
+        }
+        assert!((150..250).contains(&candidate), "{candidate}");
+        assert_eq!(targets(&config)[3..], ["a/main", "b/candidate"]);
+
+        let empty = serde_json::from_value::<RoutingConfig>(serde_json::json!({
+            "rules": [{ "split": [{ "model": "a/main", "weight": 0 }] }],
+        }));
+        assert!(empty.is_err());
+    }
+
+    #[test]
+    fn override_headers_respect_the_allowlist() {
+        let config = config(serde_json::json!({
+            "overrides": {
+                "allowed": ["openai/*"],
+                "profiles": { "cheap": "qwen/qwen3-coder" },
+            },
+        }));
+        let request = |headers: &[(&'static str, &str)]| {
+            let mut map = HeaderMap::new();
+            for (name, value) in headers {
+                map.insert(*name, value.parse().unwrap());
+            }
+            resolve_request(
+                "claude-sonnet-4",
+                &RequestFeatures::default(),
+                &map,
+                &config,
+            )
+        };
+
+        assert_eq!(request(&[]).unwrap().model, "medium/model");
+        let route = request(&[("x-ccorp-model", "openai/gpt-5")]).unwrap();
+        assert_eq!(
+            (route.model.as_str(), route.rule.as_str()),
+            ("openai/gpt-5", "override")
+        );
+        assert_eq!(
+            request(&[("x-ccorp-profile", "cheap")]).unwrap().model,
+            "qwen/qwen3-coder"
+        );
+        assert_eq!(
+            request(&[("x-ccorp-model", "x-ai/grok-4")]).unwrap_err().0,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            request(&[("x-ccorp-profile", "nope")]).unwrap_err().0,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[test]
+    fn aliases_are_resolved_before_routing() {
+        let mut config = config(serde_json::json!({}));
+        config
+            .aliases
+            .insert("fast".to_string(), "claude-3-5-haiku-latest".to_string());
+        assert_eq!(model("fast", &config), "small/model");
+    }
+
+    fn anthropic_request(request: serde_json::Value) -> AnthropicRequest {
+        serde_json::from_value(request).unwrap()
+    }
+
+    #[test]
+    fn features_of_anthropic_request() {
+        let request = anthropic_request(serde_json::json!({
+            "model": "claude-sonnet-4",
+            "system": "x".repeat(400),
+            "thinking": { "type": "enabled", "budget_tokens": 1024 },
+            "tools": [{ "name": "read", "input_schema": {} }],
+            "messages": [
+                { "role": "user", "content": [
+                    { "type": "text", "text": "look" },
+                    { "type": "image", "source": { "type": "base64", "media_type": "image/png", "data": "AAAA" } }
+                ]},
+                { "role": "assistant", "content": [{ "type": "tool_use", "id": "t1", "name": "read", "input": {} }] },
+                { "role": "user", "content": [{ "type": "tool_result", "tool_use_id": "t1", "content": "ok" }] }
+            ]
+        }));
+        let features = RequestFeatures::from_anthropic(&request);
+        assert!(features.tools);
+        assert!(features.images);
+        assert!(features.thinking);
+        assert!(features.last_message_tool_result);
+        assert!(features.prompt_tokens > 100);
+
+        let plain = anthropic_request(serde_json::json!({
+            "model": "claude-sonnet-4",
+            "messages": [{ "role": "user", "content": "hi" }]
+        }));
+        assert_eq!(
+            RequestFeatures::from_anthropic(&plain),
+            RequestFeatures {
+                prompt_tokens: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn conditions_route_by_request_content() {
+        let config = config(serde_json::json!({
+            "rules": [
+                { "name": "long", "when": { "min_prompt_tokens": 100000 }, "model": "long/context" },
+                { "name": "vision", "match": { "glob": "*sonnet*" }, "when": { "images": true }, "model": "vision/model" },
+                { "name": "tool-loop", "when": { "tools": true, "last_message_tool_result": true }, "mapping": "haiku" },
+                { "name": "sonnet", "match": { "glob": "*sonnet*" }, "mapping": "sonnet" }
+            ]
+        }));
+        let route = |features: RequestFeatures| {
+            resolve("claude-sonnet-4", &features, &config).unwrap().rule
+        };
+
+        assert_eq!(route(RequestFeatures::default()), "sonnet");
+        assert_eq!(
+            route(RequestFeatures {
+                prompt_tokens: 150_000,
+                images: true,
+                ..Default::default()
+            }),
+            "long"
+        );
+        assert_eq!(
+            route(RequestFeatures {
+                images: true,
+                ..Default::default()
+            }),
+            "vision"
+        );
+        assert_eq!(
+            route(RequestFeatures {
+                tools: true,
+                ..Default::default()
+            }),
+            "sonnet"
+        );
+        assert_eq!(
+            route(RequestFeatures {
+                tools: true,
+                last_message_tool_result: true,
+                ..Default::default()
+            }),
+            "tool-loop"
+        );
+    }
+
+    #[test]
+    fn unknown_mapping_is_rejected_when_loading() {
+        let result = serde_json::from_value::<RoutingConfig>(serde_json::json!({
+            "rules": [{ "match": { "glob": "*" }, "mapping": "gpt" }]
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn invalid_regex_is_rejected_when_loading() {
+        let result = serde_json::from_value::<RoutingConfig>(serde_json::json!({
+            "rules": [{ "match": { "regex": "(" }, "model": "x" }]
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn targets_lists_every_reachable_model_once() {
+        let config = config(serde_json::json!({
+            "rules": [
+                { "match": { "glob": "*" }, "model": "extra/model" },
+                { "match": { "glob": "*" }, "mapping": "haiku" },
+            ],
+            "default": { "model": "extra/model" },
+        }));
+        assert_eq!(
+            targets(&config),
+            vec!["small/model", "medium/model", "large/model", "extra/model"]
+        );
+    }
+}
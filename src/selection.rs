@@ -0,0 +1,225 @@
+use crate::AppState;
+use crate::config::Config;
+use crate::openrouter::{Model, ModelSource};
+use crate::routing::RouteTarget;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// What a model must offer to be picked by an automatic target
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelRequirements {
+    /// Tool calling support
+    pub tools: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_context: Option<u64>,
+    /// Input modalities besides text, e.g. `image`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub modalities: Vec<String>,
+    /// Allowed vendors, the part of the model id before the `/`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub vendors: Vec<String>,
+    /// Also consider free variants, which are heavily rate limited
+    pub allow_free: bool,
+}
+
+/// A target resolved to the cheapest model meeting its requirements
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AutoTarget {
+    #[serde(flatten)]
+    pub requirements: ModelRequirements,
+    /// The current pick, shared by all copies of the configuration
+    #[serde(skip)]
+    selected: Arc<RwLock<Option<String>>>,
+}
+
+impl PartialEq for AutoTarget {
+    fn eq(&self, other: &Self) -> bool {
+        self.requirements == other.requirements
+    }
+}
+
+impl AutoTarget {
+    /// The selected model, `None` until the catalog has been searched
+    pub fn model(&self) -> Option<String> {
+        self.selected.read().unwrap().clone()
+    }
+}
+
+/// Price per token of prompt plus completion, `None` for unpriced models.
+fn price(model: &Model) -> Option<f64> {
+    let pricing = model.pricing.as_ref()?;
+    let prompt: f64 = pricing.prompt.as_deref()?.parse().ok()?;
+    let completion: f64 = pricing.completion.as_deref()?.parse().ok()?;
+    // Negative prices mark routers such as openrouter/auto
+    (prompt >= 0.0 && completion >= 0.0).then_some(prompt + completion)
+}
+
+impl ModelRequirements {
+    fn accepts(&self, model: &Model) -> bool {
+        let vendor = model.id.split('/').next().unwrap_or_default();
+        let inputs = model
+            .architecture
+            .as_ref()
+            .and_then(|a| a.modality.as_deref())
+            .and_then(|modality| modality.split("->").next())
+            .unwrap_or("text");
+        let supports_tools = model
+            .supported_parameters
+            .as_ref()
+            .is_some_and(|params| params.iter().any(|p| p == "tools"));
+
+        (!self.tools || supports_tools)
+            && self.min_context.is_none_or(|min| {
+                model
+                    .context_length
+                    .is_some_and(|c| u64::try_from(c).is_ok_and(|c| c >= min))
+            })
+            && self.modalities.iter().all(|m| inputs.contains(m.as_str()))
+            && (self.vendors.is_empty() || self.vendors.iter().any(|v| v == vendor))
+    }
+
+    /// The cheapest model in the catalog that meets the requirements.
+    pub fn cheapest<'a>(&self, models: &'a [Model]) -> Option<&'a Model> {
+        models
+            .iter()
+            .filter(|model| self.accepts(model))
+            .filter_map(|model| Some((price(model)?, model)))
+            .filter(|(price, _)| self.allow_free || *price > 0.0)
+            .min_by(|(a, _), (b, _)| a.total_cmp(b))
+            .map(|(_, model)| model)
+    }
+}
+
+fn auto_targets(config: &Config) -> impl Iterator<Item = (String, &AutoTarget)> {
+    let rules = config
+        .routing
+        .rules
+        .iter()
+        .enumerate()
+        .map(|(index, rule)| {
+            let name = rule.name.clone().unwrap_or_else(|| format!("#{index}"));
+            (name, &rule.action.target)
+        });
+    let default = config
+        .routing
+        .default
+        .iter()
+        .map(|action| ("default".to_string(), &action.target));
+    rules
+        .chain(default)
+        .filter_map(|(name, target)| match target {
+            RouteTarget::Auto(auto) => Some((name, auto)),
+            _ => None,
+        })
+}
+
+/// Pick the cheapest qualifying model for every automatic target.
+pub async fn refresh(state: &AppState) {
+    let config = state.config.read().await.clone();
+    if auto_targets(&config).next().is_none() {
+        return;
+    }
+    let models = match state
+        .models_cache
+        .get(&state.http, &ModelSource::new(&config))
+        .await
+    {
+        Ok(models) => models,
+        Err(e) => {
+            tracing::warn!("Could not fetch OpenRouter models for automatic selection: {e}");
+            return;
+        }
+    };
+
+    for (rule, target) in auto_targets(&config) {
+        let Some(model) = target.requirements.cheapest(&models) else {
+            tracing::warn!("No OpenRouter model meets the requirements of rule {rule}");
+            continue;
+        };
+        let mut selected = target.selected.write().unwrap();
+        if selected.as_deref() != Some(model.id.as_str()) {
+            tracing::info!(
+                "Rule {rule} now selects {} (was {})",
+                model.id,
+                selected.as_deref().unwrap_or("none")
+            );
+            *selected = Some(model.id.clone());
+        }
+    }
+}
+
+/// Re-run the automatic selection periodically.
+pub fn spawn_refresh(state: AppState) {
+    tokio::spawn(async move {
+        loop {
+            let interval = state.config.read().await.routing.auto_refresh_secs;
+            if interval == 0 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+            refresh(&state).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn catalog() -> Vec<Model> {
+        serde_json::from_value(json!([
+            { "id": "a/cheap-text", "name": "", "context_length": 32768,
+              "architecture": { "modality": "text->text" },
+              "pricing": { "prompt": "0.0000001", "completion": "0.0000002" },
+              "supported_parameters": ["tools"] },
+            { "id": "a/cheap-text:free", "name": "", "context_length": 32768,
+              "architecture": { "modality": "text->text" },
+              "pricing": { "prompt": "0", "completion": "0" },
+              "supported_parameters": ["tools"] },
+            { "id": "b/vision", "name": "", "context_length": 200000,
+              "architecture": { "modality": "text+image->text" },
+              "pricing": { "prompt": "0.000001", "completion": "0.000004" },
+              "supported_parameters": ["tools"] },
+            { "id": "c/long", "name": "", "context_length": 1000000,
+              "architecture": { "modality": "text->text" },
+              "pricing": { "prompt": "0.0000005", "completion": "0.000001" } },
+            { "id": "openrouter/auto", "name": "", "context_length": 2000000,
+              "pricing": { "prompt": "-1", "completion": "-1" } },
+        ]))
+        .unwrap()
+    }
+
+    fn cheapest(requirements: serde_json::Value) -> Option<String> {
+        let requirements: ModelRequirements = serde_json::from_value(requirements).unwrap();
+        requirements.cheapest(&catalog()).map(|m| m.id.clone())
+    }
+
+    #[test]
+    fn picks_cheapest_qualifying_model() {
+        assert_eq!(cheapest(json!({})).as_deref(), Some("a/cheap-text"));
+        assert_eq!(
+            cheapest(json!({ "allow_free": true })).as_deref(),
+            Some("a/cheap-text:free")
+        );
+        assert_eq!(
+            cheapest(json!({ "min_context": 100000 })).as_deref(),
+            Some("c/long")
+        );
+        assert_eq!(
+            cheapest(json!({ "min_context": 100000, "tools": true })).as_deref(),
+            Some("b/vision")
+        );
+        assert_eq!(
+            cheapest(json!({ "modalities": ["image"] })).as_deref(),
+            Some("b/vision")
+        );
+        assert_eq!(
+            cheapest(json!({ "vendors": ["c"] })).as_deref(),
+            Some("c/long")
+        );
+        assert_eq!(cheapest(json!({ "vendors": ["d"] })), None);
+    }
+}
@@ -0,0 +1,132 @@
+use crate::AppState;
+use crate::providers::ByteStream;
+use axum::body::Bytes;
+use futures_util::stream::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Time the server is given to close connections after the drain deadline before it exits anyway
+const EXIT_GRACE: Duration = Duration::from_secs(5);
+
+/// Settings for shutting down on SIGTERM or SIGINT
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    /// Seconds active streams may keep running before they are ended with an error
+    pub drain_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig { drain_secs: 30 }
+    }
+}
+
+impl ShutdownConfig {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Tells open streams that the drain deadline has passed, shared through `AppState`
+pub struct Shutdown {
+    expired: watch::Sender<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown {
+            expired: watch::Sender::new(false),
+        }
+    }
+}
+
+impl Shutdown {
+    fn expire(&self) {
+        self.expired.send_replace(true);
+    }
+
+    /// Resolves once the drain deadline has passed
+    async fn expired(&self) {
+        let mut expired = self.expired.subscribe();
+        // The sender lives as long as the state, so waiting cannot fail
+        let _ = expired.wait_for(|expired| *expired).await;
+    }
+
+    /// The next item of an upstream stream, or `None` once the drain deadline has passed.
+    pub async fn next(&self, body: &mut ByteStream) -> Option<Option<std::io::Result<Bytes>>> {
+        tokio::select! {
+            item = body.next() => Some(item),
+            () = self.expired() => None,
+        }
+    }
+}
+
+/// The Anthropic error event sent to streams still open at the drain deadline
+pub fn error() -> Value {
+    json!({
+        "type": "error",
+        "error": {
+            "type": "overloaded_error",
+            "message": "The proxy is shutting down, please retry the request",
+        },
+    })
+}
+
+async fn terminate() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Could not listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    std::future::pending::<()>().await;
+}
+
+/// Resolves on SIGTERM or SIGINT, which makes the server stop accepting connections.
+/// Streams still open `drain_secs` later are ended with an error event.
+pub async fn signal(state: AppState) {
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        () = terminate() => {}
+    }
+
+    let drain = Duration::from_secs(state.config.read().await.shutdown.drain_secs);
+    tracing::info!("Shutting down, waiting up to {drain:?} for active requests");
+    tokio::spawn(async move {
+        tokio::time::sleep(drain).await;
+        tracing::warn!("Drain deadline passed, ending open streams");
+        state.shutdown.expire();
+        // Requests still waiting for a complete upstream response are not waited for
+        tokio::time::sleep(EXIT_GRACE).await;
+        tracing::warn!("Connections still open after the drain deadline, exiting");
+        std::process::exit(0);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn cuts_streams_off_at_the_deadline() {
+        let shutdown = Shutdown::default();
+        let mut body: ByteStream = futures_util::stream::iter([Ok(Bytes::from("data: 1\n\n"))])
+            .chain(futures_util::stream::pending())
+            .boxed();
+        assert!(matches!(shutdown.next(&mut body).await, Some(Some(Ok(_)))));
+
+        shutdown.expire();
+        assert!(shutdown.next(&mut body).await.is_none());
+    }
+}
@@ -0,0 +1,101 @@
+/// Encode an Anthropic stream event, named after its type
+pub fn anthropic_event(data: serde_json::Value) -> String {
+    format!(
+        "event: {}\ndata: {data}\n\n",
+        data["type"].as_str().unwrap_or_default()
+    )
+}
+
+/// A single server-sent event
+#[derive(Debug, Default)]
+pub struct SseEvent {
+    pub event: Option<String>,
+    pub data: String,
+}
+
+/// Incremental parser for server-sent events that may be split across network chunks
+#[derive(Default)]
+pub struct SseParser {
+    buffer: Vec<u8>,
+    /// Start of the first line in `buffer` not yet known to be complete
+    line_start: usize,
+}
+
+impl SseParser {
+    /// Length of the first complete event in the buffer, including the blank line ending it
+    fn event_end(&mut self) -> Option<usize> {
+        while let Some(newline) = self.buffer[self.line_start..]
+            .iter()
+            .position(|byte| *byte == b'\n')
+        {
+            let end = self.line_start + newline;
+            let line = &self.buffer[self.line_start..end];
+            self.line_start = end + 1;
+            if line.is_empty() || line == b"\r" {
+                return Some(end + 1);
+            }
+        }
+        None
+    }
+
+    /// Feed the next chunk of the response body and return all events it completed.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut events = Vec::new();
+        while let Some(end) = self.event_end() {
+            // Only complete events are decoded, so characters split across chunks stay intact
+            let raw = String::from_utf8_lossy(&self.buffer[..end]).into_owned();
+            self.buffer.drain(..end);
+            self.line_start = 0;
+
+            let mut event = SseEvent::default();
+            for line in raw.lines() {
+                if let Some(name) = line.strip_prefix("event:") {
+                    event.event = Some(name.trim().to_string());
+                } else if let Some(data) = line.strip_prefix("data:") {
+                    if !event.data.is_empty() {
+                        event.data.push('\n');
+                    }
+                    event.data.push_str(data.strip_prefix(' ').unwrap_or(data));
+                }
+            }
+            if event.event.is_some() || !event.data.is_empty() {
+                events.push(event);
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_characters_and_line_endings_split_across_chunks() {
+        let mut parser = SseParser::default();
+        let body = "event: delta\r\ndata: {\"text\":\"gr\u{fc}\u{df}e \u{1f600}\"}\r\n\r\ndata: [DONE]\n\n";
+        let bytes = body.as_bytes();
+        // Split inside the four-byte emoji, then inside the blank line ending the event
+        let emoji = body.find('\u{1f600}').unwrap() + 2;
+        let crlf = body.find("\r\n\r\n").unwrap() + 3;
+
+        assert!(parser.push(&bytes[..emoji]).is_empty());
+        assert!(parser.push(&bytes[emoji..crlf]).is_empty());
+        let events = parser.push(&bytes[crlf..]);
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].event.as_deref(), Some("delta"));
+        assert_eq!(events[0].data, "{\"text\":\"gr\u{fc}\u{df}e \u{1f600}\"}");
+        assert_eq!(events[1].data, "[DONE]");
+    }
+
+    #[test]
+    fn joins_multi_line_data() {
+        let mut parser = SseParser::default();
+        let events = parser.push(b"data: first\ndata: second\n\n: comment\n\n");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].data, "first\nsecond");
+    }
+}
@@ -1,4 +1,4 @@
-use crate::openrouter;
+use crate::openrouter::ModelSource;
 use minijinja::Environment;
 use serde::{Deserialize, Serialize};
 
@@ -21,9 +21,12 @@ const SWITCH_MODEL_TEMPLATE: &str = include_str!("templates/switch_model.html");
 pub async fn switch_model_get(State(state): State<crate::AppState>) -> Html<String> {
     let cfg = state.config.read().await;
     // Fetch available models from OpenRouter
-    let models_result = openrouter::fetch_models(&cfg).await;
+    let models_result = state
+        .models_cache
+        .get(&state.http, &ModelSource::new(&cfg))
+        .await;
     let models_json = match models_result {
-        Ok(models) => serde_json::to_string(&models.data).unwrap_or_else(|_| "[]".to_string()),
+        Ok(models) => serde_json::to_string(&*models).unwrap_or_else(|_| "[]".to_string()),
         Err(_) => "[]".to_string(),
     };
 
@@ -41,6 +44,7 @@ pub async fn switch_model_get(State(state): State<crate::AppState>) -> Html<Stri
         model_sonnet => cfg.model_sonnet,
         model_opus => cfg.model_opus,
         models_json => models_json,
+        circuits => state.breakers.statuses(&cfg.circuit_breaker),
     };
 
     // Render the template
@@ -0,0 +1,696 @@
+use crate::AppState;
+use crate::http::{self, HttpConfig};
+use crate::metrics::{self, METRICS};
+use crate::openai_to_anthropic::error_type_for_status;
+use crate::providers::{self, Backend, Endpoint, Format};
+use crate::random;
+use crate::routing::{self, Route};
+use crate::sse::{SseEvent, SseParser};
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, StatusCode, header},
+};
+use futures_util::stream::{BoxStream, StreamExt};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Retries of a failing upstream model before moving on to its fallbacks
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// Retries per model, 0 disables retrying
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for every further one
+    pub initial_backoff_ms: u64,
+    /// Longest delay between attempts. An upstream asking to wait longer is not retried.
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_retries: 2,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 8000,
+        }
+    }
+}
+
+impl RetryConfig {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Delay before retry number `retry` (from 0) with equal jitter, or the upstream's
+    /// requested delay. `None` when the upstream asks for more than `max_backoff_ms`.
+    fn delay(&self, retry: u32, retry_after: Option<Duration>) -> Option<Duration> {
+        let max = Duration::from_millis(self.max_backoff_ms);
+        if let Some(retry_after) = retry_after {
+            return (retry_after <= max).then_some(retry_after);
+        }
+        let backoff = Duration::from_millis(self.initial_backoff_ms)
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(max);
+        let jitter = random::below(backoff.as_millis() as u64 / 2 + 1);
+        Some(backoff / 2 + Duration::from_millis(jitter))
+    }
+}
+
+/// Why an upstream attempt failed
+#[derive(Clone, Debug)]
+pub struct UpstreamError {
+    pub status: StatusCode,
+    /// The upstream's error body, or a description of the failure
+    pub message: String,
+    /// How long the upstream asked to wait before trying again
+    pub retry_after: Option<Duration>,
+}
+
+impl UpstreamError {
+    fn new(status: StatusCode, message: impl ToString) -> Self {
+        UpstreamError {
+            status,
+            message: message.to_string(),
+            retry_after: None,
+        }
+    }
+
+    fn bad_gateway(message: impl ToString) -> Self {
+        Self::new(StatusCode::BAD_GATEWAY, message)
+    }
+
+    /// Whether trying again, or trying a different model, might succeed
+    pub fn is_retryable(&self) -> bool {
+        matches!(self.status.as_u16(), 408 | 429 | 500..)
+    }
+
+    /// Whether the upstream itself is failing, which counts towards opening its circuit.
+    /// Rate limits and timeouts the client caused say nothing about its health;
+    /// connection errors are reported as 502.
+    fn is_outage(&self) -> bool {
+        self.status.is_server_error()
+    }
+}
+
+/// A stream that has already delivered its first content
+pub struct UpstreamStream {
+    /// Events read while waiting for the stream to start
+    pub buffered: Vec<SseEvent>,
+    /// Raw bytes read so far, for passing the stream through unchanged
+    pub prefix: Vec<u8>,
+    pub parser: SseParser,
+    pub body: BoxStream<'static, std::io::Result<Bytes>>,
+}
+
+pub enum UpstreamBody {
+    Complete(Value),
+    Stream(UpstreamStream),
+}
+
+pub struct UpstreamResponse {
+    /// The model that actually served the request
+    pub model: String,
+    /// Format of the body, `Messages` for backends that answer with Anthropic messages
+    pub format: Format,
+    pub body: UpstreamBody,
+}
+
+fn to_value(request: impl Serialize) -> Value {
+    serde_json::to_value(request).expect("requests serialize")
+}
+
+/// The client's request as it arrived, which Anthropic backends receive instead of the
+/// re-serialized `messages` body
+pub struct Original {
+    body: Bytes,
+    /// The client's `anthropic-*` headers
+    headers: BTreeMap<String, String>,
+}
+
+impl Original {
+    pub fn new(body: Bytes, headers: &HeaderMap) -> Self {
+        Original {
+            body,
+            headers: providers::forwarded_headers(headers),
+        }
+    }
+}
+
+/// The request bodies for the models of a route
+#[derive(Default)]
+pub struct Payload {
+    /// A chat completions body sent to every model
+    shared: Option<Value>,
+    /// Bodies adapted to single models in the format of their backend, or why the model
+    /// cannot take the request
+    models: Vec<(String, Result<Value, UpstreamError>)>,
+    original: Option<Original>,
+}
+
+impl Payload {
+    /// A chat completions request, which backends taking Anthropic messages cannot serve
+    pub fn chat(request: impl Serialize) -> Self {
+        Payload {
+            shared: Some(to_value(request)),
+            ..Payload::default()
+        }
+    }
+
+    /// Send `request` to `model`, in the format of the model's backend.
+    pub fn add(&mut self, model: &str, request: impl Serialize) {
+        self.models.push((model.to_string(), Ok(to_value(request))));
+    }
+
+    /// Skip `model`, which cannot serve the request. The error is returned when no
+    /// other model can either.
+    pub fn reject(&mut self, model: &str, status: StatusCode, message: &str) {
+        let body = json!({
+            "type": "error",
+            "error": { "type": error_type_for_status(status.as_u16()), "message": message },
+        });
+        let error = UpstreamError::new(status, body);
+        self.models.push((model.to_string(), Err(error)));
+    }
+
+    /// Forward `original` to Anthropic backends in place of their `messages` body
+    pub fn with_original(mut self, original: Option<Original>) -> Self {
+        self.original = original;
+        self
+    }
+
+    fn body(&self, model: &str, format: Format) -> Result<&Value, UpstreamError> {
+        if let Some((_, body)) = self.models.iter().find(|(m, _)| m == model) {
+            return body.as_ref().map_err(Clone::clone);
+        }
+        match &self.shared {
+            Some(body) if format == Format::Chat => Ok(body),
+            _ => Err(UpstreamError::new(
+                StatusCode::BAD_REQUEST,
+                format!("{model} is served by a backend that only accepts /v1/messages requests"),
+            )),
+        }
+    }
+
+    /// The body for the request log, the one of the first model that takes the request
+    pub fn logged(&self) -> &Value {
+        self.shared
+            .as_ref()
+            .or_else(|| self.models.iter().find_map(|(_, body)| body.as_ref().ok()))
+            .unwrap_or(&Value::Null)
+    }
+}
+
+/// An error reported inside a successful response, as OpenRouter does for provider failures
+fn provider_error(value: &Value) -> Option<UpstreamError> {
+    let error = value.get("error").filter(|e| !e.is_null())?;
+    let status = error["code"]
+        .as_u64()
+        .and_then(|code| StatusCode::from_u16(u16::try_from(code).ok()?).ok())
+        .filter(|status| !status.is_success())
+        .unwrap_or(StatusCode::BAD_GATEWAY);
+    let mut error = UpstreamError::new(status, value);
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        error.retry_after = error_headers(value).and_then(|headers| rate_limit_reset(&headers));
+    }
+    Some(error)
+}
+
+/// Rate limit headers OpenRouter forwards from the provider in `error.metadata.headers`
+fn error_headers(value: &Value) -> Option<HeaderMap> {
+    let headers = value["error"]["metadata"]["headers"].as_object()?;
+    Some(
+        headers
+            .iter()
+            .filter_map(|(name, value)| {
+                Some((
+                    name.to_lowercase().parse().ok()?,
+                    value.as_str()?.parse().ok()?,
+                ))
+            })
+            .collect(),
+    )
+}
+
+/// When `x-ratelimit-reset`, in milliseconds since the epoch, allows trying again
+fn rate_limit_reset(headers: &HeaderMap) -> Option<Duration> {
+    let reset: i64 = headers
+        .get("x-ratelimit-reset")?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()?;
+    let wait = reset - chrono::Utc::now().timestamp_millis();
+    Some(Duration::from_millis(wait.max(0) as u64))
+}
+
+/// How long an error response asks to wait, from `Retry-After` (seconds or a date) or,
+/// for rate limited requests, OpenRouter's rate limit headers.
+fn retry_after(status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+    let Some(value) = headers.get("retry-after").and_then(|v| v.to_str().ok()) else {
+        return (status == StatusCode::TOO_MANY_REQUESTS)
+            .then(|| rate_limit_reset(headers))
+            .flatten();
+    };
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let wait = date.timestamp_millis() - chrono::Utc::now().timestamp_millis();
+    Some(Duration::from_millis(wait.max(0) as u64))
+}
+
+/// Status code for an Anthropic error type, the inverse of `error_type_for_status`
+fn status_for_error_type(error_type: &str) -> StatusCode {
+    match error_type {
+        "invalid_request_error" => StatusCode::BAD_REQUEST,
+        "authentication_error" => StatusCode::UNAUTHORIZED,
+        "permission_error" => StatusCode::FORBIDDEN,
+        "not_found_error" => StatusCode::NOT_FOUND,
+        "rate_limit_error" => StatusCode::TOO_MANY_REQUESTS,
+        "overloaded_error" => StatusCode::from_u16(529).unwrap(),
+        _ => StatusCode::BAD_GATEWAY,
+    }
+}
+
+/// Whether an Anthropic stream event carries the start of an answer.
+fn starts_message(event: &SseEvent) -> Result<bool, UpstreamError> {
+    let Ok(event) = serde_json::from_str::<Value>(&event.data) else {
+        return Ok(false);
+    };
+    match event["type"].as_str() {
+        Some("error") => Err(UpstreamError::new(
+            status_for_error_type(event["error"]["type"].as_str().unwrap_or_default()),
+            event,
+        )),
+        Some("message_start" | "ping") | None => Ok(false),
+        Some(_) => Ok(true),
+    }
+}
+
+/// Whether a stream event carries the start of an answer.
+fn starts_answer(event: &SseEvent) -> Result<bool, UpstreamError> {
+    if event.data == "[DONE]" {
+        return Err(UpstreamError::bad_gateway(
+            "upstream ended the stream without a response",
+        ));
+    }
+    let Ok(chunk) = serde_json::from_str::<Value>(&event.data) else {
+        return Ok(false);
+    };
+    if let Some(error) = provider_error(&chunk) {
+        return Err(error);
+    }
+    let choice = &chunk["choices"][0];
+    Ok(!choice["delta"]["content"].is_null()
+        || !choice["delta"]["tool_calls"].is_null()
+        || !choice["finish_reason"].is_null())
+}
+
+/// Send a single request, for streams waiting until the first content arrives.
+/// `label` is the model's `upstream_model` metrics label.
+async fn send_once(
+    client: &Client,
+    settings: &HttpConfig,
+    endpoint: &Endpoint,
+    body: &Value,
+    original: Option<&Original>,
+    label: &str,
+) -> Result<UpstreamBody, UpstreamError> {
+    let stream = body["stream"].as_bool().unwrap_or(false);
+    let mut request = client.post(endpoint.url(stream));
+    // Streams can run for a long time, they are bounded by the idle timeout instead
+    if !stream && let Some(timeout) = settings.request_timeout() {
+        request = request.timeout(timeout);
+    }
+    let passthrough = original
+        .filter(|_| endpoint.backend == Backend::Anthropic)
+        .and_then(|original| Some((endpoint.passthrough_body(&original.body)?, original)));
+    let request = match passthrough {
+        Some((body, original)) => endpoint
+            .forwarding(&original.headers)
+            .authorize(request)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body),
+        None => endpoint
+            .authorize(request)
+            .json(&endpoint.request_body(body)),
+    };
+    let res = request.send().await.map_err(UpstreamError::bad_gateway)?;
+
+    let status = res.status();
+    METRICS.inc(
+        "ccorp_upstream_responses_total",
+        &[("upstream_model", label), ("status_code", status.as_str())],
+    );
+    if !status.is_success() {
+        let retry_after = retry_after(status, res.headers());
+        let mut error = UpstreamError::new(status, res.text().await.unwrap_or_default());
+        // OpenRouter passes the provider's rate limit headers along in the error body
+        error.retry_after = retry_after
+            .or_else(|| provider_error(&serde_json::from_str(&error.message).ok()?)?.retry_after);
+        return Err(error);
+    }
+
+    if !stream {
+        let value: Value = res.json().await.map_err(UpstreamError::bad_gateway)?;
+        let value = endpoint.backend.translate_response(value);
+        return match provider_error(&value) {
+            Some(error) => Err(error),
+            None => Ok(UpstreamBody::Complete(value)),
+        };
+    }
+
+    // Nothing has been sent to the client yet, so a stream that fails before its
+    // first content can still be replaced by a fallback
+    let mut body = endpoint.backend.translate_stream(http::idle_timeout(
+        res.bytes_stream().boxed(),
+        settings.stream_idle_timeout(),
+    ));
+    let mut parser = SseParser::default();
+    let mut buffered = Vec::new();
+    let mut prefix = Vec::new();
+    loop {
+        let chunk = match body.next().await {
+            Some(Ok(chunk)) => chunk,
+            Some(Err(e)) => return Err(UpstreamError::bad_gateway(e)),
+            None => {
+                return Err(UpstreamError::bad_gateway(
+                    "upstream closed the stream without a response",
+                ));
+            }
+        };
+        prefix.extend_from_slice(&chunk);
+        let mut started = false;
+        for event in parser.push(&chunk) {
+            started |= match endpoint.backend.format() {
+                Format::Chat => starts_answer(&event)?,
+                Format::Messages => starts_message(&event)?,
+            };
+            buffered.push(event);
+        }
+        if started {
+            return Ok(UpstreamBody::Stream(UpstreamStream {
+                buffered,
+                prefix,
+                parser,
+                body,
+            }));
+        }
+    }
+}
+
+/// The model named in the first response or chunk, which is the one OpenRouter picked.
+fn reported_model(body: &UpstreamBody) -> Option<String> {
+    let model = match body {
+        UpstreamBody::Complete(value) => value["model"].as_str().map(str::to_string),
+        UpstreamBody::Stream(stream) => stream.buffered.iter().find_map(|event| {
+            serde_json::from_str::<Value>(&event.data).ok()?["model"]
+                .as_str()
+                .map(str::to_string)
+        }),
+    };
+    model.filter(|m| !m.is_empty())
+}
+
+/// Send `payload` for `route`, moving on to the route's fallback models when a model
+/// fails before anything was streamed.
+pub async fn send(
+    state: &AppState,
+    api_key: &str,
+    route: &Route,
+    payload: Payload,
+) -> Result<UpstreamResponse, UpstreamError> {
+    let endpoint_for = async |model: &str| {
+        let config = state.config.read().await;
+        providers::endpoint(&config, model, api_key)
+    };
+    let (retry, settings, breaker, targets) = {
+        let config = state.config.read().await;
+        (
+            config.retry.clone(),
+            config.http.clone(),
+            config.circuit_breaker.clone(),
+            routing::targets(&config),
+        )
+    };
+    let label = |model: &str| metrics::upstream_model_label(&targets, model);
+    let original = payload.original.as_ref();
+    // Requests are only retried until a response or the first content of a stream
+    // arrives; nothing has reached the client before that
+    let send_with_retries = async |endpoint: &Endpoint, request: &Value, model: &str| {
+        let mut retries = 0;
+        let label = label(model);
+        loop {
+            let error = match send_once(&state.http, &settings, endpoint, request, original, &label)
+                .await
+            {
+                Ok(body) => return Ok(body),
+                Err(e) if !e.is_retryable() || retries >= retry.max_retries => return Err(e),
+                Err(e) => e,
+            };
+            let Some(delay) = retry.delay(retries, error.retry_after) else {
+                return Err(error);
+            };
+            retries += 1;
+            tracing::warn!(
+                "Upstream model {model} failed with {}, retrying in {delay:?} ({retries}/{})",
+                error.status,
+                retry.max_retries
+            );
+            METRICS.inc(
+                "ccorp_upstream_retries_total",
+                &[("upstream_model", &label)],
+            );
+            tokio::time::sleep(delay).await;
+        }
+    };
+    // Models whose circuit is open fail right away, like an overloaded upstream
+    let send_guarded = async |endpoint: &Endpoint, request: &Value, model: &str| {
+        if !state.breakers.allow(&breaker, model) {
+            METRICS.inc(
+                "ccorp_circuit_rejections_total",
+                &[("upstream_model", &label(model))],
+            );
+            // Upstream error bodies reach the client as they are
+            let body = serde_json::json!({
+                "type": "error",
+                "error": {
+                    "type": "overloaded_error",
+                    "message": format!("{model} is unavailable after repeated failures"),
+                },
+            });
+            return Err(UpstreamError::new(StatusCode::from_u16(529).unwrap(), body));
+        }
+        let result = send_with_retries(endpoint, request, model).await;
+        let ok = result.as_ref().map_or_else(|e| !e.is_outage(), |_| true);
+        state.breakers.record(&breaker, model, ok);
+        result
+    };
+
+    if route.native_fallbacks {
+        // OpenRouter walks the `models` list itself and reports which one answered
+        let endpoint = endpoint_for(&route.model).await;
+        let format = endpoint.backend.format();
+        let request = payload.body(&route.model, format)?;
+        let body = send_guarded(&endpoint, request, &route.model).await?;
+        let model = reported_model(&body).unwrap_or_else(|| route.model.clone());
+        // Upstreams may report a dated version of the requested id
+        if !model.starts_with(route.model.as_str()) {
+            tracing::warn!("OpenRouter served {} with {model}", route.model);
+            METRICS.inc(
+                "ccorp_fallbacks_total",
+                &[("from", &label(&route.model)), ("to", &label(&model))],
+            );
+        }
+        return Ok(UpstreamResponse {
+            model,
+            format,
+            body,
+        });
+    }
+
+    let mut candidates = route.candidates();
+    let mut model = candidates.next().expect("a route has a model");
+    loop {
+        let endpoint = endpoint_for(model).await;
+        let format = endpoint.backend.format();
+        let result = match payload.body(model, format) {
+            Ok(request) => send_guarded(&endpoint, request, model).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(body) => {
+                return Ok(UpstreamResponse {
+                    model: model.clone(),
+                    format,
+                    body,
+                });
+            }
+            Err(e) if e.is_retryable() || payload.body(model, format).is_err() => {
+                let Some(next) = candidates.next() else {
+                    return Err(e);
+                };
+                tracing::warn!(
+                    "Upstream model {model} failed with {}, falling back to {next}",
+                    e.status
+                );
+                tracing::debug!("Upstream error from {model}: {}", e.message);
+                METRICS.inc(
+                    "ccorp_fallbacks_total",
+                    &[("from", &label(model)), ("to", &label(next))],
+                );
+                model = next;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn reads_retry_delays_from_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", "3".parse().unwrap());
+        assert_eq!(
+            retry_after(StatusCode::SERVICE_UNAVAILABLE, &headers),
+            Some(Duration::from_secs(3))
+        );
+
+        let reset = chrono::Utc::now().timestamp_millis() + 2000;
+        let body = serde_json::json!({
+            "error": {
+                "code": 429,
+                "message": "Rate limit exceeded",
+                "metadata": { "headers": { "X-RateLimit-Reset": reset.to_string() } },
+            }
+        });
+        let error = provider_error(&body).unwrap();
+        assert_eq!(error.status, StatusCode::TOO_MANY_REQUESTS);
+        let wait = error.retry_after.unwrap();
+        assert!(wait > Duration::from_secs(1) && wait <= Duration::from_secs(2));
+    }
+
+    #[test]
+    fn backs_off_exponentially_with_jitter() {
+        let retry = RetryConfig::default();
+        for (attempt, backoff) in [(0, 500), (1, 1000), (4, 8000), (10, 8000)] {
+            let delay = retry.delay(attempt, None).unwrap();
+            let backoff = Duration::from_millis(backoff);
+            assert!(delay >= backoff / 2 && delay <= backoff);
+        }
+        assert_eq!(
+            retry.delay(0, Some(Duration::from_secs(2))),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(retry.delay(0, Some(Duration::from_secs(60))), None);
+    }
+
+    #[test]
+    fn payloads_skip_models_that_rejected_the_request() {
+        let mut payload = Payload::default();
+        payload.reject("a/small", StatusCode::BAD_REQUEST, "prompt is too long");
+        payload.add("b/large", serde_json::json!({ "model": "b/large" }));
+
+        let error = payload.body("a/small", Format::Chat).unwrap_err();
+        assert_eq!(error.status, StatusCode::BAD_REQUEST);
+        let body: Value = serde_json::from_str(&error.message).unwrap();
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+        assert_eq!(
+            payload.body("b/large", Format::Chat).unwrap()["model"],
+            "b/large"
+        );
+        assert_eq!(payload.logged()["model"], "b/large");
+        assert!(payload.body("c/other", Format::Chat).is_err());
+    }
+
+    #[test]
+    fn only_server_errors_count_as_outages() {
+        let outage =
+            |status: u16| UpstreamError::new(StatusCode::from_u16(status).unwrap(), "").is_outage();
+        assert!(outage(500) && outage(503) && outage(529));
+        assert!(UpstreamError::bad_gateway("connection refused").is_outage());
+        assert!(!outage(429) && !outage(408) && !outage(400));
+    }
+
+    #[tokio::test]
+    async fn passes_anthropic_requests_through_unchanged() {
+        let received = Arc::new(Mutex::new(None));
+        let app = axum::Router::new().route(
+            "/v1/messages",
+            axum::routing::post({
+                let received = received.clone();
+                move |headers: HeaderMap, body: Bytes| async move {
+                    *received.lock().unwrap() = Some((headers, body));
+                    axum::Json(serde_json::json!({
+                        "id": "msg_1", "type": "message", "role": "assistant",
+                        "model": "claude-sonnet-4-20250514", "content": [],
+                        "stop_reason": "end_turn",
+                    }))
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        // Spacing, key order, escapes and fields the proxy does not model
+        let sent = concat!(
+            r#"{ "top_k":5, "model" : "sonnet",  "max_tokens":1024,"#,
+            r#""metadata":{"user_id":"u\u00e9"},"service_tier":"auto","#,
+            r#""messages":[{"role":"user","content":[{"type":"text","text":"hi","#,
+            r#""cache_control":{"type":"ephemeral"}}]}]}"#,
+        );
+        let mut client_headers = HeaderMap::new();
+        client_headers.insert("anthropic-version", "2023-06-01".parse().unwrap());
+        client_headers.append(
+            "anthropic-beta",
+            "prompt-caching-2024-07-31".parse().unwrap(),
+        );
+        client_headers.append("anthropic-beta", "output-128k-2025-02-19".parse().unwrap());
+        client_headers.insert("x-api-key", "client-key".parse().unwrap());
+        let original = Original::new(Bytes::from(sent), &client_headers);
+        let endpoint = Endpoint {
+            provider: Some("anthropic-api".to_string()),
+            backend: Backend::Anthropic,
+            base_url: format!("http://{addr}/v1"),
+            model: "claude-sonnet-4-20250514".to_string(),
+            api_key: Some("provider-key".to_string()),
+            auth_header: "x-api-key".to_string(),
+            api_version: None,
+            headers: BTreeMap::from([("anthropic-version".to_string(), "2023-01-01".to_string())]),
+            body: serde_json::Map::new(),
+        };
+        let typed: Value = serde_json::from_str(sent).unwrap();
+
+        let body = send_once(
+            &Client::new(),
+            &HttpConfig::default(),
+            &endpoint,
+            &typed,
+            Some(&original),
+            "anthropic-api/claude-sonnet-4-20250514",
+        )
+        .await
+        .unwrap();
+        assert!(matches!(body, UpstreamBody::Complete(_)));
+
+        let (headers, body) = received.lock().unwrap().take().unwrap();
+        assert_eq!(
+            body,
+            sent.replace(r#""sonnet""#, r#""claude-sonnet-4-20250514""#)
+        );
+        assert_eq!(headers["anthropic-version"], "2023-06-01");
+        assert_eq!(
+            headers["anthropic-beta"],
+            "prompt-caching-2024-07-31,output-128k-2025-02-19"
+        );
+        assert_eq!(headers["x-api-key"], "provider-key");
+    }
+}